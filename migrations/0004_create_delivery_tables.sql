@@ -0,0 +1,12 @@
+-- Migration number: 0004 	 2026-10-19T02:11:40.518Z
+CREATE TABLE webhook_delivery (
+    id TEXT PRIMARY KEY, -- X-GitHub-Delivery
+    expires_at TEXT NOT NULL -- Stored in UTC
+);
+CREATE INDEX idx_webhook_delivery_expires_at ON webhook_delivery (expires_at);
+
+CREATE TABLE command_execution (
+    id TEXT PRIMARY KEY, -- e.g. issue_comment:123
+    expires_at TEXT NOT NULL -- Stored in UTC
+);
+CREATE INDEX idx_command_execution_expires_at ON command_execution (expires_at);
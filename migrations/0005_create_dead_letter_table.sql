@@ -0,0 +1,13 @@
+-- Migration number: 0005 	 2026-10-19T03:02:17.204Z
+CREATE TABLE dead_letter (
+    id INTEGER PRIMARY KEY,
+    delivery_id TEXT, -- X-GitHub-Delivery
+    event TEXT NOT NULL, -- X-GitHub-Event
+    payload TEXT NOT NULL,
+    error TEXT NOT NULL,
+    attempts INTEGER NOT NULL DEFAULT 1,
+    replay_requested INTEGER NOT NULL DEFAULT 0, -- Set to 1 to replay on the next cron tick
+    created_at TEXT NOT NULL DEFAULT (DATETIME('now')), -- Stored in UTC
+    replayed_at TEXT
+);
+CREATE INDEX idx_dead_letter_replay_requested ON dead_letter (replay_requested, replayed_at);
@@ -0,0 +1,4 @@
+-- Migration number: 0006 	 2026-10-19T04:26:53.870Z
+-- NULL means the default (first) app
+ALTER TABLE merge ADD COLUMN app_id INTEGER;
+ALTER TABLE dead_letter ADD COLUMN app TEXT NOT NULL DEFAULT 'default';
@@ -0,0 +1,16 @@
+-- Migration number: 0007 	 2026-10-19T05:40:12.331Z
+CREATE TABLE installation (
+    id INTEGER PRIMARY KEY, -- Installation id on GitHub
+    app_id INTEGER,
+    account TEXT NOT NULL,
+    suspended INTEGER NOT NULL DEFAULT 0,
+    created_at TEXT NOT NULL DEFAULT (DATETIME('now')) -- Stored in UTC
+);
+
+CREATE TABLE installation_repository (
+    installation_id INTEGER NOT NULL,
+    repository_id INTEGER NOT NULL,
+    owner TEXT NOT NULL,
+    name TEXT NOT NULL,
+    PRIMARY KEY (installation_id, repository_id)
+);
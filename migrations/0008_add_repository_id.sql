@@ -0,0 +1,6 @@
+-- Migration number: 0008 	 2026-10-19T06:18:45.092Z
+-- Stable identifiers so that renamed or transferred repositories can still be addressed
+ALTER TABLE merge ADD COLUMN repository_id INTEGER;
+ALTER TABLE merge ADD COLUMN pr_node_id TEXT;
+CREATE INDEX idx_merge_repository_id ON merge (repository_id, pr_number);
+CREATE INDEX idx_installation_repository_repository_id ON installation_repository (repository_id);
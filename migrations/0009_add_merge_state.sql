@@ -0,0 +1,22 @@
+-- Migration number: 0009 	 2026-10-19T07:12:36.481Z
+-- pending -> merged | failed | cancelled, and pending <-> suspended while the installation is suspended
+ALTER TABLE merge ADD COLUMN state TEXT NOT NULL DEFAULT 'pending';
+UPDATE merge SET state = 'merged' WHERE merged = 1;
+UPDATE merge SET state = 'suspended'
+    WHERE state = 'pending' AND installation_id IN (SELECT id FROM installation WHERE suspended = 1);
+
+-- Only the newest of duplicated schedules is kept
+UPDATE merge SET state = 'cancelled'
+    WHERE state IN ('pending', 'suspended')
+    AND id NOT IN (
+        SELECT MAX(id) FROM merge WHERE state IN ('pending', 'suspended')
+        GROUP BY repository_id, owner, repository, pr_number
+    );
+
+DROP INDEX idx_will_merged_at_merged;
+ALTER TABLE merge DROP COLUMN merged;
+
+CREATE INDEX idx_merge_state_will_merged_at ON merge (state, will_merged_at);
+-- At most one active schedule per pull request. Every state but the finished ones is active
+CREATE UNIQUE INDEX idx_merge_active_pr ON merge (repository_id, pr_number)
+    WHERE state NOT IN ('merged', 'failed', 'cancelled');
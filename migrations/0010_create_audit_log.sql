@@ -0,0 +1,18 @@
+-- Migration number: 0010 	 2026-10-19T08:03:27.915Z
+CREATE TABLE audit_log (
+    id INTEGER PRIMARY KEY,
+    created_at TEXT NOT NULL DEFAULT (DATETIME('now')), -- Stored in UTC
+    actor TEXT NOT NULL, -- GitHub login, or "scheduler" for the cron
+    installation_id INTEGER,
+    repository_id INTEGER,
+    owner TEXT,
+    repository TEXT,
+    pr_number INTEGER,
+    action TEXT NOT NULL, -- e.g. merge.add, installation.deleted
+    command TEXT, -- The comment that ran the command
+    result TEXT NOT NULL, -- success, rejected, denied or failed
+    error TEXT -- The error from GitHub or D1
+);
+CREATE INDEX idx_audit_log_repository_id ON audit_log (repository_id, pr_number);
+CREATE INDEX idx_audit_log_owner_repository ON audit_log (owner, repository, pr_number);
+CREATE INDEX idx_audit_log_created_at ON audit_log (created_at);
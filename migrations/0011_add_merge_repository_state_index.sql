@@ -0,0 +1,3 @@
+-- Migration number: 0011 	 2026-10-19T09:21:05.664Z
+-- Each repository scheduler looks up only its own pending merges
+CREATE INDEX idx_merge_state_repository_id ON merge (state, repository_id, will_merged_at);
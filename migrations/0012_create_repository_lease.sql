@@ -0,0 +1,10 @@
+-- Migration number: 0012 	 2026-10-19T10:04:51.238Z
+-- Only the holder of a repository's lease may merge, cancel or reschedule its jobs
+CREATE TABLE repository_lease (
+    key TEXT PRIMARY KEY, -- id:<repository_id> or name:<owner>/<repository>
+    holder TEXT NOT NULL,
+    expires_at TEXT NOT NULL -- Stored in UTC
+);
+
+-- A merge in progress is "merging" and stays active until it finishes
+ALTER TABLE merge ADD COLUMN started_at TEXT; -- Stored in UTC
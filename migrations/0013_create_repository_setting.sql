@@ -0,0 +1,8 @@
+-- Migration number: 0013 	 2026-10-19T11:15:42.077Z
+CREATE TABLE repository_setting (
+    repository_id INTEGER PRIMARY KEY,
+    -- direct: the bot merges at the scheduled time
+    -- auto: the bot enables GitHub's auto-merge at the scheduled time
+    merge_mode TEXT NOT NULL DEFAULT 'direct',
+    updated_at TEXT NOT NULL DEFAULT (DATETIME('now')) -- Stored in UTC
+);
@@ -0,0 +1,4 @@
+-- Migration number: 0014 	 2026-10-19T13:02:18.511Z
+ALTER TABLE merge ADD COLUMN check_run_id INTEGER;
+ALTER TABLE merge ADD COLUMN check_run_sha TEXT;
+ALTER TABLE merge ADD COLUMN scheduled_by TEXT;
@@ -0,0 +1,7 @@
+-- Migration number: 0015 	 2026-10-19T14:21:47.903Z
+-- A merge waiting for another pull request to be merged first is "blocked" and stays active
+ALTER TABLE merge ADD COLUMN after_owner TEXT;
+ALTER TABLE merge ADD COLUMN after_repository TEXT;
+ALTER TABLE merge ADD COLUMN after_pr_number INTEGER;
+CREATE INDEX idx_merge_after ON merge (after_owner, after_repository, after_pr_number)
+    WHERE state = 'blocked';
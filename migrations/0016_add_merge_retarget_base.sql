@@ -0,0 +1,3 @@
+-- Migration number: 0016 	 2026-10-19T15:40:09.126Z
+-- Set on pull requests in a stack (`merge stack`): the base branch to switch to after the one below is merged
+ALTER TABLE merge ADD COLUMN retarget_base TEXT;
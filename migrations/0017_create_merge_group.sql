@@ -0,0 +1,19 @@
+-- Migration number: 0017 	 2026-10-19T16:32:51.448Z
+-- Pull requests across repositories of one installation merged together (`merge group create`)
+CREATE TABLE merge_group (
+    id INTEGER PRIMARY KEY,
+    installation_id INTEGER NOT NULL,
+    app_id INTEGER, -- NULL means the default (first) app
+    name TEXT NOT NULL,
+    will_merged_at TEXT NOT NULL, -- Stored in UTC
+    state TEXT NOT NULL DEFAULT 'pending', -- pending, merging, merged, failed or cancelled
+    created_by TEXT,
+    created_at TEXT NOT NULL DEFAULT (DATETIME('now')), -- Stored in UTC
+    started_at TEXT -- Stored in UTC
+);
+-- The name is unique while the group is waiting or being merged
+CREATE UNIQUE INDEX idx_merge_group_active_name ON merge_group (installation_id, name)
+    WHERE state IN ('pending', 'merging');
+CREATE INDEX idx_merge_group_state_will_merged_at ON merge_group (state, will_merged_at);
+ALTER TABLE merge ADD COLUMN group_id INTEGER;
+CREATE INDEX idx_merge_group_id ON merge (group_id) WHERE group_id IS NOT NULL;
@@ -0,0 +1,20 @@
+-- Migration number: 0018 	 2026-10-19T17:48:06.215Z
+-- Jobs run by the cron on a recurring schedule, like release trains (`train weekdays 17:00`)
+CREATE TABLE recurring_job (
+    id INTEGER PRIMARY KEY,
+    kind TEXT NOT NULL, -- train
+    installation_id INTEGER NOT NULL,
+    app_id INTEGER, -- NULL means the default (first) app
+    repository_id INTEGER NOT NULL,
+    owner TEXT NOT NULL,
+    repository TEXT NOT NULL,
+    rule TEXT NOT NULL, -- When to run, e.g. "weekdays 17:00"
+    argument TEXT, -- e.g. the label of a train
+    catch_up TEXT NOT NULL DEFAULT 'skip', -- skip or once: what to do with a run missed by more than an hour
+    next_run_at TEXT NOT NULL, -- Stored in UTC
+    last_run_at TEXT, -- Stored in UTC
+    updated_by TEXT,
+    updated_at TEXT NOT NULL DEFAULT (DATETIME('now')) -- Stored in UTC
+);
+CREATE UNIQUE INDEX idx_recurring_job_kind_repository_id ON recurring_job (kind, repository_id);
+CREATE INDEX idx_recurring_job_next_run_at ON recurring_job (next_run_at);
@@ -0,0 +1,3 @@
+-- Migration number: 0019 	 2026-10-19T21:05:31.442Z
+-- How many times a merge failed before it started (e.g. no installation token), to back off and give up
+ALTER TABLE merge ADD COLUMN attempts INTEGER NOT NULL DEFAULT 0;
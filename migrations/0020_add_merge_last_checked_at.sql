@@ -0,0 +1,3 @@
+-- Migration number: 0020 	 2026-10-19T21:40:12.918Z
+-- When the cron last checked a blocked merge, so that every blocked merge gets its turn
+ALTER TABLE merge ADD COLUMN last_checked_at DATETIME;
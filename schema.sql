@@ -1,8 +1,145 @@
+-- The result of applying every file in migrations/. Keep this in sync with them.
+
 CREATE TABLE IF NOT EXISTS merge (
     id INTEGER PRIMARY KEY,
     pr_number INTEGER NOT NULL,
     owner TEXT NOT NULL,
     repository TEXT NOT NULL,
     will_merged_at TEXT NOT NULL, -- Stored in UTC
-    merged INTEGER NOT NULL DEFAULT 0
+    installation_id INTEGER,
+    app_id INTEGER, -- NULL means the default (first) app
+    repository_id INTEGER,
+    pr_node_id TEXT,
+    state TEXT NOT NULL DEFAULT 'pending', -- pending, blocked, merging, merged, auto_merge, queued, failed, cancelled or suspended
+    started_at TEXT, -- Stored in UTC
+    check_run_id INTEGER, -- The "Scheduled merge" check run on the pull request
+    check_run_sha TEXT, -- The head commit the check run was created on
+    scheduled_by TEXT, -- The login of who scheduled it last
+    -- The pull request that has to be merged first (`merge after owner/repo#123`)
+    after_owner TEXT,
+    after_repository TEXT,
+    after_pr_number INTEGER,
+    retarget_base TEXT, -- In a stack, the base branch to switch to after the one below is merged
+    group_id INTEGER, -- The merge group it is merged with (`merge group join`)
+    attempts INTEGER NOT NULL DEFAULT 0, -- Failures before the merge started, to back off and give up
+    last_checked_at DATETIME -- When the cron last checked the merge it is waiting for
 );
+CREATE INDEX idx_merge_state_will_merged_at ON merge (state, will_merged_at);
+-- At most one active schedule per pull request. Every state but the finished ones is active
+CREATE UNIQUE INDEX idx_merge_active_pr ON merge (repository_id, pr_number)
+    WHERE state NOT IN ('merged', 'failed', 'cancelled');
+CREATE INDEX idx_merge_repository_id ON merge (repository_id, pr_number);
+CREATE INDEX idx_merge_state_repository_id ON merge (state, repository_id, will_merged_at);
+CREATE INDEX idx_merge_after ON merge (after_owner, after_repository, after_pr_number)
+    WHERE state = 'blocked';
+CREATE INDEX idx_merge_group_id ON merge (group_id) WHERE group_id IS NOT NULL;
+
+CREATE TABLE merge_group (
+    id INTEGER PRIMARY KEY,
+    installation_id INTEGER NOT NULL,
+    app_id INTEGER, -- NULL means the default (first) app
+    name TEXT NOT NULL,
+    will_merged_at TEXT NOT NULL, -- Stored in UTC
+    state TEXT NOT NULL DEFAULT 'pending', -- pending, merging, merged, failed or cancelled
+    created_by TEXT,
+    created_at TEXT NOT NULL DEFAULT (DATETIME('now')), -- Stored in UTC
+    started_at TEXT -- Stored in UTC
+);
+-- The name is unique while the group is waiting or being merged
+CREATE UNIQUE INDEX idx_merge_group_active_name ON merge_group (installation_id, name)
+    WHERE state IN ('pending', 'merging');
+CREATE INDEX idx_merge_group_state_will_merged_at ON merge_group (state, will_merged_at);
+
+CREATE TABLE recurring_job (
+    id INTEGER PRIMARY KEY,
+    kind TEXT NOT NULL, -- train
+    installation_id INTEGER NOT NULL,
+    app_id INTEGER, -- NULL means the default (first) app
+    repository_id INTEGER NOT NULL,
+    owner TEXT NOT NULL,
+    repository TEXT NOT NULL,
+    rule TEXT NOT NULL, -- When to run, e.g. "weekdays 17:00"
+    argument TEXT, -- e.g. the label of a train
+    catch_up TEXT NOT NULL DEFAULT 'skip', -- skip or once: what to do with a run missed by more than an hour
+    next_run_at TEXT NOT NULL, -- Stored in UTC
+    last_run_at TEXT, -- Stored in UTC
+    updated_by TEXT,
+    updated_at TEXT NOT NULL DEFAULT (DATETIME('now')) -- Stored in UTC
+);
+CREATE UNIQUE INDEX idx_recurring_job_kind_repository_id ON recurring_job (kind, repository_id);
+CREATE INDEX idx_recurring_job_next_run_at ON recurring_job (next_run_at);
+
+CREATE TABLE repository_setting (
+    repository_id INTEGER PRIMARY KEY,
+    -- direct: the bot merges at the scheduled time
+    -- auto: the bot enables GitHub's auto-merge at the scheduled time
+    merge_mode TEXT NOT NULL DEFAULT 'direct',
+    updated_at TEXT NOT NULL DEFAULT (DATETIME('now')) -- Stored in UTC
+);
+
+CREATE TABLE repository_lease (
+    key TEXT PRIMARY KEY, -- id:<repository_id> or name:<owner>/<repository>
+    holder TEXT NOT NULL,
+    expires_at TEXT NOT NULL -- Stored in UTC
+);
+
+CREATE TABLE webhook_delivery (
+    id TEXT PRIMARY KEY, -- X-GitHub-Delivery
+    expires_at TEXT NOT NULL -- Stored in UTC
+);
+CREATE INDEX idx_webhook_delivery_expires_at ON webhook_delivery (expires_at);
+
+CREATE TABLE command_execution (
+    id TEXT PRIMARY KEY, -- e.g. issue_comment:123
+    expires_at TEXT NOT NULL -- Stored in UTC
+);
+CREATE INDEX idx_command_execution_expires_at ON command_execution (expires_at);
+
+CREATE TABLE dead_letter (
+    id INTEGER PRIMARY KEY,
+    delivery_id TEXT, -- X-GitHub-Delivery
+    event TEXT NOT NULL, -- X-GitHub-Event
+    payload TEXT NOT NULL,
+    error TEXT NOT NULL,
+    attempts INTEGER NOT NULL DEFAULT 1,
+    replay_requested INTEGER NOT NULL DEFAULT 0, -- Set to 1 to replay on the next cron tick
+    created_at TEXT NOT NULL DEFAULT (DATETIME('now')), -- Stored in UTC
+    replayed_at TEXT,
+    app TEXT NOT NULL DEFAULT 'default'
+);
+CREATE INDEX idx_dead_letter_replay_requested ON dead_letter (replay_requested, replayed_at);
+
+CREATE TABLE installation (
+    id INTEGER PRIMARY KEY, -- Installation id on GitHub
+    app_id INTEGER,
+    account TEXT NOT NULL,
+    suspended INTEGER NOT NULL DEFAULT 0,
+    created_at TEXT NOT NULL DEFAULT (DATETIME('now')) -- Stored in UTC
+);
+
+CREATE TABLE installation_repository (
+    installation_id INTEGER NOT NULL,
+    repository_id INTEGER NOT NULL,
+    owner TEXT NOT NULL,
+    name TEXT NOT NULL,
+    PRIMARY KEY (installation_id, repository_id)
+);
+CREATE INDEX idx_installation_repository_repository_id ON installation_repository (repository_id);
+
+CREATE TABLE audit_log (
+    id INTEGER PRIMARY KEY,
+    created_at TEXT NOT NULL DEFAULT (DATETIME('now')), -- Stored in UTC
+    actor TEXT NOT NULL, -- GitHub login, or "scheduler" for the cron
+    installation_id INTEGER,
+    repository_id INTEGER,
+    owner TEXT,
+    repository TEXT,
+    pr_number INTEGER,
+    action TEXT NOT NULL, -- e.g. merge.add, installation.deleted
+    command TEXT, -- The comment that ran the command
+    result TEXT NOT NULL, -- success, rejected, denied or failed
+    error TEXT -- The error from GitHub or D1
+);
+CREATE INDEX idx_audit_log_repository_id ON audit_log (repository_id, pr_number);
+CREATE INDEX idx_audit_log_owner_repository ON audit_log (owner, repository, pr_number);
+CREATE INDEX idx_audit_log_created_at ON audit_log (created_at);
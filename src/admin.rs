@@ -0,0 +1,268 @@
+//! 運用のための管理API
+//! `ADMIN_TOKEN`を`Authorization: Bearer <token>`で送る。設定されていなければ無効
+//! JSONで返す
+
+use std::collections::HashMap;
+use std::rc::Rc;
+
+use subtle::ConstantTimeEq;
+use worker::*;
+
+use crate::app::AppConfig;
+use crate::db::{
+    self,
+    audit::{NewAuditEntry, Outcome},
+    lease::RepoLease,
+    merge::State,
+};
+use crate::schedule::MergeOutcome;
+
+/// 監査ログのactor
+const ADMIN: &str = "admin";
+
+const DEFAULT_LIMIT: u32 = 50;
+
+/// リースが空くまで待つ時間
+const LEASE_WAIT: std::time::Duration = std::time::Duration::from_secs(5);
+
+type Ctx = RouteContext<Rc<worker::Context>>;
+
+/// 認証できなければそのまま返すレスポンス
+fn authorize(req: &Request, env: &Env) -> Result<Option<Response>> {
+    let Ok(token) = env.secret("ADMIN_TOKEN") else {
+        return Ok(Some(Response::error("Not Found", 404)?));
+    };
+    let token = token.to_string();
+
+    let authorized = req
+        .headers()
+        .get("Authorization")?
+        .and_then(|header| header.strip_prefix("Bearer ").map(str::to_string))
+        .is_some_and(|sent| {
+            !token.is_empty() && sent.as_bytes().ct_eq(token.as_bytes()).unwrap_u8() == 1
+        });
+
+    if authorized {
+        Ok(None)
+    } else {
+        Ok(Some(Response::error("Unauthorised", 401)?))
+    }
+}
+
+fn query_params(req: &Request) -> Result<HashMap<String, String>> {
+    Ok(req.url()?.query_pairs().into_owned().collect())
+}
+
+/// 数字でなければNone
+fn limit(params: &HashMap<String, String>) -> Option<u32> {
+    match params.get("limit") {
+        Some(limit) => limit.parse::<u32>().ok().map(|limit| limit.min(500)),
+        None => Some(DEFAULT_LIMIT),
+    }
+}
+
+fn id_param(ctx: &Ctx) -> Option<u64> {
+    ctx.param("id").and_then(|id| id.parse().ok())
+}
+
+/// `GET /admin/merges?state=&owner=&repository=&limit=`
+pub async fn list_merges(req: Request, ctx: Ctx) -> Result<Response> {
+    if let Some(res) = authorize(&req, &ctx.env)? {
+        return Ok(res);
+    }
+    let params = query_params(&req)?;
+    let Some(limit) = limit(&params) else {
+        return Response::error("Bad Request (limit is not a number)", 400);
+    };
+
+    let filter = db::merge::Filter {
+        state: params.get("state").map(String::as_str),
+        owner: params.get("owner").map(String::as_str),
+        repository: params.get("repository").map(String::as_str),
+    };
+    let jobs = db::merge::list(&ctx.env.d1("DB")?, &filter, limit).await?;
+
+    Response::from_json(&jobs)
+}
+
+/// `POST /admin/merges/:id/cancel`
+pub async fn cancel_merge(req: Request, ctx: Ctx) -> Result<Response> {
+    if let Some(res) = authorize(&req, &ctx.env)? {
+        return Ok(res);
+    }
+    let Some(id) = id_param(&ctx) else {
+        return Response::error("Bad Request (id is not a number)", 400);
+    };
+    let d1 = ctx.env.d1("DB")?;
+
+    let Some(job) = db::merge::find_by_id(&d1, id).await? else {
+        return Response::error("Not Found", 404);
+    };
+    let Some(lease) = RepoLease::acquire(&d1, &job.repo(), LEASE_WAIT).await? else {
+        return Response::error("Conflict (the repository is busy)", 409);
+    };
+    let cancelled = db::merge::cancel_by_id(&d1, id).await;
+    lease.release().await?;
+
+    if !cancelled? {
+        return match db::merge::find_by_id(&d1, id).await?.map(|job| job.state) {
+            Some(State::Merging) => Response::error("Conflict (already merging)", 409),
+            _ => Response::error("Conflict (the merge is not scheduled)", 409),
+        };
+    }
+    crate::repo_scheduler::try_sync(&ctx.env, &job.repo()).await;
+
+    // チェックを更新するのにトークンがいる
+    let apps = AppConfig::load_all(&ctx.env)?;
+    if let Some(app) = crate::schedule::app_for_job(&apps, &job).await? {
+        let token = app.github_app.token(job.installation_id).await?;
+        crate::check_run::try_sync(&d1, &job.repo(), job.pr_number, &token).await;
+    }
+
+    db::audit::record(
+        &d1,
+        &NewAuditEntry {
+            actor: ADMIN,
+            installation_id: Some(job.installation_id),
+            repo: Some(job.repo()),
+            pr_number: Some(job.pr_number),
+            action: "merge.cancel",
+            command: None,
+            result: Outcome::Success,
+            error: None,
+        },
+    )
+    .await;
+
+    Response::from_json(&db::merge::find_by_id(&d1, id).await?)
+}
+
+/// `POST /admin/merges/:id/run`
+/// 時間を待たずにすぐマージする
+pub async fn run_merge(req: Request, ctx: Ctx) -> Result<Response> {
+    if let Some(res) = authorize(&req, &ctx.env)? {
+        return Ok(res);
+    }
+    let Some(id) = id_param(&ctx) else {
+        return Response::error("Bad Request (id is not a number)", 400);
+    };
+    let d1 = ctx.env.d1("DB")?;
+
+    let Some(job) = db::merge::find_by_id(&d1, id).await? else {
+        return Response::error("Not Found", 404);
+    };
+    if job.state != State::Pending {
+        return Response::error("Conflict (the merge is not pending)", 409);
+    }
+
+    let apps = AppConfig::load_all(&ctx.env)?;
+    let Some(app) = crate::schedule::app_for_job(&apps, &job).await? else {
+        return Response::error("Conflict (the app of the merge is not configured)", 409);
+    };
+    match crate::schedule::merge(&d1, app, &job, ADMIN).await? {
+        MergeOutcome::Busy => {
+            return Response::error("Service Unavailable (the repository is busy)", 503)
+        }
+        MergeOutcome::NotPending => {
+            return Response::error("Conflict (the merge is no longer pending)", 409)
+        }
+        _ => {}
+    }
+
+    Response::from_json(&db::merge::find_by_id(&d1, id).await?)
+}
+
+/// `GET /admin/audit?owner=&repository=&pr=&actor=&limit=`
+pub async fn list_audit(req: Request, ctx: Ctx) -> Result<Response> {
+    if let Some(res) = authorize(&req, &ctx.env)? {
+        return Ok(res);
+    }
+    let params = query_params(&req)?;
+    let Some(limit) = limit(&params) else {
+        return Response::error("Bad Request (limit is not a number)", 400);
+    };
+    let pr_number = match params.get("pr").map(|pr| pr.parse::<u64>()) {
+        Some(Ok(pr)) => Some(pr),
+        Some(Err(_)) => return Response::error("Bad Request (pr is not a number)", 400),
+        None => None,
+    };
+
+    let filter = db::audit::Filter {
+        owner: params.get("owner").map(String::as_str),
+        repository: params.get("repository").map(String::as_str),
+        pr_number,
+        actor: params.get("actor").map(String::as_str),
+    };
+    let entries = db::audit::list(&ctx.env.d1("DB")?, &filter, limit).await?;
+
+    Response::from_json(&entries)
+}
+
+/// `GET /admin/dead-letters?all=1&limit=`
+/// デフォルトではまだ再実行されていないものだけ
+pub async fn list_dead_letters(req: Request, ctx: Ctx) -> Result<Response> {
+    if let Some(res) = authorize(&req, &ctx.env)? {
+        return Ok(res);
+    }
+    let params = query_params(&req)?;
+    let Some(limit) = limit(&params) else {
+        return Response::error("Bad Request (limit is not a number)", 400);
+    };
+    let pending_only = !params.get("all").is_some_and(|all| all == "1");
+
+    let dead_letters =
+        db::delivery::list_dead_letters(&ctx.env.d1("DB")?, pending_only, limit).await?;
+
+    Response::from_json(&dead_letters)
+}
+
+/// `POST /admin/dead-letters/:id/replay`
+/// 次のcronか`POST /admin/schedule`で再実行される
+pub async fn replay_dead_letter(req: Request, ctx: Ctx) -> Result<Response> {
+    if let Some(res) = authorize(&req, &ctx.env)? {
+        return Ok(res);
+    }
+    let Some(id) = id_param(&ctx) else {
+        return Response::error("Bad Request (id is not a number)", 400);
+    };
+
+    if !db::delivery::request_replay(&ctx.env.d1("DB")?, id).await? {
+        return Response::error("Not Found (or already replayed)", 404);
+    }
+
+    Ok(Response::empty()?.with_status(202))
+}
+
+/// `POST /admin/status-link?hours=`
+/// `/status`を見るための署名付きリンクを作る。デフォルトは24時間有効
+pub async fn status_link(req: Request, ctx: Ctx) -> Result<Response> {
+    if let Some(res) = authorize(&req, &ctx.env)? {
+        return Ok(res);
+    }
+    let params = query_params(&req)?;
+    let hours = match params.get("hours").map(|hours| hours.parse::<i64>()) {
+        Some(Ok(hours)) if (1..=24 * 30).contains(&hours) => hours,
+        Some(_) => return Response::error("Bad Request (hours must be 1 to 720)", 400),
+        None => 24,
+    };
+
+    let expires = chrono::Utc::now().timestamp() + hours * 3600;
+    let origin = req.url()?.origin().ascii_serialization();
+    let Some(url) = crate::status::signed_link(&ctx.env, &origin, expires) else {
+        return Response::error("Not Found (STATUS_LINK_SECRET is not set)", 404);
+    };
+
+    Response::from_json(&serde_json::json!({ "url": url, "expires": expires }))
+}
+
+/// `POST /admin/schedule`
+/// cronを待たずにスケジューラを動かす
+pub async fn run_scheduler(req: Request, ctx: Ctx) -> Result<Response> {
+    if let Some(res) = authorize(&req, &ctx.env)? {
+        return Ok(res);
+    }
+
+    crate::run_scheduled(&ctx.env).await?;
+
+    Ok(Response::empty()?.with_status(204))
+}
@@ -0,0 +1,138 @@
+//! 1つのWorkerで複数のGitHub Appを扱うための設定
+//! `GITHUB_APPS`に`production,staging`のように書くと、
+//! それぞれ`GITHUB_CLIENT_ID_PRODUCTION`のようにsuffixのついたsecretを使う
+//! 書かれていない場合はsuffixなしのものを`default`として使う
+
+use worker::*;
+
+use crate::crypt::GitHubApp;
+
+const DEFAULT_APP: &str = "default";
+
+pub struct AppConfig {
+    pub name: String,
+    /// 設定されていなければ`GET /app`で取得する
+    app_id: Option<u64>,
+    pub github_app: GitHubApp,
+    /// `crypt::parse_secrets`の形式
+    pub webhook_secret: String,
+    /// コマンドを使える人。空ならリポジトリのownerだけ
+    pub authorised_users: Vec<String>,
+    /// `parser::Trigger::parse_list`の形式。なければメンションと`/`
+    pub command_triggers: Option<String>,
+}
+
+/// `a,b`のようなカンマ区切りの設定
+fn split_list(value: &str) -> Vec<String> {
+    value
+        .split(',')
+        .map(str::trim)
+        .filter(|item| !item.is_empty())
+        .map(String::from)
+        .collect()
+}
+
+impl AppConfig {
+    pub fn names(env: &Env) -> Vec<String> {
+        match env.var("GITHUB_APPS") {
+            Ok(apps) => split_list(&apps.to_string()),
+            Err(_) => vec![DEFAULT_APP.into()],
+        }
+    }
+
+    fn suffix(name: &str) -> String {
+        if name == DEFAULT_APP {
+            String::new()
+        } else {
+            format!("_{}", name.to_uppercase())
+        }
+    }
+
+    /// 鍵を読まずに`GITHUB_APP_ID`だけを読む
+    fn configured_app_id(env: &Env, name: &str) -> Result<Option<u64>> {
+        let suffix = Self::suffix(name);
+        match env.var(&format!("GITHUB_APP_ID{suffix}")) {
+            Ok(id) => Ok(Some(id.to_string().parse().map_err(|e| {
+                Error::RustError(format!("GITHUB_APP_ID{suffix} is not a number: {e}"))
+            })?)),
+            Err(_) => Ok(None),
+        }
+    }
+
+    pub fn load(env: &Env, name: &str) -> Result<Self> {
+        let suffix = Self::suffix(name);
+
+        Ok(AppConfig {
+            name: name.into(),
+            app_id: Self::configured_app_id(env, name)?,
+            github_app: GitHubApp::new(
+                &env.secret(&format!("GITHUB_PRIVATE_KEY{suffix}"))?
+                    .to_string(),
+                &env.secret(&format!("GITHUB_CLIENT_ID{suffix}"))?
+                    .to_string(),
+            )?,
+            webhook_secret: env.secret(&format!("WEBHOOK_SEC{suffix}"))?.to_string(),
+            authorised_users: env
+                .var(&format!("AUTHORISED_USERS{suffix}"))
+                .map(|users| split_list(&users.to_string()))
+                .unwrap_or_default(),
+            command_triggers: env
+                .var(&format!("COMMAND_TRIGGERS{suffix}"))
+                .ok()
+                .map(|triggers| triggers.to_string()),
+        })
+    }
+
+    pub fn load_all(env: &Env) -> Result<Vec<Self>> {
+        Self::names(env)
+            .iter()
+            .map(|name| Self::load(env, name))
+            .collect()
+    }
+
+    pub async fn app_id(&self) -> Result<u64> {
+        match self.app_id {
+            Some(id) => Ok(id),
+            None => Ok(self.github_app.identity().await?.id),
+        }
+    }
+
+    /// `/webhook/{app}`の`{app}`か`X-GitHub-Hook-Installation-Target-ID`からAppを選ぶ
+    pub async fn resolve(
+        env: &Env,
+        route_app: Option<&str>,
+        target_id: Option<u64>,
+    ) -> Result<Option<Self>> {
+        let names = Self::names(env);
+
+        if let Some(route_app) = route_app {
+            return match names.iter().find(|name| name.as_str() == route_app) {
+                Some(name) => Ok(Some(Self::load(env, name)?)),
+                None => Ok(None),
+            };
+        }
+
+        if names.len() == 1 {
+            return Ok(Some(Self::load(env, &names[0])?));
+        }
+
+        // 鍵を読むのは選んだAppだけにする
+        if let Some(target_id) = target_id {
+            for name in &names {
+                match Self::configured_app_id(env, name)? {
+                    Some(id) if id == target_id => return Ok(Some(Self::load(env, name)?)),
+                    Some(_) => {}
+                    // IDが設定されていなければ`GET /app`で確かめる
+                    None => {
+                        let app = Self::load(env, name)?;
+                        if app.app_id().await? == target_id {
+                            return Ok(Some(app));
+                        }
+                    }
+                }
+            }
+        }
+
+        Ok(None)
+    }
+}
@@ -0,0 +1,339 @@
+//! PRのheadに"Scheduled merge"というチェックを作って予定を表示する
+//! ジョブの状態が変わるたびに更新する
+//! 待っている間はボタン (`requested_action`) で今すぐマージ、キャンセル、1時間延期ができる
+
+use chrono::NaiveDateTime;
+use worker::*;
+
+use crate::db::{
+    self,
+    merge::{MergeJob, State},
+};
+use crate::github::{
+    create_check_run, get_pull_request, update_check_run, CheckRun, CheckRunAction, CheckRunOutput,
+    PullRequest, Repo, Token,
+};
+use crate::merge_queue::{Repository, Sender};
+use crate::parser::format_jst;
+
+pub const NAME: &str = "Scheduled merge";
+
+// 使うところだけ自前でデシリアライズする
+
+#[derive(Debug, serde::Deserialize)]
+pub struct CheckRunEvent {
+    pub action: String,
+    pub check_run: CheckRunPayload,
+    /// `requested_action`のとき
+    pub requested_action: Option<RequestedAction>,
+    pub repository: Repository,
+    pub installation: Installation,
+    pub sender: Sender,
+}
+
+#[derive(Debug, serde::Deserialize)]
+pub struct CheckRunPayload {
+    pub id: u64,
+    pub name: String,
+    pub pull_requests: Vec<CheckRunPullRequest>,
+}
+
+#[derive(Debug, serde::Deserialize)]
+pub struct CheckRunPullRequest {
+    pub number: u64,
+}
+
+#[derive(Debug, serde::Deserialize)]
+pub struct RequestedAction {
+    pub identifier: String,
+}
+
+#[derive(Debug, serde::Deserialize)]
+pub struct Installation {
+    pub id: u64,
+}
+
+/// チェックのボタン
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum Action {
+    MergeNow,
+    Cancel,
+    /// 1時間延期する
+    Postpone,
+}
+
+impl Action {
+    const ALL: [Action; 3] = [Action::MergeNow, Action::Cancel, Action::Postpone];
+
+    fn identifier(self) -> &'static str {
+        match self {
+            Action::MergeNow => "merge_now",
+            Action::Cancel => "cancel",
+            Action::Postpone => "postpone_1h",
+        }
+    }
+
+    pub fn from_identifier(identifier: &str) -> Option<Action> {
+        Self::ALL
+            .into_iter()
+            .find(|action| action.identifier() == identifier)
+    }
+
+    pub fn label(self) -> &'static str {
+        match self {
+            Action::MergeNow => "Merge now",
+            Action::Cancel => "Cancel schedule",
+            Action::Postpone => "Postpone 1h",
+        }
+    }
+
+    fn to_check_run_action(self) -> CheckRunAction {
+        CheckRunAction {
+            label: self.label(),
+            description: match self {
+                Action::MergeNow => "Merge without waiting for the time",
+                Action::Cancel => "Cancel the automatic merge",
+                Action::Postpone => "Merge one hour later than scheduled",
+            },
+            identifier: self.identifier(),
+        }
+    }
+}
+
+/// PRの一番新しいジョブに合わせてチェックを作るか更新する
+/// headが変わっていたら新しいコミットに作り直す
+pub async fn sync(d1: &D1Database, repo: &Repo<'_>, pr_number: u64, token: &Token) -> Result<()> {
+    let Some(job) = db::merge::find_latest_by_pr(d1, repo, pr_number).await? else {
+        return Ok(());
+    };
+    let pr = get_pull_request(pr_number, repo, token).await?;
+    let group = match job.group_id {
+        Some(group_id) => db::group::find_by_id(d1, group_id)
+            .await?
+            .map(|group| group.name),
+        None => None,
+    };
+    let check_run = render(&job, group.as_deref(), pr.mergeable_state.as_deref());
+
+    match job.check_run_id {
+        Some(id) if job.check_run_sha.as_deref() == Some(pr.head.sha.as_str()) => {
+            update_check_run(repo, id, &check_run, token).await
+        }
+        _ => {
+            let id = create_check_run(repo, &pr.head.sha, &check_run, token).await?;
+            db::merge::set_check_run(d1, job.id, id, &pr.head.sha).await
+        }
+    }
+}
+
+/// 表示のためだけなので失敗してもログだけ残す
+pub async fn try_sync(d1: &D1Database, repo: &Repo<'_>, pr_number: u64, token: &Token) {
+    if let Err(e) = sync(d1, repo, pr_number, token).await {
+        console_error!(
+            "Failed to update the check run of {}/{}#{pr_number}: {e}",
+            repo.owner,
+            repo.name
+        );
+    }
+}
+
+/// `group`はグループの名前
+fn render(job: &MergeJob, group: Option<&str>, mergeable_state: Option<&str>) -> CheckRun<'static> {
+    let after = job
+        .after()
+        .map(|(repo, number)| crate::dependency::display(&repo, number));
+    let (title, conclusion) = match job.state {
+        State::Pending => match group {
+            Some(group) => (format!("Waiting for the merge group `{group}`"), "neutral"),
+            None => ("Waiting for the scheduled time".into(), "neutral"),
+        },
+        State::Blocked => (
+            format!(
+                "Waiting for {} to be merged",
+                after.as_deref().unwrap_or("another Pull Request")
+            ),
+            "neutral",
+        ),
+        State::Suspended => (
+            "Suspended because the installation is suspended".into(),
+            "neutral",
+        ),
+        State::Merging => ("Merging".into(), "neutral"),
+        State::AutoMerge => ("Auto-merge has been enabled".into(), "neutral"),
+        State::Queued => ("In the merge queue".into(), "neutral"),
+        State::Merged => ("Merged".into(), "success"),
+        State::Failed => ("Failed to merge".into(), "failure"),
+        State::Cancelled => ("Cancelled".into(), "cancelled"),
+    };
+    let actions = match job.state {
+        // グループはまとめてマージするので、1つだけ先にマージしたり延期したりはできない
+        State::Pending | State::Suspended if group.is_some() => {
+            vec![Action::Cancel.to_check_run_action()]
+        }
+        State::Pending | State::Suspended => Action::ALL.map(Action::to_check_run_action).into(),
+        // マージされるまで待つので延期やすぐにマージはできない
+        State::Blocked => vec![Action::Cancel.to_check_run_action()],
+        _ => Vec::new(),
+    };
+
+    let scheduled_at = NaiveDateTime::parse_from_str(&job.will_merged_at, "%Y-%m-%d %H:%M:%S")
+        .map(format_jst)
+        .unwrap_or_else(|_| job.will_merged_at.clone());
+    let scheduled_by = job
+        .scheduled_by
+        .as_ref()
+        .map_or("Unknown".into(), |login| format!("@{login}"));
+
+    let mut summary = format!(
+        "| | |\n| --- | --- |\n| Scheduled at | {scheduled_at} |\n| Scheduled by | {scheduled_by} |\n| State | `{}` |\n| Readiness | {} |\n",
+        job.state.as_str(),
+        readiness(mergeable_state),
+    );
+    if let Some(after) = &after {
+        summary.push_str(&format!("| Merge after | {after} |\n"));
+    }
+    if let Some(group) = group {
+        summary.push_str(&format!("| Merge group | `{group}` |\n"));
+    }
+
+    CheckRun {
+        name: NAME,
+        conclusion,
+        output: CheckRunOutput { title, summary },
+        actions,
+    }
+}
+
+/// GitHubが`mergeable_state`を計算し終わるのを待つ間隔。合わせて数秒で諦める
+const COMPUTE_WAITS: [std::time::Duration; 3] = [
+    std::time::Duration::from_secs(1),
+    std::time::Duration::from_secs(2),
+    std::time::Duration::from_secs(4),
+];
+
+/// `mergeable_state`が計算されているか。取得した直後はnullか`unknown`のことがある
+pub fn is_computed(mergeable_state: Option<&str>) -> bool {
+    !matches!(mergeable_state, None | Some("unknown"))
+}
+
+/// PRを取得する。`mergeable_state`が計算されるまで少し待って取り直す
+/// 待っても計算されなければそのまま返すので`is_computed`で確かめる
+pub async fn get_computed_pull_request(
+    pr_number: u64,
+    repo: &Repo<'_>,
+    token: &Token,
+) -> Result<PullRequest> {
+    let mut pr = get_pull_request(pr_number, repo, token).await?;
+    for wait in COMPUTE_WAITS {
+        if pr.state == "closed" || is_computed(pr.mergeable_state.as_deref()) {
+            break;
+        }
+        Delay::from(wait).await;
+        pr = get_pull_request(pr_number, repo, token).await?;
+    }
+    Ok(pr)
+}
+
+/// マージしてよい`mergeable_state`か
+pub fn is_ready(mergeable_state: Option<&str>) -> bool {
+    matches!(mergeable_state, Some("clean" | "has_hooks"))
+}
+
+/// `mergeable_state`の説明
+pub fn readiness(mergeable_state: Option<&str>) -> &'static str {
+    match mergeable_state {
+        Some("clean" | "has_hooks") => "Ready to merge",
+        Some("unstable") => "Mergeable, but some checks are not passing",
+        Some("blocked") => "Blocked by branch protection (reviews or required checks)",
+        Some("behind") => "The head branch is behind the base branch",
+        Some("dirty") => "There are merge conflicts",
+        Some("draft") => "The Pull Request is a draft",
+        _ => "Not computed yet",
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::{Action, MergeJob, State};
+
+    fn job(state: State) -> MergeJob {
+        MergeJob {
+            id: 1,
+            pr_number: 2,
+            owner: "owner".into(),
+            repository: "repo".into(),
+            will_merged_at: "2024-12-31 09:00:00".into(),
+            state,
+            installation_id: 3,
+            app_id: None,
+            repository_id: Some(4),
+            pr_node_id: None,
+            check_run_id: None,
+            check_run_sha: None,
+            scheduled_by: Some("satler-git".into()),
+            after_owner: None,
+            after_repository: None,
+            after_pr_number: None,
+            retarget_base: None,
+            group_id: None,
+        }
+    }
+
+    #[test]
+    fn test_action_identifier() {
+        for action in Action::ALL {
+            assert_eq!(Action::from_identifier(action.identifier()), Some(action));
+            // GitHubの制限
+            assert!(action.identifier().len() <= 20);
+            assert!(action.label().len() <= 20);
+            assert!(action.to_check_run_action().description.len() <= 40);
+        }
+        assert_eq!(Action::from_identifier("unknown"), None);
+    }
+
+    #[test]
+    fn test_render() {
+        let pending = super::render(&job(State::Pending), None, Some("clean"));
+        assert_eq!(pending.conclusion, "neutral");
+        assert_eq!(pending.actions.len(), 3);
+        assert!(pending.output.summary.contains("2024-12-31 18:00 JST"));
+        assert!(pending.output.summary.contains("@satler-git"));
+        assert!(pending.output.summary.contains("Ready to merge"));
+
+        let mut blocked = job(State::Blocked);
+        blocked.after_owner = Some("owner".into());
+        blocked.after_repository = Some("other".into());
+        blocked.after_pr_number = Some(5);
+        let blocked = super::render(&blocked, None, None);
+        assert_eq!(
+            blocked.output.title,
+            "Waiting for owner/other#5 to be merged"
+        );
+        assert_eq!(blocked.actions.len(), 1);
+        assert!(blocked
+            .output
+            .summary
+            .contains("| Merge after | owner/other#5 |"));
+
+        let mut grouped = job(State::Pending);
+        grouped.group_id = Some(6);
+        let grouped = super::render(&grouped, Some("api-v2"), Some("clean"));
+        assert_eq!(grouped.output.title, "Waiting for the merge group `api-v2`");
+        assert_eq!(grouped.actions.len(), 1);
+        assert!(grouped
+            .output
+            .summary
+            .contains("| Merge group | `api-v2` |"));
+
+        let merged = super::render(&job(State::Merged), None, None);
+        assert_eq!(merged.conclusion, "success");
+        assert!(merged.actions.is_empty());
+    }
+    #[test]
+    fn test_is_computed() {
+        assert!(!super::is_computed(None));
+        assert!(!super::is_computed(Some("unknown")));
+        assert!(super::is_computed(Some("clean")));
+        assert!(super::is_computed(Some("blocked")));
+    }
+}
@@ -0,0 +1,217 @@
+//! D1へのアクセスをまとめている
+//! スキーマは`migrations/`が正で、`schema.sql`はそれを全部適用した結果
+//! SQLは全部ここに置いて、テストでマイグレーションを適用したSQLiteに対して検証する
+
+pub mod audit;
+pub mod delivery;
+pub mod group;
+pub mod installation;
+pub mod lease;
+pub mod merge;
+pub mod recurring;
+pub mod setting;
+
+#[cfg(test)]
+mod tests {
+    use rusqlite::Connection;
+
+    fn migrations() -> Vec<(String, String)> {
+        let dir = concat!(env!("CARGO_MANIFEST_DIR"), "/migrations");
+
+        let mut migrations = std::fs::read_dir(dir)
+            .unwrap()
+            .map(|entry| {
+                let path = entry.unwrap().path();
+                (
+                    path.file_name().unwrap().to_string_lossy().into_owned(),
+                    std::fs::read_to_string(&path).unwrap(),
+                )
+            })
+            .collect::<Vec<_>>();
+        migrations.sort();
+        migrations
+    }
+
+    fn migrated() -> Connection {
+        let conn = Connection::open_in_memory().unwrap();
+        for (name, sql) in migrations() {
+            conn.execute_batch(&sql)
+                .unwrap_or_else(|e| panic!("{name}: {e}"));
+        }
+        conn
+    }
+
+    /// ALTER TABLEでsqlite_masterのSQLは書き換わるので、カラムとインデックスで比べる
+    fn schema(conn: &Connection) -> Vec<String> {
+        let mut schema = vec![];
+
+        let mut objects = conn
+            .prepare("SELECT type, name, tbl_name, sql FROM sqlite_master WHERE name NOT LIKE 'sqlite_%' ORDER BY name")
+            .unwrap();
+        let objects = objects
+            .query_map([], |row| {
+                Ok((
+                    row.get::<_, String>(0)?,
+                    row.get::<_, String>(1)?,
+                    row.get::<_, String>(2)?,
+                    row.get::<_, Option<String>>(3)?,
+                ))
+            })
+            .unwrap()
+            .collect::<Result<Vec<_>, _>>()
+            .unwrap();
+
+        for (kind, name, table, sql) in objects {
+            match kind.as_str() {
+                "table" => {
+                    let mut columns = conn.prepare(&format!("PRAGMA table_info({name})")).unwrap();
+                    let columns = columns
+                        .query_map([], |row| {
+                            Ok(format!(
+                                "{name}.{} {} notnull={} default={:?} pk={}",
+                                row.get::<_, String>(1)?,
+                                row.get::<_, String>(2)?,
+                                row.get::<_, i64>(3)?,
+                                row.get::<_, Option<String>>(4)?,
+                                row.get::<_, i64>(5)?,
+                            ))
+                        })
+                        .unwrap()
+                        .collect::<Result<Vec<_>, _>>()
+                        .unwrap();
+                    schema.extend(columns);
+                }
+                _ => {
+                    let sql = sql
+                        .map(|sql| sql.split_whitespace().collect::<Vec<_>>().join(" "))
+                        .unwrap_or_default();
+                    schema.push(format!("{kind} {name} on {table}: {sql}"));
+                }
+            }
+        }
+
+        schema
+    }
+
+    #[test]
+    fn test_schema_sql_matches_migrations() {
+        let conn = Connection::open_in_memory().unwrap();
+        conn.execute_batch(include_str!("../schema.sql")).unwrap();
+
+        assert_eq!(schema(&conn), schema(&migrated()));
+    }
+
+    #[test]
+    fn test_migrations_keep_data() {
+        let conn = Connection::open_in_memory().unwrap();
+        let migrations = migrations();
+
+        // 0003までは適用済みのデータベースがある。適用済みのものは書き換えないで、後に追加する
+        let (applied, added) = migrations.split_at(3);
+        for (_, sql) in applied {
+            conn.execute_batch(sql).unwrap();
+        }
+        conn.execute(
+            "INSERT INTO merge (pr_number, owner, repository, will_merged_at, installation_id) VALUES (1, 'owner', 'repo', '2024-12-31 07:00:00', 1)",
+            [],
+        )
+        .unwrap();
+
+        for (name, sql) in added {
+            conn.execute_batch(sql)
+                .unwrap_or_else(|e| panic!("{name}: {e}"));
+        }
+
+        let count: i64 = conn
+            .query_row("SELECT COUNT(*) FROM merge", [], |row| row.get(0))
+            .unwrap();
+        assert_eq!(count, 1);
+    }
+
+    #[test]
+    fn test_one_active_job_per_pr() {
+        let conn = migrated();
+        let insert = |state: &str| {
+            conn.execute(
+                "INSERT INTO merge (pr_number, owner, repository, repository_id, will_merged_at, state) VALUES (1, 'owner', 'repo', 10, '2024-12-31 07:00:00', ?1)",
+                [state],
+            )
+        };
+
+        insert("merged").unwrap();
+        insert("cancelled").unwrap();
+        insert("pending").unwrap();
+        assert!(insert("pending").is_err());
+        assert!(insert("suspended").is_err());
+        assert!(insert("blocked").is_err());
+    }
+
+    #[test]
+    fn test_settle_by_pr() {
+        use super::merge::SETTLE_BY_PR;
+
+        let conn = migrated();
+        conn.execute(
+            "INSERT INTO merge (pr_number, owner, repository, repository_id, will_merged_at, state) VALUES (1, 'owner', 'repo', 10, '2024-12-31 07:00:00', 'auto_merge')",
+            [],
+        )
+        .unwrap();
+        // `settle_by_pr`と同じ順に渡す
+        let settle = |pr_number: u64| {
+            conn.prepare(SETTLE_BY_PR)
+                .unwrap()
+                .query_map(
+                    rusqlite::params![pr_number, Some(10), "owner", "repo", "merged"],
+                    |row| row.get::<_, i64>(0),
+                )
+                .unwrap()
+                .count()
+        };
+
+        assert_eq!(settle(2), 0);
+        assert_eq!(settle(1), 1);
+        // 既に記録されている
+        assert_eq!(settle(1), 0);
+        let state: String = conn
+            .query_row("SELECT state FROM merge WHERE pr_number = 1", [], |row| {
+                row.get(0)
+            })
+            .unwrap();
+        assert_eq!(state, "merged");
+    }
+
+    #[test]
+    fn test_one_active_group_per_name() {
+        let conn = migrated();
+        let insert = |installation_id: i64, state: &str| {
+            conn.execute(
+                "INSERT INTO merge_group (installation_id, name, will_merged_at, state) VALUES (?1, 'api-v2', '2024-12-31 09:00:00', ?2)",
+                rusqlite::params![installation_id, state],
+            )
+        };
+
+        insert(1, "merged").unwrap();
+        insert(1, "pending").unwrap();
+        assert!(insert(1, "merging").is_err());
+        // installationが違えば別のグループ
+        insert(2, "pending").unwrap();
+    }
+
+    #[test]
+    fn test_queries() {
+        let conn = migrated();
+
+        for sql in super::audit::QUERIES
+            .iter()
+            .chain(super::delivery::QUERIES)
+            .chain(super::group::QUERIES)
+            .chain(super::installation::QUERIES)
+            .chain(super::lease::QUERIES)
+            .chain(super::merge::QUERIES)
+            .chain(super::recurring::QUERIES)
+            .chain(super::setting::QUERIES)
+        {
+            conn.prepare(sql).unwrap_or_else(|e| panic!("{sql}: {e}"));
+        }
+    }
+}
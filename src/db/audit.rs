@@ -0,0 +1,172 @@
+//! `audit_log`テーブル
+//! 誰が何をしてどうなったかを残す
+
+use worker::*;
+
+use crate::github::Repo;
+
+/// cronで行われた操作のactor
+pub const SCHEDULER: &str = "scheduler";
+
+/// 操作の結果
+#[derive(Debug, Clone, Copy, PartialEq, Eq, serde::Deserialize, serde::Serialize)]
+#[serde(rename_all = "lowercase")]
+pub enum Outcome {
+    Success,
+    /// 過去の時間を指定したなど、条件を満たしていなかった
+    Rejected,
+    /// 権限がなかった
+    Denied,
+    /// GitHubやD1でエラーが起きた
+    Failed,
+}
+
+impl Outcome {
+    pub fn as_str(self) -> &'static str {
+        match self {
+            Outcome::Success => "success",
+            Outcome::Rejected => "rejected",
+            Outcome::Denied => "denied",
+            Outcome::Failed => "failed",
+        }
+    }
+}
+
+/// `audit_log`テーブルの行
+#[derive(Debug, serde::Deserialize, serde::Serialize)]
+pub struct AuditEntry {
+    pub id: u64,
+    /// UTC
+    pub created_at: String,
+    pub actor: String,
+    pub installation_id: Option<u64>,
+    pub repository_id: Option<u64>,
+    pub owner: Option<String>,
+    pub repository: Option<String>,
+    pub pr_number: Option<u64>,
+    pub action: String,
+    pub command: Option<String>,
+    pub result: Outcome,
+    pub error: Option<String>,
+}
+
+/// 新しく記録するもの
+pub struct NewAuditEntry<'a> {
+    pub actor: &'a str,
+    pub installation_id: Option<u64>,
+    pub repo: Option<Repo<'a>>,
+    pub pr_number: Option<u64>,
+    /// `merge.add`や`installation.deleted`など
+    pub action: &'a str,
+    pub command: Option<&'a str>,
+    pub result: Outcome,
+    pub error: Option<&'a str>,
+}
+
+const INSERT: &str = "INSERT INTO audit_log (actor, installation_id, repository_id, owner, repository, pr_number, action, command, result, error)
+    VALUES (?1, ?2, ?3, ?4, ?5, ?6, ?7, ?8, ?9, ?10)";
+
+// repository_idがない古い行は名前で探す
+const SELECT_BY_PR: &str = "SELECT * FROM audit_log
+    WHERE pr_number = ?1 AND (repository_id = ?2 OR (repository_id IS NULL AND (owner, repository) = (?3, ?4)))
+    ORDER BY id DESC LIMIT ?5";
+// NULLのものは絞り込まない
+const LIST: &str = "SELECT * FROM audit_log
+    WHERE (?1 IS NULL OR owner = ?1) AND (?2 IS NULL OR repository = ?2) AND (?3 IS NULL OR pr_number = ?3)
+    AND (?4 IS NULL OR actor = ?4)
+    ORDER BY id DESC LIMIT ?5";
+
+const RENAME_REPOSITORY: &str =
+    "UPDATE audit_log SET owner = ?2, repository = ?3 WHERE repository_id = ?1";
+
+const PRUNE: &str = "DELETE FROM audit_log WHERE created_at < DATETIME('now', '-90 days')";
+
+#[cfg(test)]
+pub(super) const QUERIES: &[&str] = &[INSERT, SELECT_BY_PR, LIST, RENAME_REPOSITORY, PRUNE];
+
+pub fn insert_query(d1: &D1Database, entry: &NewAuditEntry<'_>) -> Result<D1PreparedStatement> {
+    query!(
+        &d1,
+        INSERT,
+        entry.actor,
+        entry.installation_id,
+        entry.repo.and_then(|repo| repo.id),
+        entry.repo.map(|repo| repo.owner),
+        entry.repo.map(|repo| repo.name),
+        entry.pr_number,
+        entry.action,
+        entry.command,
+        entry.result.as_str(),
+        entry.error,
+    )
+}
+
+/// 記録に失敗しても元の操作は失敗させない
+pub async fn record(d1: &D1Database, entry: &NewAuditEntry<'_>) {
+    let result = match insert_query(d1, entry) {
+        Ok(query) => query.run().await.map(|_| ()),
+        Err(e) => Err(e),
+    };
+
+    if let Err(e) = result {
+        console_error!("Failed to write the audit log of {}: {e}", entry.action);
+    }
+}
+
+/// 新しい順
+pub async fn find_by_pr(
+    d1: &D1Database,
+    repo: &Repo<'_>,
+    pr_number: u64,
+    limit: u32,
+) -> Result<Vec<AuditEntry>> {
+    let query = query!(
+        &d1,
+        SELECT_BY_PR,
+        pr_number,
+        repo.id,
+        repo.owner,
+        repo.name,
+        limit
+    )?;
+    query.run().await?.results::<AuditEntry>()
+}
+
+/// 管理用の絞り込み
+#[derive(Debug, Default)]
+pub struct Filter<'a> {
+    pub owner: Option<&'a str>,
+    pub repository: Option<&'a str>,
+    pub pr_number: Option<u64>,
+    pub actor: Option<&'a str>,
+}
+
+/// 新しい順
+pub async fn list(d1: &D1Database, filter: &Filter<'_>, limit: u32) -> Result<Vec<AuditEntry>> {
+    let query = query!(
+        &d1,
+        LIST,
+        filter.owner,
+        filter.repository,
+        filter.pr_number,
+        filter.actor,
+        limit
+    )?;
+    query.run().await?.results::<AuditEntry>()
+}
+
+pub fn rename_repository_query(
+    d1: &D1Database,
+    repository_id: u64,
+    owner: &str,
+    name: &str,
+) -> Result<D1PreparedStatement> {
+    query!(&d1, RENAME_REPOSITORY, repository_id, owner, name)
+}
+
+/// 90日より古いものを消す
+pub async fn prune(d1: &D1Database) -> Result<()> {
+    let delete_query = query!(&d1, PRUNE);
+    delete_query.run().await?;
+    Ok(())
+}
@@ -0,0 +1,181 @@
+//! `webhook_delivery`、`command_execution`、`dead_letter`テーブル
+//! GitHubは最大3日前の配送まで再送できるのでそれだけ覚えておく
+
+use worker::*;
+
+/// `dead_letter`テーブルの行 (再実行に必要なところだけ)
+#[derive(Debug, serde::Deserialize)]
+pub struct DeadLetter {
+    pub id: u64,
+    /// `AppConfig`の名前
+    pub app: String,
+    pub event: String,
+    pub payload: String,
+}
+
+/// 管理用に一覧するときの`dead_letter`テーブルの行 (payload以外)
+#[derive(Debug, serde::Deserialize, serde::Serialize)]
+pub struct DeadLetterSummary {
+    pub id: u64,
+    pub app: String,
+    pub delivery_id: Option<String>,
+    pub event: String,
+    pub error: String,
+    pub attempts: u32,
+    pub replay_requested: u8,
+    /// UTC
+    pub created_at: String,
+    pub replayed_at: Option<String>,
+}
+
+/// 新しく記録する失敗した配送
+pub struct NewDeadLetter<'a> {
+    pub app: &'a str,
+    pub delivery_id: Option<&'a str>,
+    pub event: &'a str,
+    pub payload: &'a str,
+    pub error: &'a str,
+}
+
+// 期限切れのものは上書きして新しく処理する
+const CLAIM_DELIVERY: &str =
+    "INSERT INTO webhook_delivery (id, expires_at) VALUES (?1, DATETIME('now', '+3 days'))
+    ON CONFLICT (id) DO UPDATE SET expires_at = excluded.expires_at
+    WHERE webhook_delivery.expires_at < DATETIME('now')
+    RETURNING id";
+const RELEASE_DELIVERY: &str = "DELETE FROM webhook_delivery WHERE id = ?1";
+const PRUNE_DELIVERIES: &str = "DELETE FROM webhook_delivery WHERE expires_at < DATETIME('now')";
+
+const CLAIM_COMMAND: &str =
+    "INSERT INTO command_execution (id, expires_at) VALUES (?1, DATETIME('now', '+3 days'))
+    ON CONFLICT (id) DO UPDATE SET expires_at = excluded.expires_at
+    WHERE command_execution.expires_at < DATETIME('now')
+    RETURNING id";
+const RELEASE_COMMAND: &str = "DELETE FROM command_execution WHERE id = ?1";
+const PRUNE_COMMANDS: &str = "DELETE FROM command_execution WHERE expires_at < DATETIME('now')";
+
+const INSERT_DEAD_LETTER: &str =
+    "INSERT INTO dead_letter (app, delivery_id, event, payload, error) VALUES (?1, ?2, ?3, ?4, ?5)";
+const SELECT_REPLAY_REQUESTED: &str = "SELECT id, app, event, payload FROM dead_letter
+    WHERE replay_requested = 1 AND replayed_at IS NULL LIMIT ?1";
+const LIST_DEAD_LETTERS: &str = "SELECT id, app, delivery_id, event, error, attempts, replay_requested, created_at, replayed_at FROM dead_letter
+    WHERE (?1 = 0 OR replayed_at IS NULL)
+    ORDER BY id DESC LIMIT ?2";
+const REQUEST_REPLAY: &str =
+    "UPDATE dead_letter SET replay_requested = 1 WHERE id = ?1 AND replayed_at IS NULL RETURNING id";
+const MARK_REPLAYED: &str = "UPDATE dead_letter SET replay_requested = 0, replayed_at = DATETIME('now'), attempts = attempts + 1 WHERE id = ?1";
+const MARK_REPLAY_FAILED: &str = "UPDATE dead_letter SET replay_requested = 0, error = ?2, attempts = attempts + 1 WHERE id = ?1";
+
+#[cfg(test)]
+pub(super) const QUERIES: &[&str] = &[
+    CLAIM_DELIVERY,
+    RELEASE_DELIVERY,
+    PRUNE_DELIVERIES,
+    CLAIM_COMMAND,
+    RELEASE_COMMAND,
+    PRUNE_COMMANDS,
+    INSERT_DEAD_LETTER,
+    SELECT_REPLAY_REQUESTED,
+    LIST_DEAD_LETTERS,
+    REQUEST_REPLAY,
+    MARK_REPLAYED,
+    MARK_REPLAY_FAILED,
+];
+
+pub async fn claim_delivery(d1: &D1Database, delivery_id: &str) -> Result<bool> {
+    claim(d1, CLAIM_DELIVERY, delivery_id).await
+}
+
+pub async fn release_delivery(d1: &D1Database, delivery_id: &str) -> Result<()> {
+    let query = query!(&d1, RELEASE_DELIVERY, delivery_id)?;
+    query.run().await?;
+    Ok(())
+}
+
+/// `source_id`はコマンドが書かれたコメントなどを一意に表すもの (`issue_comment:123`)
+pub async fn claim_command(d1: &D1Database, source_id: &str) -> Result<bool> {
+    claim(d1, CLAIM_COMMAND, source_id).await
+}
+
+pub async fn release_command(d1: &D1Database, source_id: &str) -> Result<()> {
+    let query = query!(&d1, RELEASE_COMMAND, source_id)?;
+    query.run().await?;
+    Ok(())
+}
+
+/// まだ処理されていなければ処理済みにしてtrueを返す
+async fn claim(d1: &D1Database, sql: &str, id: &str) -> Result<bool> {
+    #[derive(Debug, serde::Deserialize)]
+    struct Res {
+        #[allow(dead_code)]
+        id: String,
+    }
+
+    let query = query!(&d1, sql, id)?;
+    let result = query.run().await?.results::<Res>()?;
+
+    Ok(!result.is_empty())
+}
+
+/// 期限切れのものを消す
+pub async fn prune(d1: &D1Database) -> Result<()> {
+    let delete_delivery_query = query!(&d1, PRUNE_DELIVERIES);
+    let delete_command_query = query!(&d1, PRUNE_COMMANDS);
+
+    d1.batch(vec![delete_delivery_query, delete_command_query])
+        .await?;
+    Ok(())
+}
+
+pub async fn insert_dead_letter(d1: &D1Database, dead_letter: &NewDeadLetter<'_>) -> Result<()> {
+    let insert_query = query!(
+        &d1,
+        INSERT_DEAD_LETTER,
+        dead_letter.app,
+        dead_letter.delivery_id,
+        dead_letter.event,
+        dead_letter.payload,
+        dead_letter.error,
+    )?;
+    insert_query.run().await?;
+    Ok(())
+}
+
+/// `replay_requested = 1`にされたもの
+pub async fn replay_requested(d1: &D1Database, limit: u32) -> Result<Vec<DeadLetter>> {
+    let query = query!(&d1, SELECT_REPLAY_REQUESTED, limit)?;
+    query.run().await?.results::<DeadLetter>()
+}
+
+/// 新しい順。`pending_only`ならまだ再実行されていないものだけ
+pub async fn list_dead_letters(
+    d1: &D1Database,
+    pending_only: bool,
+    limit: u32,
+) -> Result<Vec<DeadLetterSummary>> {
+    let query = query!(&d1, LIST_DEAD_LETTERS, pending_only as u8, limit)?;
+    query.run().await?.results::<DeadLetterSummary>()
+}
+
+/// 次のcronで再実行されるようにする。再実行済みか存在しなければfalse
+pub async fn request_replay(d1: &D1Database, id: u64) -> Result<bool> {
+    #[derive(Debug, serde::Deserialize)]
+    struct Res {
+        #[allow(dead_code)]
+        id: u64,
+    }
+
+    let query = query!(&d1, REQUEST_REPLAY, id)?;
+
+    Ok(!query.run().await?.results::<Res>()?.is_empty())
+}
+
+/// 失敗したらエラーを更新して再実行の指定を外す
+pub async fn mark_replayed(d1: &D1Database, id: u64, error: Option<&worker::Error>) -> Result<()> {
+    let update_query = match error {
+        None => query!(&d1, MARK_REPLAYED, id)?,
+        Some(e) => query!(&d1, MARK_REPLAY_FAILED, id, &e.to_string())?,
+    };
+    update_query.run().await?;
+    Ok(())
+}
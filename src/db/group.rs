@@ -0,0 +1,168 @@
+//! `merge_group`テーブル
+//! メンバーは`merge.group_id`で持つ
+
+use chrono::NaiveDateTime;
+use worker::*;
+
+/// グループの状態
+#[derive(Debug, Clone, Copy, PartialEq, Eq, serde::Deserialize, serde::Serialize)]
+#[serde(rename_all = "lowercase")]
+pub enum GroupState {
+    Pending,
+    /// cronがメンバーを確かめてマージしている最中
+    Merging,
+    /// 全部マージされたかGitHubに任せた
+    Merged,
+    /// 揃わなかったか、一部をマージできなかった
+    Failed,
+    /// 時間になったときに待っているメンバーがいなかった
+    Cancelled,
+}
+
+impl GroupState {
+    pub fn as_str(self) -> &'static str {
+        match self {
+            GroupState::Pending => "pending",
+            GroupState::Merging => "merging",
+            GroupState::Merged => "merged",
+            GroupState::Failed => "failed",
+            GroupState::Cancelled => "cancelled",
+        }
+    }
+}
+
+/// `merge_group`テーブルの行 (使うところだけ)
+#[derive(Debug, serde::Deserialize, serde::Serialize)]
+pub struct MergeGroup {
+    pub id: u64,
+    pub installation_id: u64,
+    /// NULLなら最初のApp
+    pub app_id: Option<u64>,
+    pub name: String,
+    /// UTC
+    pub will_merged_at: String,
+    pub state: GroupState,
+}
+
+// 同じ名前のグループが待っていれば時間を変える。マージ中なら何も返さない
+const UPSERT: &str = "INSERT INTO merge_group (installation_id, app_id, name, will_merged_at, created_by) VALUES (?1, ?2, ?3, ?4, ?5)
+    ON CONFLICT (installation_id, name) WHERE state IN ('pending', 'merging') DO UPDATE SET
+        will_merged_at = excluded.will_merged_at
+    WHERE merge_group.state = 'pending'
+    RETURNING id, installation_id, app_id, name, will_merged_at, state";
+const FIND_PENDING_BY_NAME: &str =
+    "SELECT id, installation_id, app_id, name, will_merged_at, state FROM merge_group
+    WHERE installation_id = ?1 AND name = ?2 AND state = 'pending'";
+const FIND_BY_ID: &str =
+    "SELECT id, installation_id, app_id, name, will_merged_at, state FROM merge_group WHERE id = ?1";
+// suspendされている間は実行しない
+const SELECT_DUE: &str =
+    "SELECT id, installation_id, app_id, name, will_merged_at, state FROM merge_group
+    WHERE state = 'pending' AND will_merged_at <= DATETIME('now')
+    AND installation_id NOT IN (SELECT id FROM installation WHERE suspended = 1)
+    ORDER BY will_merged_at LIMIT ?1";
+const START: &str = "UPDATE merge_group SET state = 'merging', started_at = DATETIME('now')
+    WHERE id = ?1 AND state = 'pending'
+    RETURNING id";
+const FINISH: &str = "UPDATE merge_group SET state = ?2 WHERE id = ?1 AND state = 'merging'";
+// マージ中にWorkerが落ちたもの。待っているメンバーだけもう一度確かめる
+const RESET_STALE: &str = "UPDATE merge_group SET state = 'pending'
+    WHERE state = 'merging' AND started_at < DATETIME('now', '-10 minutes')";
+
+#[cfg(test)]
+pub(super) const QUERIES: &[&str] = &[
+    UPSERT,
+    FIND_PENDING_BY_NAME,
+    FIND_BY_ID,
+    SELECT_DUE,
+    START,
+    FINISH,
+    RESET_STALE,
+];
+
+/// グループを作るか、待っていれば時間を変える
+/// マージ中ならNone
+pub async fn create(
+    d1: &D1Database,
+    installation_id: u64,
+    app_id: u64,
+    name: &str,
+    will_merged_at: NaiveDateTime,
+    created_by: &str,
+) -> Result<Option<MergeGroup>> {
+    let query = query!(
+        &d1,
+        UPSERT,
+        installation_id,
+        app_id,
+        name,
+        &will_merged_at.to_string(),
+        created_by,
+    )?;
+
+    Ok(query
+        .run()
+        .await?
+        .results::<MergeGroup>()?
+        .into_iter()
+        .next())
+}
+
+/// 参加できるグループ。名前はinstallationごと
+pub async fn find_pending_by_name(
+    d1: &D1Database,
+    installation_id: u64,
+    name: &str,
+) -> Result<Option<MergeGroup>> {
+    let query = query!(&d1, FIND_PENDING_BY_NAME, installation_id, name)?;
+
+    Ok(query
+        .run()
+        .await?
+        .results::<MergeGroup>()?
+        .into_iter()
+        .next())
+}
+
+pub async fn find_by_id(d1: &D1Database, id: u64) -> Result<Option<MergeGroup>> {
+    let query = query!(&d1, FIND_BY_ID, id)?;
+
+    Ok(query
+        .run()
+        .await?
+        .results::<MergeGroup>()?
+        .into_iter()
+        .next())
+}
+
+/// 時間が来たグループ
+pub async fn due(d1: &D1Database, limit: u32) -> Result<Vec<MergeGroup>> {
+    let query = query!(&d1, SELECT_DUE, limit)?;
+    query.run().await?.results::<MergeGroup>()
+}
+
+/// 待っているグループをマージ中にする。他が始めていればfalse
+pub async fn start(d1: &D1Database, id: u64) -> Result<bool> {
+    #[derive(Debug, serde::Deserialize)]
+    struct Res {
+        #[allow(dead_code)]
+        id: u64,
+    }
+
+    let query = query!(&d1, START, id)?;
+
+    Ok(!query.run().await?.results::<Res>()?.is_empty())
+}
+
+pub async fn finish(d1: &D1Database, id: u64, state: GroupState) -> Result<()> {
+    let update_query = query!(&d1, FINISH, id, state.as_str())?;
+    update_query.run().await?;
+    Ok(())
+}
+
+/// マージ中のまま残ったものを待っている状態に戻す
+pub async fn reset_stale(d1: &D1Database) -> Result<()> {
+    let update_query = query!(&d1, RESET_STALE);
+    update_query.run().await?;
+    Ok(())
+}
@@ -0,0 +1,108 @@
+//! `installation`と`installation_repository`テーブル
+
+use worker::*;
+
+/// `installation`テーブルの行
+#[derive(Debug)]
+pub struct Installation<'a> {
+    pub id: u64,
+    pub app_id: u64,
+    pub account: &'a str,
+}
+
+/// `installation_repository`テーブルの行
+#[derive(Debug)]
+pub struct InstallationRepository<'a> {
+    pub installation_id: u64,
+    pub repository_id: u64,
+    pub owner: &'a str,
+    pub name: &'a str,
+}
+
+const UPSERT: &str = "INSERT INTO installation (id, app_id, account) VALUES (?1, ?2, ?3)
+    ON CONFLICT (id) DO UPDATE SET app_id = excluded.app_id, account = excluded.account, suspended = 0";
+const DELETE: &str = "DELETE FROM installation WHERE id = ?1";
+const SET_SUSPENDED: &str = "UPDATE installation SET suspended = ?2 WHERE id = ?1";
+
+const UPSERT_REPOSITORY: &str = "INSERT INTO installation_repository (installation_id, repository_id, owner, name) VALUES (?1, ?2, ?3, ?4)
+    ON CONFLICT (installation_id, repository_id) DO UPDATE SET owner = excluded.owner, name = excluded.name";
+const DELETE_REPOSITORY: &str =
+    "DELETE FROM installation_repository WHERE (installation_id, repository_id) = (?1, ?2)";
+const DELETE_REPOSITORIES: &str = "DELETE FROM installation_repository WHERE installation_id = ?1";
+const RENAME_REPOSITORY: &str =
+    "UPDATE installation_repository SET owner = ?2, name = ?3 WHERE repository_id = ?1";
+
+#[cfg(test)]
+pub(super) const QUERIES: &[&str] = &[
+    UPSERT,
+    DELETE,
+    SET_SUSPENDED,
+    UPSERT_REPOSITORY,
+    DELETE_REPOSITORY,
+    DELETE_REPOSITORIES,
+    RENAME_REPOSITORY,
+];
+
+/// 作り直された場合はsuspendも解除される
+pub fn upsert_query(
+    d1: &D1Database,
+    installation: &Installation<'_>,
+) -> Result<D1PreparedStatement> {
+    query!(
+        &d1,
+        UPSERT,
+        installation.id,
+        installation.app_id,
+        installation.account
+    )
+}
+
+pub fn delete_query(d1: &D1Database, installation_id: u64) -> Result<D1PreparedStatement> {
+    query!(&d1, DELETE, installation_id)
+}
+
+pub fn set_suspended_query(
+    d1: &D1Database,
+    installation_id: u64,
+    suspended: bool,
+) -> Result<D1PreparedStatement> {
+    query!(&d1, SET_SUSPENDED, installation_id, suspended as u8)
+}
+
+pub fn upsert_repository_query(
+    d1: &D1Database,
+    repository: &InstallationRepository<'_>,
+) -> Result<D1PreparedStatement> {
+    query!(
+        &d1,
+        UPSERT_REPOSITORY,
+        repository.installation_id,
+        repository.repository_id,
+        repository.owner,
+        repository.name
+    )
+}
+
+pub fn delete_repository_query(
+    d1: &D1Database,
+    installation_id: u64,
+    repository_id: u64,
+) -> Result<D1PreparedStatement> {
+    query!(&d1, DELETE_REPOSITORY, installation_id, repository_id)
+}
+
+pub fn delete_repositories_query(
+    d1: &D1Database,
+    installation_id: u64,
+) -> Result<D1PreparedStatement> {
+    query!(&d1, DELETE_REPOSITORIES, installation_id)
+}
+
+pub fn rename_repository_query(
+    d1: &D1Database,
+    repository_id: u64,
+    owner: &str,
+    name: &str,
+) -> Result<D1PreparedStatement> {
+    query!(&d1, RENAME_REPOSITORY, repository_id, owner, name)
+}
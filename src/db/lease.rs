@@ -0,0 +1,83 @@
+//! `repository_lease`テーブル
+//! 同じリポジトリのマージ、キャンセル、再スケジュールが同時に走らないようにする
+//! Workerが落ちても期限が切れれば他が取れる
+
+use std::time::Duration;
+
+use worker::*;
+
+use crate::github::Repo;
+
+/// 取ったまま落ちても、この時間が経てば他が取れる
+const ACQUIRE: &str = "INSERT INTO repository_lease (key, holder, expires_at) VALUES (?1, ?2, DATETIME('now', '+60 seconds'))
+    ON CONFLICT (key) DO UPDATE SET holder = excluded.holder, expires_at = excluded.expires_at
+    WHERE repository_lease.expires_at < DATETIME('now')
+    RETURNING key";
+const RELEASE: &str = "DELETE FROM repository_lease WHERE key = ?1 AND holder = ?2";
+const PRUNE: &str = "DELETE FROM repository_lease WHERE expires_at < DATETIME('now')";
+
+#[cfg(test)]
+pub(super) const QUERIES: &[&str] = &[ACQUIRE, RELEASE, PRUNE];
+
+/// 取れなかったときに待つ間隔
+const RETRY_INTERVAL: Duration = Duration::from_millis(250);
+
+/// リポジトリのリース。使い終わったら`release`する
+pub struct RepoLease<'a> {
+    d1: &'a D1Database,
+    key: String,
+    holder: String,
+}
+
+/// idが分かっていればrenameされても同じものになる
+pub fn key(repo: &Repo<'_>) -> String {
+    match repo.id {
+        Some(id) => format!("id:{id}"),
+        None => format!("name:{}/{}", repo.owner, repo.name),
+    }
+}
+
+impl<'a> RepoLease<'a> {
+    /// `wait`の間取れなければNone
+    pub async fn acquire(
+        d1: &'a D1Database,
+        repo: &Repo<'_>,
+        wait: Duration,
+    ) -> Result<Option<RepoLease<'a>>> {
+        #[derive(Debug, serde::Deserialize)]
+        struct Res {
+            #[allow(dead_code)]
+            key: String,
+        }
+
+        let key = key(repo);
+        let holder = format!("{:016x}", (js_sys::Math::random() * u64::MAX as f64) as u64);
+        let mut waited = Duration::ZERO;
+
+        loop {
+            let query = query!(&d1, ACQUIRE, &key, &holder)?;
+            if !query.run().await?.results::<Res>()?.is_empty() {
+                return Ok(Some(RepoLease { d1, key, holder }));
+            }
+
+            if waited >= wait {
+                return Ok(None);
+            }
+            Delay::from(RETRY_INTERVAL).await;
+            waited += RETRY_INTERVAL;
+        }
+    }
+
+    pub async fn release(self) -> Result<()> {
+        let query = query!(&self.d1, RELEASE, &self.key, &self.holder)?;
+        query.run().await?;
+        Ok(())
+    }
+}
+
+/// 期限切れのものを消す
+pub async fn prune(d1: &D1Database) -> Result<()> {
+    let delete_query = query!(&d1, PRUNE);
+    delete_query.run().await?;
+    Ok(())
+}
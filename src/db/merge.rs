@@ -0,0 +1,709 @@
+//! `merge`テーブル
+
+use chrono::NaiveDateTime;
+use worker::*;
+
+use crate::github::Repo;
+
+/// ジョブの状態
+/// `Pending`と`Blocked`、`Suspended`、`Merging`がアクティブで、PRごとに1つまでしか存在しない
+#[derive(Debug, Clone, Copy, PartialEq, Eq, serde::Deserialize, serde::Serialize)]
+#[serde(rename_all = "lowercase")]
+pub enum State {
+    Pending,
+    /// 先にマージされるのを待っているPRがある
+    Blocked,
+    /// installationがsuspendされている間
+    Suspended,
+    /// リポジトリのリースを持ってマージしている最中
+    Merging,
+    Merged,
+    /// GitHubのauto-mergeを有効にした。マージはGitHubがする
+    #[serde(rename = "auto_merge")]
+    AutoMerge,
+    /// マージキューに入れた。マージはGitHubがする
+    Queued,
+    /// マージを試みたが失敗した
+    Failed,
+    Cancelled,
+}
+
+impl State {
+    pub fn as_str(self) -> &'static str {
+        match self {
+            State::Pending => "pending",
+            State::Blocked => "blocked",
+            State::Suspended => "suspended",
+            State::Merging => "merging",
+            State::Merged => "merged",
+            State::AutoMerge => "auto_merge",
+            State::Queued => "queued",
+            State::Failed => "failed",
+            State::Cancelled => "cancelled",
+        }
+    }
+}
+
+/// `merge`テーブルの行 (使うところだけ)
+#[derive(Debug, serde::Deserialize, serde::Serialize)]
+pub struct MergeJob {
+    pub id: u64,
+    pub pr_number: u64,
+    pub owner: String,
+    pub repository: String,
+    /// UTC
+    pub will_merged_at: String,
+    pub state: State,
+    pub installation_id: u64,
+    /// NULLなら最初のApp
+    pub app_id: Option<u64>,
+    pub repository_id: Option<u64>,
+    /// 古い行にはない
+    pub pr_node_id: Option<String>,
+    /// "Scheduled merge"のチェック。まだ作っていなければNone
+    pub check_run_id: Option<u64>,
+    /// チェックを作ったときのheadのコミット
+    pub check_run_sha: Option<String>,
+    /// 最後にスケジュールした人のlogin
+    pub scheduled_by: Option<String>,
+    /// 先にマージされるのを待っているPR
+    pub after_owner: Option<String>,
+    pub after_repository: Option<String>,
+    pub after_pr_number: Option<u64>,
+    /// スタックのPRなら、下のPRがマージされた後にこのブランチをベースにする
+    pub retarget_base: Option<String>,
+    /// 一緒にマージするグループ。グループのものはcronでまとめてマージする
+    pub group_id: Option<u64>,
+}
+
+impl MergeJob {
+    pub fn repo(&self) -> Repo<'_> {
+        Repo {
+            owner: &self.owner,
+            name: &self.repository,
+            id: self.repository_id,
+        }
+    }
+
+    /// 先にマージされるのを待っているPR。別のリポジトリかもしれないので名前だけ
+    pub fn after(&self) -> Option<(Repo<'_>, u64)> {
+        match (
+            &self.after_owner,
+            &self.after_repository,
+            self.after_pr_number,
+        ) {
+            (Some(owner), Some(name), Some(number)) => Some((
+                Repo {
+                    owner,
+                    name,
+                    id: None,
+                },
+                number,
+            )),
+            _ => None,
+        }
+    }
+}
+
+/// 待っているジョブがあるリポジトリ
+#[derive(Debug, serde::Deserialize)]
+pub struct PendingRepository {
+    pub owner: String,
+    pub repository: String,
+    pub repository_id: Option<u64>,
+}
+
+impl PendingRepository {
+    pub fn repo(&self) -> Repo<'_> {
+        Repo {
+            owner: &self.owner,
+            name: &self.repository,
+            id: self.repository_id,
+        }
+    }
+}
+
+/// 新しくスケジュールするもの
+pub struct NewMergeJob<'a> {
+    pub pr_number: u64,
+    pub repo: Repo<'a>,
+    /// UTC
+    pub will_merged_at: NaiveDateTime,
+    pub installation_id: u64,
+    pub app_id: u64,
+    pub pr_node_id: &'a str,
+    pub scheduled_by: &'a str,
+    /// このPRがマージされるまで待つ
+    pub after: Option<(Repo<'a>, u64)>,
+    pub retarget_base: Option<&'a str>,
+    pub group_id: Option<u64>,
+}
+
+// アクティブなジョブがあれば時間などを上書きする。suspendされていればそのまま
+// 上書きできる状態のものだけ更新して、作ったか更新した行を返す
+const UPSERT: &str = "INSERT INTO merge (pr_number, owner, repository, will_merged_at, installation_id, app_id, repository_id, pr_node_id, scheduled_by, after_owner, after_repository, after_pr_number, state, retarget_base, group_id)
+    VALUES (?1, ?2, ?3, ?4, ?5, ?6, ?7, ?8, ?9, ?10, ?11, ?12, ?13, ?14, ?15)
+    ON CONFLICT (repository_id, pr_number) WHERE state NOT IN ('merged', 'failed', 'cancelled') DO UPDATE SET
+        will_merged_at = excluded.will_merged_at,
+        installation_id = excluded.installation_id,
+        app_id = excluded.app_id,
+        owner = excluded.owner,
+        repository = excluded.repository,
+        pr_node_id = excluded.pr_node_id,
+        scheduled_by = excluded.scheduled_by,
+        after_owner = excluded.after_owner,
+        after_repository = excluded.after_repository,
+        after_pr_number = excluded.after_pr_number,
+        retarget_base = excluded.retarget_base,
+        group_id = excluded.group_id,
+        attempts = 0,
+        state = CASE merge.state WHEN 'suspended' THEN 'suspended' ELSE excluded.state END
+    WHERE merge.state IN ('pending', 'blocked', 'suspended')
+    RETURNING id";
+
+// repository_idがない古い行は名前で探す
+const FIND_LATEST_BY_PR: &str = "SELECT id, pr_number, owner, repository, will_merged_at, state, installation_id, app_id, repository_id, pr_node_id, check_run_id, check_run_sha, scheduled_by, after_owner, after_repository, after_pr_number, retarget_base, group_id FROM merge
+    WHERE pr_number = ?1 AND (repository_id = ?2 OR (repository_id IS NULL AND (owner, repository) = (?3, ?4)))
+    ORDER BY id DESC LIMIT 1";
+const CANCEL_BY_PR: &str = "UPDATE merge SET state = 'cancelled'
+    WHERE pr_number = ?1 AND (repository_id = ?2 OR (repository_id IS NULL AND (owner, repository) = (?3, ?4)))
+    AND state IN ('pending', 'suspended', 'blocked')
+    RETURNING id";
+
+// 普段はDurable Objectのアラームで実行されるので、それに漏れたものだけ
+// グループのものは`group`でまとめて実行するので、アラームとこれでは見ない
+const SELECT_OVERDUE: &str = "SELECT id, pr_number, owner, repository, will_merged_at, state, installation_id, app_id, repository_id, pr_node_id, check_run_id, check_run_sha, scheduled_by, after_owner, after_repository, after_pr_number, retarget_base, group_id FROM merge
+    WHERE state = 'pending' AND group_id IS NULL AND will_merged_at < DATETIME('now', '-2 minutes') LIMIT ?1";
+const SELECT_PENDING_REPOSITORIES: &str =
+    "SELECT DISTINCT owner, repository, repository_id FROM merge WHERE state = 'pending' AND group_id IS NULL";
+const SELECT_PENDING_REPOSITORIES_BY_INSTALLATION: &str =
+    "SELECT DISTINCT owner, repository, repository_id FROM merge WHERE state = 'pending' AND group_id IS NULL AND installation_id = ?1";
+// repository_idがない古い行は名前で探す
+const SELECT_DUE_IN_REPOSITORY: &str = "SELECT id, pr_number, owner, repository, will_merged_at, state, installation_id, app_id, repository_id, pr_node_id, check_run_id, check_run_sha, scheduled_by, after_owner, after_repository, after_pr_number, retarget_base, group_id FROM merge
+    WHERE state = 'pending' AND group_id IS NULL AND will_merged_at <= DATETIME('now')
+    AND (repository_id = ?1 OR (repository_id IS NULL AND (owner, repository) = (?2, ?3)))
+    ORDER BY will_merged_at LIMIT ?4";
+const SELECT_NEXT_IN_REPOSITORY: &str = "SELECT will_merged_at FROM merge
+    WHERE state = 'pending' AND group_id IS NULL
+    AND (repository_id = ?1 OR (repository_id IS NULL AND (owner, repository) = (?2, ?3)))
+    ORDER BY will_merged_at LIMIT 1";
+// NULLのものは絞り込まない
+const LIST: &str = "SELECT id, pr_number, owner, repository, will_merged_at, state, installation_id, app_id, repository_id, pr_node_id, check_run_id, check_run_sha, scheduled_by, after_owner, after_repository, after_pr_number, retarget_base, group_id FROM merge
+    WHERE (?1 IS NULL OR state = ?1) AND (?2 IS NULL OR owner = ?2) AND (?3 IS NULL OR repository = ?3)
+    ORDER BY will_merged_at DESC LIMIT ?4";
+const FIND_BY_ID: &str = "SELECT id, pr_number, owner, repository, will_merged_at, state, installation_id, app_id, repository_id, pr_node_id, check_run_id, check_run_sha, scheduled_by, after_owner, after_repository, after_pr_number, retarget_base, group_id FROM merge
+    WHERE id = ?1";
+const CANCEL_BY_ID: &str = "UPDATE merge SET state = 'cancelled'
+    WHERE id = ?1 AND state IN ('pending', 'suspended', 'blocked')
+    RETURNING id";
+const FAIL_BY_ID: &str = "UPDATE merge SET state = 'failed' WHERE id = ?1 AND state = 'pending'";
+// 1, 2, 4...分後にやり直す
+const RETRY_LATER: &str = "UPDATE merge SET attempts = attempts + 1,
+        will_merged_at = DATETIME('now', printf('+%d minutes', 1 << attempts))
+    WHERE id = ?1 AND state = 'pending'
+    RETURNING attempts";
+const START: &str = "UPDATE merge SET state = 'merging', started_at = DATETIME('now')
+    WHERE id = ?1 AND state = 'pending'
+    RETURNING id";
+const FINISH: &str = "UPDATE merge SET state = ?2 WHERE id = ?1 AND state = 'merging'";
+// GitHubに任せたもの。同じ結果が複数のイベントで届くので、変わった行だけ返す
+pub(super) const SETTLE_BY_PR: &str = "UPDATE merge SET state = ?5
+    WHERE pr_number = ?1 AND (repository_id = ?2 OR (repository_id IS NULL AND (owner, repository) = (?3, ?4)))
+    AND state IN ('auto_merge', 'queued')
+    RETURNING id, pr_number, owner, repository, will_merged_at, state, installation_id, app_id, repository_id, pr_node_id, check_run_id, check_run_sha, scheduled_by, after_owner, after_repository, after_pr_number, retarget_base, group_id";
+// 古く確認したものから取り出して確認した時間を付ける。新しいものが後回しにされ続けないように
+// 一度も確認していないもの (NULL) が先になる
+const TAKE_BLOCKED: &str = "UPDATE merge SET last_checked_at = DATETIME('now')
+    WHERE id IN (SELECT id FROM merge WHERE state = 'blocked' ORDER BY last_checked_at, id LIMIT ?1)
+    RETURNING id, pr_number, owner, repository, will_merged_at, state, installation_id, app_id, repository_id, pr_node_id, check_run_id, check_run_sha, scheduled_by, after_owner, after_repository, after_pr_number, retarget_base, group_id";
+// GitHubの名前は大文字小文字を区別しない
+const SELECT_DEPENDENTS: &str = "SELECT id, pr_number, owner, repository, will_merged_at, state, installation_id, app_id, repository_id, pr_node_id, check_run_id, check_run_sha, scheduled_by, after_owner, after_repository, after_pr_number, retarget_base, group_id FROM merge
+    WHERE state = 'blocked'
+    AND after_owner = ?1 COLLATE NOCASE AND after_repository = ?2 COLLATE NOCASE AND after_pr_number = ?3";
+const FIND_BLOCKED_BY_NAME: &str = "SELECT id, pr_number, owner, repository, will_merged_at, state, installation_id, app_id, repository_id, pr_node_id, check_run_id, check_run_sha, scheduled_by, after_owner, after_repository, after_pr_number, retarget_base, group_id FROM merge
+    WHERE state = 'blocked' AND pr_number = ?3
+    AND owner = ?1 COLLATE NOCASE AND repository = ?2 COLLATE NOCASE
+    LIMIT 1";
+// 時間が過ぎていればすぐにマージする
+const UNBLOCK: &str =
+    "UPDATE merge SET state = 'pending', will_merged_at = MAX(will_merged_at, DATETIME('now'))
+    WHERE id = ?1 AND state = 'blocked'
+    RETURNING id";
+const SELECT_GROUP_MEMBERS: &str = "SELECT id, pr_number, owner, repository, will_merged_at, state, installation_id, app_id, repository_id, pr_node_id, check_run_id, check_run_sha, scheduled_by, after_owner, after_repository, after_pr_number, retarget_base, group_id FROM merge
+    WHERE group_id = ?1 ORDER BY id";
+const RESCHEDULE_GROUP: &str =
+    "UPDATE merge SET will_merged_at = ?2 WHERE group_id = ?1 AND state IN ('pending', 'suspended')";
+// 揃わなかったのでマージしなかったもの
+const FAIL_GROUP: &str =
+    "UPDATE merge SET state = 'failed' WHERE group_id = ?1 AND state = 'pending'";
+const SET_CHECK_RUN: &str = "UPDATE merge SET check_run_id = ?2, check_run_sha = ?3 WHERE id = ?1";
+// マージ中にWorkerが落ちたもの。リースが切れているのでやり直す
+const RESET_STALE: &str = "UPDATE merge SET state = 'pending'
+    WHERE state = 'merging' AND started_at < DATETIME('now', '-10 minutes')";
+
+const CANCEL_BY_INSTALLATION: &str = "UPDATE merge SET state = 'cancelled'
+    WHERE installation_id = ?1 AND state IN ('pending', 'suspended', 'blocked')";
+const SUSPEND_BY_INSTALLATION: &str = "UPDATE merge SET state = 'suspended'
+    WHERE installation_id = ?1 AND state IN ('pending', 'blocked')";
+// 待っているPRがあればもう一度確認する
+const UNSUSPEND_BY_INSTALLATION: &str =
+    "UPDATE merge SET state = CASE WHEN after_pr_number IS NULL THEN 'pending' ELSE 'blocked' END
+    WHERE installation_id = ?1 AND state = 'suspended'";
+// renameされた後でも外せるようにidで探す。repository_idがない古い行は名前で探す
+const CANCEL_BY_REPOSITORY: &str = "UPDATE merge SET state = 'cancelled'
+    WHERE installation_id = ?1 AND state IN ('pending', 'suspended', 'blocked')
+    AND (repository_id = ?2 OR (repository_id IS NULL AND (owner, repository) = (?3, ?4)))";
+
+const RENAME_REPOSITORY: &str =
+    "UPDATE merge SET owner = ?2, repository = ?3 WHERE repository_id = ?1";
+// 待っているPRは名前で持っているので前の名前で探す
+const RENAME_AFTER: &str = "UPDATE merge SET after_owner = ?3, after_repository = ?4
+    WHERE after_owner = ?1 COLLATE NOCASE AND after_repository = ?2 COLLATE NOCASE
+    AND state IN ('blocked', 'suspended')";
+const ADOPT_LEGACY_REPOSITORY: &str = "UPDATE merge SET repository_id = ?1, repository = ?3
+    WHERE repository_id IS NULL AND (owner, repository) = (?2, ?4)";
+
+#[cfg(test)]
+pub(super) const QUERIES: &[&str] = &[
+    UPSERT,
+    FIND_LATEST_BY_PR,
+    CANCEL_BY_PR,
+    SELECT_OVERDUE,
+    SELECT_PENDING_REPOSITORIES,
+    SELECT_PENDING_REPOSITORIES_BY_INSTALLATION,
+    SELECT_DUE_IN_REPOSITORY,
+    SELECT_NEXT_IN_REPOSITORY,
+    LIST,
+    FIND_BY_ID,
+    CANCEL_BY_ID,
+    FAIL_BY_ID,
+    RETRY_LATER,
+    START,
+    FINISH,
+    SETTLE_BY_PR,
+    TAKE_BLOCKED,
+    SELECT_DEPENDENTS,
+    FIND_BLOCKED_BY_NAME,
+    UNBLOCK,
+    SELECT_GROUP_MEMBERS,
+    RESCHEDULE_GROUP,
+    FAIL_GROUP,
+    SET_CHECK_RUN,
+    RESET_STALE,
+    CANCEL_BY_INSTALLATION,
+    SUSPEND_BY_INSTALLATION,
+    UNSUSPEND_BY_INSTALLATION,
+    CANCEL_BY_REPOSITORY,
+    RENAME_REPOSITORY,
+    RENAME_AFTER,
+    ADOPT_LEGACY_REPOSITORY,
+];
+
+/// PRのアクティブなジョブを作るか、あれば時間を更新する
+/// 一文で行うので同時にコマンドが来ても重複しない
+/// 上書きできない状態のジョブがあればfalse
+pub async fn schedule(d1: &D1Database, job: &NewMergeJob<'_>) -> Result<bool> {
+    #[derive(Debug, serde::Deserialize)]
+    struct Res {
+        #[allow(dead_code)]
+        id: u64,
+    }
+
+    // repository_idがない古い行にもidをつけて、同じPRのジョブとして扱う
+    let mut queries = Vec::new();
+    if let Some(repository_id) = job.repo.id {
+        queries.push(adopt_legacy_repository_query(
+            d1,
+            repository_id,
+            job.repo.owner,
+            job.repo.name,
+            job.repo.name,
+        )?);
+    }
+    let upsert = queries.len();
+    queries.push(query!(
+        &d1,
+        UPSERT,
+        job.pr_number,
+        job.repo.owner,
+        job.repo.name,
+        &job.will_merged_at.to_string(),
+        job.installation_id,
+        job.app_id,
+        job.repo.id,
+        job.pr_node_id,
+        job.scheduled_by,
+        job.after.map(|(repo, _)| repo.owner),
+        job.after.map(|(repo, _)| repo.name),
+        job.after.map(|(_, number)| number),
+        if job.after.is_some() {
+            State::Blocked
+        } else {
+            State::Pending
+        }
+        .as_str(),
+        job.retarget_base,
+        job.group_id,
+    )?);
+
+    let results = d1.batch(queries).await?;
+
+    if let Some(result) = results.iter().find(|result| !result.success()) {
+        return Err(worker::Error::RustError(
+            result.error().unwrap().to_string(),
+        ));
+    }
+    Ok(!results[upsert].results::<Res>()?.is_empty())
+}
+
+/// PRの一番新しいジョブ
+pub async fn find_latest_by_pr(
+    d1: &D1Database,
+    repo: &Repo<'_>,
+    pr_number: u64,
+) -> Result<Option<MergeJob>> {
+    let query = query!(
+        &d1,
+        FIND_LATEST_BY_PR,
+        pr_number,
+        repo.id,
+        repo.owner,
+        repo.name
+    )?;
+
+    Ok(query.run().await?.results::<MergeJob>()?.into_iter().next())
+}
+
+/// アクティブなジョブをキャンセルする。なければfalse
+pub async fn cancel_by_pr(d1: &D1Database, repo: &Repo<'_>, pr_number: u64) -> Result<bool> {
+    #[derive(Debug, serde::Deserialize)]
+    struct Res {
+        #[allow(dead_code)]
+        id: u64,
+    }
+
+    let query = query!(&d1, CANCEL_BY_PR, pr_number, repo.id, repo.owner, repo.name)?;
+
+    Ok(!query.run().await?.results::<Res>()?.is_empty())
+}
+
+/// アクティブなジョブをキャンセルする。なければfalse
+pub async fn cancel_by_id(d1: &D1Database, id: u64) -> Result<bool> {
+    #[derive(Debug, serde::Deserialize)]
+    struct Res {
+        #[allow(dead_code)]
+        id: u64,
+    }
+
+    let query = query!(&d1, CANCEL_BY_ID, id)?;
+
+    Ok(!query.run().await?.results::<Res>()?.is_empty())
+}
+
+/// 実行できないジョブを失敗にする。待っているものだけ
+pub async fn fail_by_id(d1: &D1Database, id: u64) -> Result<()> {
+    let update_query = query!(&d1, FAIL_BY_ID, id)?;
+    update_query.run().await?;
+    Ok(())
+}
+
+/// 始める前に失敗したものを後に回す。何回目の失敗か。待っていなければNone
+pub async fn retry_later(d1: &D1Database, id: u64) -> Result<Option<u32>> {
+    #[derive(Debug, serde::Deserialize)]
+    struct Res {
+        attempts: u32,
+    }
+
+    let query = query!(&d1, RETRY_LATER, id)?;
+
+    Ok(query
+        .run()
+        .await?
+        .results::<Res>()?
+        .into_iter()
+        .next()
+        .map(|res| res.attempts))
+}
+
+/// 管理用の絞り込み
+#[derive(Debug, Default)]
+pub struct Filter<'a> {
+    pub state: Option<&'a str>,
+    pub owner: Option<&'a str>,
+    pub repository: Option<&'a str>,
+}
+
+/// 予定の新しい順
+pub async fn list(d1: &D1Database, filter: &Filter<'_>, limit: u32) -> Result<Vec<MergeJob>> {
+    let query = query!(
+        &d1,
+        LIST,
+        filter.state,
+        filter.owner,
+        filter.repository,
+        limit
+    )?;
+    query.run().await?.results::<MergeJob>()
+}
+
+pub async fn find_by_id(d1: &D1Database, id: u64) -> Result<Option<MergeJob>> {
+    let query = query!(&d1, FIND_BY_ID, id)?;
+
+    Ok(query.run().await?.results::<MergeJob>()?.into_iter().next())
+}
+
+/// 時間が過ぎても実行されずに待っているもの
+pub async fn overdue(d1: &D1Database, limit: u32) -> Result<Vec<MergeJob>> {
+    let query = query!(&d1, SELECT_OVERDUE, limit)?;
+    query.run().await?.results::<MergeJob>()
+}
+
+pub async fn pending_repositories(d1: &D1Database) -> Result<Vec<PendingRepository>> {
+    let query = query!(&d1, SELECT_PENDING_REPOSITORIES);
+    query.run().await?.results::<PendingRepository>()
+}
+
+pub async fn pending_repositories_by_installation(
+    d1: &D1Database,
+    installation_id: u64,
+) -> Result<Vec<PendingRepository>> {
+    let query = query!(
+        &d1,
+        SELECT_PENDING_REPOSITORIES_BY_INSTALLATION,
+        installation_id
+    );
+    query.run().await?.results::<PendingRepository>()
+}
+
+/// リポジトリの時間が過ぎていて待っているもの
+pub async fn due_in_repository(
+    d1: &D1Database,
+    repo: &Repo<'_>,
+    limit: u32,
+) -> Result<Vec<MergeJob>> {
+    let query = query!(
+        &d1,
+        SELECT_DUE_IN_REPOSITORY,
+        repo.id,
+        repo.owner,
+        repo.name,
+        limit
+    )?;
+    query.run().await?.results::<MergeJob>()
+}
+
+/// リポジトリで次にマージする時間 (UTC)
+pub async fn next_in_repository(d1: &D1Database, repo: &Repo<'_>) -> Result<Option<NaiveDateTime>> {
+    #[derive(Debug, serde::Deserialize)]
+    struct Res {
+        will_merged_at: String,
+    }
+
+    let query = query!(
+        &d1,
+        SELECT_NEXT_IN_REPOSITORY,
+        repo.id,
+        repo.owner,
+        repo.name
+    )?;
+
+    query
+        .run()
+        .await?
+        .results::<Res>()?
+        .into_iter()
+        .next()
+        .map(|res| {
+            NaiveDateTime::parse_from_str(&res.will_merged_at, "%Y-%m-%d %H:%M:%S")
+                .map_err(|e| Error::RustError(format!("Invalid will_merged_at: {e}")))
+        })
+        .transpose()
+}
+
+/// 待っているジョブをマージ中にする。キャンセルされていたりしたらfalse
+/// リポジトリのリースを持っているときに呼ぶ
+pub async fn start(d1: &D1Database, id: u64) -> Result<bool> {
+    #[derive(Debug, serde::Deserialize)]
+    struct Res {
+        #[allow(dead_code)]
+        id: u64,
+    }
+
+    let query = query!(&d1, START, id)?;
+
+    Ok(!query.run().await?.results::<Res>()?.is_empty())
+}
+
+/// 先にマージされるPRを待っているもの。最後に確認してから長いものから
+pub async fn take_blocked(d1: &D1Database, limit: u32) -> Result<Vec<MergeJob>> {
+    let query = query!(&d1, TAKE_BLOCKED, limit)?;
+    query.run().await?.results::<MergeJob>()
+}
+
+/// このPRがマージされるのを待っているもの
+pub async fn dependents(d1: &D1Database, repo: &Repo<'_>, pr_number: u64) -> Result<Vec<MergeJob>> {
+    let query = query!(&d1, SELECT_DEPENDENTS, repo.owner, repo.name, pr_number)?;
+    query.run().await?.results::<MergeJob>()
+}
+
+/// 待っているPRを辿るため。`after`には名前しかない
+pub async fn find_blocked_by_name(
+    d1: &D1Database,
+    repo: &Repo<'_>,
+    pr_number: u64,
+) -> Result<Option<MergeJob>> {
+    let query = query!(&d1, FIND_BLOCKED_BY_NAME, repo.owner, repo.name, pr_number)?;
+
+    Ok(query.run().await?.results::<MergeJob>()?.into_iter().next())
+}
+
+/// 待っているPRがマージされたので予定の時間にマージする。既に変わっていればfalse
+pub async fn unblock(d1: &D1Database, id: u64) -> Result<bool> {
+    #[derive(Debug, serde::Deserialize)]
+    struct Res {
+        #[allow(dead_code)]
+        id: u64,
+    }
+
+    let query = query!(&d1, UNBLOCK, id)?;
+
+    Ok(!query.run().await?.results::<Res>()?.is_empty())
+}
+
+/// グループのジョブ。終わったものも含む
+pub async fn group_members(d1: &D1Database, group_id: u64) -> Result<Vec<MergeJob>> {
+    let query = query!(&d1, SELECT_GROUP_MEMBERS, group_id)?;
+    query.run().await?.results::<MergeJob>()
+}
+
+/// グループの時間が変わったので、待っているジョブの時間も変える
+pub async fn reschedule_group(
+    d1: &D1Database,
+    group_id: u64,
+    will_merged_at: NaiveDateTime,
+) -> Result<()> {
+    let update_query = query!(&d1, RESCHEDULE_GROUP, group_id, &will_merged_at.to_string())?;
+    update_query.run().await?;
+    Ok(())
+}
+
+/// グループが揃わなかったので、待っているジョブを全部失敗にする
+pub async fn fail_group(d1: &D1Database, group_id: u64) -> Result<()> {
+    let update_query = query!(&d1, FAIL_GROUP, group_id)?;
+    update_query.run().await?;
+    Ok(())
+}
+
+pub async fn set_check_run(d1: &D1Database, id: u64, check_run_id: u64, sha: &str) -> Result<()> {
+    let update_query = query!(&d1, SET_CHECK_RUN, id, check_run_id, sha)?;
+    update_query.run().await?;
+    Ok(())
+}
+
+/// マージ中のまま残ったものを待っている状態に戻す
+pub async fn reset_stale(d1: &D1Database) -> Result<()> {
+    let update_query = query!(&d1, RESET_STALE);
+    update_query.run().await?;
+    Ok(())
+}
+
+/// マージを試みた結果を記録する。マージ中のものだけ更新する
+pub async fn finish(d1: &D1Database, id: u64, state: State) -> Result<()> {
+    let update_query = query!(&d1, FINISH, id, state.as_str())?;
+    update_query.run().await?;
+    Ok(())
+}
+
+/// auto-mergeかマージキューでGitHubに任せたジョブの結果を記録する
+/// 既に記録されていればNone
+pub async fn settle_by_pr(
+    d1: &D1Database,
+    repo: &Repo<'_>,
+    pr_number: u64,
+    state: State,
+) -> Result<Option<MergeJob>> {
+    let query = query!(
+        &d1,
+        SETTLE_BY_PR,
+        pr_number,
+        repo.id,
+        repo.owner,
+        repo.name,
+        state.as_str()
+    )?;
+
+    Ok(query.run().await?.results::<MergeJob>()?.into_iter().next())
+}
+
+pub fn cancel_by_installation_query(
+    d1: &D1Database,
+    installation_id: u64,
+) -> Result<D1PreparedStatement> {
+    query!(&d1, CANCEL_BY_INSTALLATION, installation_id)
+}
+
+pub fn set_suspended_by_installation_query(
+    d1: &D1Database,
+    installation_id: u64,
+    suspended: bool,
+) -> Result<D1PreparedStatement> {
+    let sql = if suspended {
+        SUSPEND_BY_INSTALLATION
+    } else {
+        UNSUSPEND_BY_INSTALLATION
+    };
+    query!(&d1, sql, installation_id)
+}
+
+pub fn cancel_by_repository_query(
+    d1: &D1Database,
+    installation_id: u64,
+    repository_id: u64,
+    owner: &str,
+    name: &str,
+) -> Result<D1PreparedStatement> {
+    query!(
+        &d1,
+        CANCEL_BY_REPOSITORY,
+        installation_id,
+        repository_id,
+        owner,
+        name
+    )
+}
+
+pub fn rename_repository_query(
+    d1: &D1Database,
+    repository_id: u64,
+    owner: &str,
+    name: &str,
+) -> Result<D1PreparedStatement> {
+    query!(&d1, RENAME_REPOSITORY, repository_id, owner, name)
+}
+
+/// renameされたリポジトリのPRを待っているもの
+pub fn rename_after_query(
+    d1: &D1Database,
+    from: &Repo<'_>,
+    to: &Repo<'_>,
+) -> Result<D1PreparedStatement> {
+    query!(&d1, RENAME_AFTER, from.owner, from.name, to.owner, to.name)
+}
+
+/// repository_idがない古い行に前の名前からidをつける
+pub fn adopt_legacy_repository_query(
+    d1: &D1Database,
+    repository_id: u64,
+    owner: &str,
+    name: &str,
+    old_name: &str,
+) -> Result<D1PreparedStatement> {
+    query!(
+        &d1,
+        ADOPT_LEGACY_REPOSITORY,
+        repository_id,
+        owner,
+        name,
+        old_name
+    )
+}
@@ -0,0 +1,228 @@
+//! `recurring_job`テーブル
+//! 種類とリポジトリごとに1つ。止めたら行を消す
+
+use chrono::NaiveDateTime;
+use worker::*;
+
+use crate::github::Repo;
+
+/// 何を実行するか
+#[derive(Debug, Clone, Copy, PartialEq, Eq, serde::Deserialize, serde::Serialize)]
+#[serde(rename_all = "lowercase")]
+pub enum Kind {
+    /// ラベルの付いたPRをまとめてマージする。`argument`はラベル
+    Train,
+}
+
+impl Kind {
+    pub fn as_str(self) -> &'static str {
+        match self {
+            Kind::Train => "train",
+        }
+    }
+}
+
+/// 大きく遅れた回 (suspendされていた間など) をどうするか
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Default, serde::Deserialize, serde::Serialize)]
+#[serde(rename_all = "lowercase")]
+pub enum CatchUp {
+    /// 実行しないで次の回に進める
+    #[default]
+    Skip,
+    /// 何回遅れても1回だけ実行する
+    Once,
+}
+
+impl CatchUp {
+    pub fn as_str(self) -> &'static str {
+        match self {
+            CatchUp::Skip => "skip",
+            CatchUp::Once => "once",
+        }
+    }
+}
+
+/// `recurring_job`テーブルの行 (使うところだけ)
+#[derive(Debug, serde::Deserialize, serde::Serialize)]
+pub struct RecurringJob {
+    pub id: u64,
+    pub kind: Kind,
+    pub installation_id: u64,
+    /// NULLなら最初のApp
+    pub app_id: Option<u64>,
+    pub repository_id: u64,
+    pub owner: String,
+    pub repository: String,
+    /// `every weekday 17:00`など。`parser::Recurrence`で読む
+    pub rule: String,
+    pub argument: Option<String>,
+    pub catch_up: CatchUp,
+    /// UTC
+    pub next_run_at: String,
+}
+
+impl RecurringJob {
+    pub fn repo(&self) -> Repo<'_> {
+        Repo {
+            owner: &self.owner,
+            name: &self.repository,
+            id: Some(self.repository_id),
+        }
+    }
+}
+
+pub struct NewRecurringJob<'a> {
+    pub kind: Kind,
+    pub repo: Repo<'a>,
+    pub repository_id: u64,
+    pub installation_id: u64,
+    pub app_id: u64,
+    pub rule: &'a str,
+    pub argument: Option<&'a str>,
+    pub catch_up: CatchUp,
+    /// UTC
+    pub next_run_at: NaiveDateTime,
+    pub updated_by: &'a str,
+}
+
+const UPSERT: &str = "INSERT INTO recurring_job (kind, installation_id, app_id, repository_id, owner, repository, rule, argument, catch_up, next_run_at, updated_by)
+    VALUES (?1, ?2, ?3, ?4, ?5, ?6, ?7, ?8, ?9, ?10, ?11)
+    ON CONFLICT (kind, repository_id) DO UPDATE SET
+        installation_id = excluded.installation_id,
+        app_id = excluded.app_id,
+        owner = excluded.owner,
+        repository = excluded.repository,
+        rule = excluded.rule,
+        argument = excluded.argument,
+        catch_up = excluded.catch_up,
+        next_run_at = excluded.next_run_at,
+        updated_by = excluded.updated_by,
+        updated_at = DATETIME('now')";
+const FIND: &str = "SELECT id, kind, installation_id, app_id, repository_id, owner, repository, rule, argument, catch_up, next_run_at FROM recurring_job
+    WHERE kind = ?1 AND repository_id = ?2";
+const DELETE: &str =
+    "DELETE FROM recurring_job WHERE kind = ?1 AND repository_id = ?2 RETURNING id";
+// suspendされている間は実行しない
+const SELECT_DUE: &str = "SELECT id, kind, installation_id, app_id, repository_id, owner, repository, rule, argument, catch_up, next_run_at FROM recurring_job
+    WHERE next_run_at <= DATETIME('now')
+    AND installation_id NOT IN (SELECT id FROM installation WHERE suspended = 1)
+    ORDER BY next_run_at LIMIT ?1";
+// 同じ回を2回実行しないように、読んだときの時間のままなら次に進める
+const ADVANCE: &str = "UPDATE recurring_job SET next_run_at = ?3, last_run_at = DATETIME('now')
+    WHERE id = ?1 AND next_run_at = ?2
+    RETURNING id";
+const DELETE_BY_INSTALLATION: &str = "DELETE FROM recurring_job WHERE installation_id = ?1";
+const DELETE_BY_REPOSITORY: &str =
+    "DELETE FROM recurring_job WHERE installation_id = ?1 AND repository_id = ?2";
+const RENAME_REPOSITORY: &str =
+    "UPDATE recurring_job SET owner = ?2, repository = ?3 WHERE repository_id = ?1";
+
+#[cfg(test)]
+pub(super) const QUERIES: &[&str] = &[
+    UPSERT,
+    FIND,
+    DELETE,
+    SELECT_DUE,
+    ADVANCE,
+    DELETE_BY_INSTALLATION,
+    DELETE_BY_REPOSITORY,
+    RENAME_REPOSITORY,
+];
+
+/// 設定を変えると次の時刻も計算し直す
+pub async fn set(d1: &D1Database, job: &NewRecurringJob<'_>) -> Result<()> {
+    let query = query!(
+        &d1,
+        UPSERT,
+        job.kind.as_str(),
+        job.installation_id,
+        job.app_id,
+        job.repository_id,
+        job.repo.owner,
+        job.repo.name,
+        job.rule,
+        job.argument,
+        job.catch_up.as_str(),
+        &job.next_run_at.to_string(),
+        job.updated_by,
+    )?;
+    query.run().await?;
+    Ok(())
+}
+
+pub async fn find(d1: &D1Database, kind: Kind, repository_id: u64) -> Result<Option<RecurringJob>> {
+    let query = query!(&d1, FIND, kind.as_str(), repository_id)?;
+
+    Ok(query
+        .run()
+        .await?
+        .results::<RecurringJob>()?
+        .into_iter()
+        .next())
+}
+
+/// 止める。なければfalse
+pub async fn delete(d1: &D1Database, kind: Kind, repository_id: u64) -> Result<bool> {
+    #[derive(Debug, serde::Deserialize)]
+    struct Res {
+        #[allow(dead_code)]
+        id: u64,
+    }
+
+    let query = query!(&d1, DELETE, kind.as_str(), repository_id)?;
+
+    Ok(!query.run().await?.results::<Res>()?.is_empty())
+}
+
+/// 時刻が来たもの
+pub async fn due(d1: &D1Database, limit: u32) -> Result<Vec<RecurringJob>> {
+    let query = query!(&d1, SELECT_DUE, limit)?;
+    query.run().await?.results::<RecurringJob>()
+}
+
+/// 次の時刻に進める。他が先に進めていればfalse
+pub async fn advance(
+    d1: &D1Database,
+    job: &RecurringJob,
+    next_run_at: NaiveDateTime,
+) -> Result<bool> {
+    #[derive(Debug, serde::Deserialize)]
+    struct Res {
+        #[allow(dead_code)]
+        id: u64,
+    }
+
+    let query = query!(
+        &d1,
+        ADVANCE,
+        job.id,
+        &job.next_run_at,
+        &next_run_at.to_string(),
+    )?;
+
+    Ok(!query.run().await?.results::<Res>()?.is_empty())
+}
+
+pub fn delete_by_installation_query(
+    d1: &D1Database,
+    installation_id: u64,
+) -> Result<D1PreparedStatement> {
+    query!(&d1, DELETE_BY_INSTALLATION, installation_id)
+}
+
+pub fn delete_by_repository_query(
+    d1: &D1Database,
+    installation_id: u64,
+    repository_id: u64,
+) -> Result<D1PreparedStatement> {
+    query!(&d1, DELETE_BY_REPOSITORY, installation_id, repository_id)
+}
+
+pub fn rename_repository_query(
+    d1: &D1Database,
+    repository_id: u64,
+    owner: &str,
+    name: &str,
+) -> Result<D1PreparedStatement> {
+    query!(&d1, RENAME_REPOSITORY, repository_id, owner, name)
+}
@@ -0,0 +1,60 @@
+//! `repository_setting`テーブル
+//! 行がなければデフォルト
+
+use worker::*;
+
+/// 予定の時間に何をするか
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Default, serde::Deserialize, serde::Serialize)]
+#[serde(rename_all = "lowercase")]
+pub enum MergeMode {
+    /// Botが直接マージする
+    #[default]
+    Direct,
+    /// GitHubのauto-mergeを有効にして、必須のチェックが通ったらGitHubがマージする
+    Auto,
+}
+
+impl MergeMode {
+    pub fn as_str(self) -> &'static str {
+        match self {
+            MergeMode::Direct => "direct",
+            MergeMode::Auto => "auto",
+        }
+    }
+}
+
+const SELECT_MERGE_MODE: &str =
+    "SELECT merge_mode FROM repository_setting WHERE repository_id = ?1";
+const UPSERT_MERGE_MODE: &str = "INSERT INTO repository_setting (repository_id, merge_mode) VALUES (?1, ?2)
+    ON CONFLICT (repository_id) DO UPDATE SET merge_mode = excluded.merge_mode, updated_at = DATETIME('now')";
+
+#[cfg(test)]
+pub(super) const QUERIES: &[&str] = &[SELECT_MERGE_MODE, UPSERT_MERGE_MODE];
+
+/// repository_idが分からない古いジョブはデフォルト
+pub async fn merge_mode(d1: &D1Database, repository_id: Option<u64>) -> Result<MergeMode> {
+    #[derive(Debug, serde::Deserialize)]
+    struct Res {
+        merge_mode: MergeMode,
+    }
+
+    let Some(repository_id) = repository_id else {
+        return Ok(MergeMode::default());
+    };
+
+    let query = query!(&d1, SELECT_MERGE_MODE, repository_id)?;
+    Ok(query
+        .run()
+        .await?
+        .results::<Res>()?
+        .into_iter()
+        .next()
+        .map(|res| res.merge_mode)
+        .unwrap_or_default())
+}
+
+pub async fn set_merge_mode(d1: &D1Database, repository_id: u64, mode: MergeMode) -> Result<()> {
+    let query = query!(&d1, UPSERT_MERGE_MODE, repository_id, mode.as_str())?;
+    query.run().await?;
+    Ok(())
+}
@@ -0,0 +1,262 @@
+//! `merge after #123`で他のPRがマージされるのを待っているジョブ (`blocked`)
+//! 待っているPRがマージされたら予定の時間 (過ぎていればすぐ) にマージして、
+//! マージされずに閉じられたらキャンセルする
+//! 同じAppが入っているリポジトリなら`pull_request.closed`ですぐに、それ以外はcronで確認する
+
+use worker::*;
+
+use crate::app::AppConfig;
+use crate::db::{
+    self,
+    audit::{NewAuditEntry, Outcome},
+    merge::MergeJob,
+};
+use crate::github::{comment_on_issue, find_pull_request, get_pull_request, Repo, Token};
+
+/// cronで一度に確認する数
+const CHECK_LIMIT: u32 = 20;
+
+/// 辿るのを諦める長さ。これより長いものは循環しているとみなす
+const MAX_CHAIN: usize = 32;
+
+/// スケジュールするときの待つPRの状態
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum Prerequisite {
+    Open,
+    Merged,
+    /// マージされずに閉じられた
+    Closed,
+    /// 存在しないかAppがアクセスできない
+    NotFound,
+    /// 辿るとこのPRに戻ってくる
+    Cycle,
+}
+
+/// `owner/repo#123`
+pub fn display(repo: &Repo<'_>, pr_number: u64) -> String {
+    format!("{}/{}#{pr_number}", repo.owner, repo.name)
+}
+
+fn same_pr(a: &Repo<'_>, a_number: u64, b: &Repo<'_>, b_number: u64) -> bool {
+    a_number == b_number
+        && a.owner.eq_ignore_ascii_case(b.owner)
+        && a.name.eq_ignore_ascii_case(b.name)
+}
+
+/// `repo#pr_number`が`after#after_number`を待てるか確かめる
+pub async fn check(
+    d1: &D1Database,
+    token: &Token,
+    repo: &Repo<'_>,
+    pr_number: u64,
+    after: &Repo<'_>,
+    after_number: u64,
+) -> Result<Prerequisite> {
+    if same_pr(repo, pr_number, after, after_number) {
+        return Ok(Prerequisite::Cycle);
+    }
+
+    // 404以外の失敗は存在しないとは言えないのでそのまま返す
+    let Some(pr) = find_pull_request(after_number, after, token).await? else {
+        return Ok(Prerequisite::NotFound);
+    };
+    if pr.merged {
+        return Ok(Prerequisite::Merged);
+    }
+    if pr.state == "closed" {
+        return Ok(Prerequisite::Closed);
+    }
+
+    // 待っているPRを辿ってこのPRに戻ってきたら循環している
+    let mut current = (
+        after.owner.to_string(),
+        after.name.to_string(),
+        after_number,
+    );
+    for _ in 0..MAX_CHAIN {
+        let current_repo = Repo {
+            owner: &current.0,
+            name: &current.1,
+            id: None,
+        };
+        let Some(job) = db::merge::find_blocked_by_name(d1, &current_repo, current.2).await? else {
+            return Ok(Prerequisite::Open);
+        };
+        let Some((next, next_number)) = job.after() else {
+            return Ok(Prerequisite::Open);
+        };
+        if same_pr(repo, pr_number, &next, next_number) {
+            return Ok(Prerequisite::Cycle);
+        }
+        current = (next.owner.to_string(), next.name.to_string(), next_number);
+    }
+
+    Ok(Prerequisite::Cycle)
+}
+
+/// cronで待っているPRを確認する
+/// 別のinstallationのリポジトリにあるPRはイベントが届かないのでこちらで見つける
+pub async fn check_blocked(d1: &D1Database, env: &Env, apps: &[AppConfig]) -> Result<()> {
+    for job in db::merge::take_blocked(d1, CHECK_LIMIT).await? {
+        let Some(app) = crate::schedule::app_for_job(apps, &job).await? else {
+            console_error!("The app {:?} is not configured", job.app_id);
+            continue;
+        };
+
+        // 1つ失敗しても残りは続ける
+        if let Err(e) = check_job(d1, env, app, &job).await {
+            console_error!("Failed to check the dependency of the job {}: {e}", job.id);
+        }
+    }
+    Ok(())
+}
+
+async fn check_job(d1: &D1Database, env: &Env, app: &AppConfig, job: &MergeJob) -> Result<()> {
+    let Some((after, after_number)) = job.after() else {
+        return Ok(());
+    };
+    let token = app.github_app.token(job.installation_id).await?;
+
+    let pr = get_pull_request(after_number, &after, &token).await?;
+    if pr.merged {
+        resolve(d1, env, &token, job, true).await
+    } else if pr.state == "closed" {
+        resolve(d1, env, &token, job, false).await
+    } else {
+        Ok(())
+    }
+}
+
+/// `pull_request.closed`が届いたので、そのPRを待っているものを進める
+pub async fn prerequisite_closed(
+    env: &Env,
+    repo: &Repo<'_>,
+    pr_number: u64,
+    merged: bool,
+) -> Result<()> {
+    let d1 = env.d1("DB")?;
+    let dependents = db::merge::dependents(&d1, repo, pr_number).await?;
+    if dependents.is_empty() {
+        return Ok(());
+    }
+
+    let apps = AppConfig::load_all(env)?;
+    for job in dependents {
+        let Some(app) = crate::schedule::app_for_job(&apps, &job).await? else {
+            console_error!("The app {:?} is not configured", job.app_id);
+            continue;
+        };
+        let token = app.github_app.token(job.installation_id).await?;
+
+        resolve(&d1, env, &token, &job, merged).await?;
+    }
+    Ok(())
+}
+
+/// マージされていれば予定の時間に、閉じられていればキャンセルする
+async fn resolve(
+    d1: &D1Database,
+    env: &Env,
+    token: &Token,
+    job: &MergeJob,
+    merged: bool,
+) -> Result<()> {
+    let Some((after, after_number)) = job.after() else {
+        return Ok(());
+    };
+    let after = display(&after, after_number);
+    let repo = job.repo();
+
+    // スタックのPRはベースブランチが消えるかもしれないので先に変える
+    // 何度変えても同じなので、cronとイベントの両方から来ても問題ない
+    if let Some(base) = job.retarget_base.as_deref().filter(|_| merged) {
+        if let Err(e) = crate::stack::retarget(job, base, token).await {
+            console_error!("Failed to retarget the job {}: {e}", job.id);
+            let message = format!(
+                "{after} has been merged, but the base branch of this Pull Request could not be changed to `{base}` ({e}), so the automatic merge has been cancelled"
+            );
+            return stop(d1, env, token, job, &message, Some(e.to_string())).await;
+        }
+    }
+
+    // 同じ結果がcronとイベントの両方から来るかもしれない
+    let (changed, action, message) = if merged {
+        (
+            db::merge::unblock(d1, job.id).await?,
+            "merge.unblock",
+            format!("{after} has been merged, so this Pull Request will be merged at the scheduled time"),
+        )
+    } else {
+        (
+            db::merge::cancel_by_id(d1, job.id).await?,
+            "merge.cancel",
+            format!("The automatic merge has been cancelled because {after} was closed without being merged"),
+        )
+    };
+    if !changed {
+        return Ok(());
+    }
+    console_log!("{after} was closed, so ran {action} on the job {}", job.id);
+
+    crate::repo_scheduler::try_sync(env, &repo).await;
+    crate::check_run::try_sync(d1, &repo, job.pr_number, token).await;
+    comment_on_issue(job.pr_number, &repo, &message, token).await?;
+
+    db::audit::record(
+        d1,
+        &NewAuditEntry {
+            actor: db::audit::SCHEDULER,
+            installation_id: Some(job.installation_id),
+            repo: Some(repo),
+            pr_number: Some(job.pr_number),
+            action,
+            command: None,
+            result: Outcome::Success,
+            error: None,
+        },
+    )
+    .await;
+
+    // スタックの下が閉じられたなら、その上も進められない
+    if !merged && job.retarget_base.is_some() {
+        crate::stack::stop(d1, token, job).await?;
+    }
+
+    Ok(())
+}
+
+/// スタックのPRのベースを変えられなかったので、このPRから上をキャンセルする
+async fn stop(
+    d1: &D1Database,
+    env: &Env,
+    token: &Token,
+    job: &MergeJob,
+    message: &str,
+    error: Option<String>,
+) -> Result<()> {
+    if !db::merge::cancel_by_id(d1, job.id).await? {
+        return Ok(());
+    }
+    let repo = job.repo();
+
+    crate::repo_scheduler::try_sync(env, &repo).await;
+    crate::check_run::try_sync(d1, &repo, job.pr_number, token).await;
+    comment_on_issue(job.pr_number, &repo, message, token).await?;
+
+    db::audit::record(
+        d1,
+        &NewAuditEntry {
+            actor: db::audit::SCHEDULER,
+            installation_id: Some(job.installation_id),
+            repo: Some(repo),
+            pr_number: Some(job.pr_number),
+            action: "merge.cancel",
+            command: None,
+            result: Outcome::Failed,
+            error: error.as_deref(),
+        },
+    )
+    .await;
+
+    crate::stack::stop(d1, token, job).await
+}
@@ -1,7 +1,12 @@
 use thiserror::Error;
 
 #[derive(Debug, Error)]
-pub(crate) enum Error {}
+pub(crate) enum Error {
+    #[error("The private key is malformed: {0}")]
+    InvalidPrivateKey(String),
+    #[error("No private key is configured")]
+    NoPrivateKey,
+}
 
 impl From<Error> for worker::Error {
     fn from(val: Error) -> Self {
@@ -10,6 +10,14 @@ pub struct GitHubEvent {
 #[derive(Debug)]
 pub enum EventType {
     IssueComment,
+    PullRequestReview,
+    PullRequestReviewComment,
+    Installation,
+    InstallationRepositories,
+    Repository,
+    PullRequest,
+    MergeGroup,
+    CheckRun,
     // _Unknown(String),
     _Unknown,
 }
@@ -19,28 +27,108 @@ use worker::{console_debug, Result};
 
 use reqwest::header;
 
-// TODO: pr_number: u64, owner: &str, repo: &str,をつくる
+/// インストールのトークン。どのAppのものか分かるように、AppのslugをUser-Agentにする
+/// ログに出ないようにDebugは実装しない
+#[derive(Clone)]
+pub struct Token {
+    pub secret: String,
+    pub user_agent: String,
+}
+
+/// idが分かっていればrenameやtransferされても追えるのでそちらでアクセスする
+#[derive(Debug, Clone, Copy)]
+pub struct Repo<'a> {
+    pub owner: &'a str,
+    pub name: &'a str,
+    pub id: Option<u64>,
+}
+
+impl Repo<'_> {
+    fn endpoint(&self) -> String {
+        match self.id {
+            Some(id) => format!("https://api.github.com/repositories/{id}"),
+            None => format!("https://api.github.com/repos/{}/{}", self.owner, self.name),
+        }
+    }
+}
+
+/// 必要なところだけ
+#[derive(Debug, serde::Deserialize)]
+pub struct PullRequest {
+    pub number: u64,
+    pub node_id: String,
+    /// `open`か`closed`
+    pub state: String,
+    #[serde(default)]
+    pub merged: bool,
+    pub head: PullRequestHead,
+    pub base: PullRequestBase,
+    /// `clean`や`blocked`、`behind`など。計算中は`unknown`
+    pub mergeable_state: Option<String>,
+}
+
+#[derive(Debug, serde::Deserialize)]
+pub struct PullRequestHead {
+    pub sha: String,
+    #[serde(rename = "ref")]
+    pub ref_name: String,
+}
+
+#[derive(Debug, serde::Deserialize)]
+pub struct PullRequestBase {
+    #[serde(rename = "ref")]
+    pub ref_name: String,
+    pub repo: PullRequestBaseRepository,
+}
+
+#[derive(Debug, serde::Deserialize)]
+pub struct PullRequestBaseRepository {
+    pub default_branch: String,
+}
+
+/// Checks APIで作るチェック。常に`completed`にして、必須のチェックにならないようにする
+#[derive(Debug, serde::Serialize)]
+pub struct CheckRun<'a> {
+    pub name: &'a str,
+    pub conclusion: &'a str,
+    pub output: CheckRunOutput,
+    /// 空にするとボタンが消える
+    pub actions: Vec<CheckRunAction>,
+}
+
+#[derive(Debug, serde::Serialize)]
+pub struct CheckRunOutput {
+    pub title: String,
+    pub summary: String,
+}
+
+/// `check_run.requested_action`で`identifier`が送られてくる
+#[derive(Debug, serde::Serialize)]
+pub struct CheckRunAction {
+    /// 20文字まで
+    pub label: &'static str,
+    /// 40文字まで
+    pub description: &'static str,
+    /// 20文字まで
+    pub identifier: &'static str,
+}
 
 pub async fn comment_on_issue<'a>(
     number: u64,
-    owner: &str,
-    repo: &str,
+    repo: &Repo<'_>,
     content: &str,
-    token: &str,
+    token: &Token,
 ) -> Result<()> {
-    let endpoint = format!(
-        "https://api.github.com/repos/{}/{}/issues/{}/comments",
-        owner, repo, number
-    );
+    let endpoint = format!("{}/issues/{}/comments", repo.endpoint(), number);
 
     let client = reqwest::Client::new();
 
     client
         .post(endpoint)
         .header("X-GitHub-Api-Version", "2022-11-28")
-        .header(header::AUTHORIZATION, format!("Bearer {token}"))
+        .header(header::AUTHORIZATION, format!("Bearer {}", token.secret))
         .header(header::ACCEPT, "application/vnd.github+json")
-        .header(header::USER_AGENT, crate::APP_NAME)
+        .header(header::USER_AGENT, &token.user_agent)
         .body(
             serde_json::json!({
                 "body": content,
@@ -53,25 +141,86 @@ pub async fn comment_on_issue<'a>(
         .map(|_| ())
 }
 
+pub async fn get_pull_request(
+    pr_number: u64,
+    repo: &Repo<'_>,
+    token: &Token,
+) -> Result<PullRequest> {
+    let endpoint = format!("{}/pulls/{}", repo.endpoint(), pr_number);
+
+    let client = reqwest::Client::new();
+
+    let res = client
+        .get(endpoint)
+        .header("X-GitHub-Api-Version", "2022-11-28")
+        .header(header::AUTHORIZATION, format!("Bearer {}", token.secret))
+        .header(header::ACCEPT, "application/vnd.github+json")
+        .header(header::USER_AGENT, &token.user_agent)
+        .send()
+        .await
+        .map_err(|e| worker::Error::RustError(format!("Error in sending a request: {e}")))?
+        .text()
+        .await
+        .map_err(|e| {
+            worker::Error::RustError(format!("Error in reading text from the body: {e}"))
+        })?;
+
+    serde_json::from_str(&res).map_err(worker::Error::SerdeJsonError)
+}
+
+/// PRを取得する。存在しないかアクセスできなければNone
+pub async fn find_pull_request(
+    pr_number: u64,
+    repo: &Repo<'_>,
+    token: &Token,
+) -> Result<Option<PullRequest>> {
+    let endpoint = format!("{}/pulls/{}", repo.endpoint(), pr_number);
+
+    let client = reqwest::Client::new();
+
+    let res = client
+        .get(endpoint)
+        .header("X-GitHub-Api-Version", "2022-11-28")
+        .header(header::AUTHORIZATION, format!("Bearer {}", token.secret))
+        .header(header::ACCEPT, "application/vnd.github+json")
+        .header(header::USER_AGENT, &token.user_agent)
+        .send()
+        .await
+        .map_err(|e| worker::Error::RustError(format!("Error in sending a request: {e}")))?;
+
+    if res.status() == reqwest::StatusCode::NOT_FOUND {
+        return Ok(None);
+    }
+    if !res.status().is_success() {
+        return Err(worker::Error::RustError(format!(
+            "Failed to get the pull request {pr_number}: {}",
+            res.status()
+        )));
+    }
+    let res = res.text().await.map_err(|e| {
+        worker::Error::RustError(format!("Error in reading text from the body: {e}"))
+    })?;
+
+    serde_json::from_str(&res)
+        .map(Some)
+        .map_err(worker::Error::SerdeJsonError)
+}
+
 pub async fn is_pr_mergeable(
     pr_number: u64,
-    owner: &str,
-    repo: &str,
-    token: &str,
+    repo: &Repo<'_>,
+    token: &Token,
 ) -> Result<Option<bool>> {
-    let endpoint = format!(
-        "https://api.github.com/repos/{}/{}/issues/{}/comments",
-        owner, repo, pr_number
-    );
+    let endpoint = format!("{}/issues/{}/comments", repo.endpoint(), pr_number);
 
     let client = reqwest::Client::new();
 
     let res = client
         .get(endpoint)
         .header("X-GitHub-Api-Version", "2022-11-28")
-        .header(header::AUTHORIZATION, format!("Bearer {token}"))
+        .header(header::AUTHORIZATION, format!("Bearer {}", token.secret))
         .header(header::ACCEPT, "application/vnd.github+json")
-        .header(header::USER_AGENT, crate::APP_NAME)
+        .header(header::USER_AGENT, &token.user_agent)
         .send()
         .await
         .map_err(|e| worker::Error::RustError(format!("Error in sending a request: {e}")))?
@@ -89,8 +238,8 @@ pub async fn is_pr_mergeable(
     Ok(pr.mergeable)
 }
 
-pub async fn marge_pr(pr_number: u64, owner: &str, repo: &str, token: &str) -> Result<()> {
-    let endpoint = format!("https://api.github.com/repos/{owner}/{repo}/pulls/{pr_number}/merge");
+pub async fn marge_pr(pr_number: u64, repo: &Repo<'_>, token: &Token) -> Result<()> {
+    let endpoint = format!("{}/pulls/{pr_number}/merge", repo.endpoint());
     #[derive(Debug, serde::Deserialize)]
     struct Res {
         // sha: String,
@@ -103,9 +252,9 @@ pub async fn marge_pr(pr_number: u64, owner: &str, repo: &str, token: &str) -> R
     let res = client
         .put(endpoint)
         .header("X-GitHub-Api-Version", "2022-11-28")
-        .header(header::AUTHORIZATION, format!("Bearer {token}"))
+        .header(header::AUTHORIZATION, format!("Bearer {}", token.secret))
         .header(header::ACCEPT, "application/vnd.github+json")
-        .header(header::USER_AGENT, crate::APP_NAME)
+        .header(header::USER_AGENT, &token.user_agent)
         .send()
         .await
         .map_err(|e| worker::Error::RustError(format!("Error in sending a request: {e}")))?
@@ -128,10 +277,365 @@ pub async fn marge_pr(pr_number: u64, owner: &str, repo: &str, token: &str) -> R
     }
 }
 
+/// チェックを作ってidを返す
+pub async fn create_check_run(
+    repo: &Repo<'_>,
+    head_sha: &str,
+    check_run: &CheckRun<'_>,
+    token: &Token,
+) -> Result<u64> {
+    let endpoint = format!("{}/check-runs", repo.endpoint());
+    #[derive(Debug, serde::Deserialize)]
+    struct Res {
+        id: u64,
+    }
+
+    let mut body = serde_json::to_value(check_run).map_err(worker::Error::SerdeJsonError)?;
+    body["head_sha"] = head_sha.into();
+    body["status"] = "completed".into();
+
+    let client = reqwest::Client::new();
+
+    let res = client
+        .post(endpoint)
+        .header("X-GitHub-Api-Version", "2022-11-28")
+        .header(header::AUTHORIZATION, format!("Bearer {}", token.secret))
+        .header(header::ACCEPT, "application/vnd.github+json")
+        .header(header::USER_AGENT, &token.user_agent)
+        .body(body.to_string())
+        .send()
+        .await
+        .map_err(|e| worker::Error::RustError(format!("Error in sending a request: {e}")))?
+        .text()
+        .await
+        .map_err(|e| {
+            worker::Error::RustError(format!("Error in reading text from the body: {e}"))
+        })?;
+
+    let res: Res = serde_json::from_str(&res).map_err(worker::Error::SerdeJsonError)?;
+    Ok(res.id)
+}
+
+pub async fn update_check_run(
+    repo: &Repo<'_>,
+    check_run_id: u64,
+    check_run: &CheckRun<'_>,
+    token: &Token,
+) -> Result<()> {
+    let endpoint = format!("{}/check-runs/{check_run_id}", repo.endpoint());
+
+    let client = reqwest::Client::new();
+
+    let res = client
+        .patch(endpoint)
+        .header("X-GitHub-Api-Version", "2022-11-28")
+        .header(header::AUTHORIZATION, format!("Bearer {}", token.secret))
+        .header(header::ACCEPT, "application/vnd.github+json")
+        .header(header::USER_AGENT, &token.user_agent)
+        .body(serde_json::to_string(check_run).map_err(worker::Error::SerdeJsonError)?)
+        .send()
+        .await
+        .map_err(|e| worker::Error::RustError(format!("Error in sending a request: {e}")))?;
+
+    if !res.status().is_success() {
+        return Err(worker::Error::RustError(format!(
+            "Failed to update the check run {check_run_id}: {}",
+            res.status()
+        )));
+    }
+    Ok(())
+}
+
+/// `branch`をheadにしている開いているPR。同じリポジトリのブランチだけ
+pub async fn find_pull_requests_by_head(
+    repo: &Repo<'_>,
+    branch: &str,
+    token: &Token,
+) -> Result<Vec<PullRequest>> {
+    let endpoint = format!("{}/pulls", repo.endpoint());
+
+    let client = reqwest::Client::new();
+
+    let res = client
+        .get(endpoint)
+        .query(&[
+            ("state", "open"),
+            ("head", &format!("{}:{branch}", repo.owner)),
+        ])
+        .header("X-GitHub-Api-Version", "2022-11-28")
+        .header(header::AUTHORIZATION, format!("Bearer {}", token.secret))
+        .header(header::ACCEPT, "application/vnd.github+json")
+        .header(header::USER_AGENT, &token.user_agent)
+        .send()
+        .await
+        .map_err(|e| worker::Error::RustError(format!("Error in sending a request: {e}")))?
+        .text()
+        .await
+        .map_err(|e| {
+            worker::Error::RustError(format!("Error in reading text from the body: {e}"))
+        })?;
+
+    serde_json::from_str(&res).map_err(worker::Error::SerdeJsonError)
+}
+
+/// ラベルの付いた開いているPRの番号。古いものから
+/// 100件まで
+pub async fn list_labelled_pull_requests(
+    repo: &Repo<'_>,
+    label: &str,
+    token: &Token,
+) -> Result<Vec<u64>> {
+    let endpoint = format!("{}/issues", repo.endpoint());
+    // IssueとPRが混ざって返ってくる
+    #[derive(Debug, serde::Deserialize)]
+    struct Issue {
+        number: u64,
+        pull_request: Option<serde::de::IgnoredAny>,
+    }
+
+    let client = reqwest::Client::new();
+
+    let mut request = client.get(endpoint).query(&[
+        ("state", "open"),
+        ("labels", label),
+        ("sort", "created"),
+        ("direction", "asc"),
+        ("per_page", "100"),
+    ]);
+    let mut pr_numbers = Vec::new();
+    // 100件を超えるときは`Link`ヘッダーの次のページを辿る
+    loop {
+        let res = request
+            .header("X-GitHub-Api-Version", "2022-11-28")
+            .header(header::AUTHORIZATION, format!("Bearer {}", token.secret))
+            .header(header::ACCEPT, "application/vnd.github+json")
+            .header(header::USER_AGENT, &token.user_agent)
+            .send()
+            .await
+            .map_err(|e| worker::Error::RustError(format!("Error in sending a request: {e}")))?;
+        let next = res
+            .headers()
+            .get(header::LINK)
+            .and_then(|link| link.to_str().ok())
+            .and_then(next_link)
+            .map(str::to_string);
+        let res = res.text().await.map_err(|e| {
+            worker::Error::RustError(format!("Error in reading text from the body: {e}"))
+        })?;
+
+        let issues: Vec<Issue> =
+            serde_json::from_str(&res).map_err(worker::Error::SerdeJsonError)?;
+        pr_numbers.extend(
+            issues
+                .into_iter()
+                .filter(|issue| issue.pull_request.is_some())
+                .map(|issue| issue.number),
+        );
+
+        let Some(next) = next else {
+            return Ok(pr_numbers);
+        };
+        request = client.get(next);
+    }
+}
+
+/// `Link`ヘッダーの`rel="next"`のURL
+fn next_link(link: &str) -> Option<&str> {
+    link.split(',').find_map(|part| {
+        let (url, params) = part.split_once(';')?;
+        params
+            .split(';')
+            .any(|param| param.trim() == r#"rel="next""#)
+            .then(|| url.trim().trim_start_matches('<').trim_end_matches('>'))
+    })
+}
+
+/// PRのベースブランチを変える
+pub async fn update_pull_request_base(
+    pr_number: u64,
+    repo: &Repo<'_>,
+    base: &str,
+    token: &Token,
+) -> Result<()> {
+    let endpoint = format!("{}/pulls/{pr_number}", repo.endpoint());
+
+    let client = reqwest::Client::new();
+
+    let res = client
+        .patch(endpoint)
+        .header("X-GitHub-Api-Version", "2022-11-28")
+        .header(header::AUTHORIZATION, format!("Bearer {}", token.secret))
+        .header(header::ACCEPT, "application/vnd.github+json")
+        .header(header::USER_AGENT, &token.user_agent)
+        .body(serde_json::json!({ "base": base }).to_string())
+        .send()
+        .await
+        .map_err(|e| worker::Error::RustError(format!("Error in sending a request: {e}")))?;
+
+    if !res.status().is_success() {
+        return Err(worker::Error::RustError(format!(
+            "Failed to change the base branch of #{pr_number}: {}",
+            res.status()
+        )));
+    }
+    Ok(())
+}
+
+/// ベースブランチをheadにマージして最新にする
+/// 非同期で行われるので`202`が返ってくる
+pub async fn update_branch(pr_number: u64, repo: &Repo<'_>, token: &Token) -> Result<()> {
+    let endpoint = format!("{}/pulls/{pr_number}/update-branch", repo.endpoint());
+
+    let client = reqwest::Client::new();
+
+    let res = client
+        .put(endpoint)
+        .header("X-GitHub-Api-Version", "2022-11-28")
+        .header(header::AUTHORIZATION, format!("Bearer {}", token.secret))
+        .header(header::ACCEPT, "application/vnd.github+json")
+        .header(header::USER_AGENT, &token.user_agent)
+        .send()
+        .await
+        .map_err(|e| worker::Error::RustError(format!("Error in sending a request: {e}")))?;
+
+    if !res.status().is_success() {
+        return Err(worker::Error::RustError(format!(
+            "Failed to update the branch of #{pr_number}: {}",
+            res.status()
+        )));
+    }
+    Ok(())
+}
+
+/// GraphQL APIを呼んで`data`を返す。`errors`があればエラーにする
+pub async fn graphql(
+    query: &str,
+    variables: serde_json::Value,
+    token: &Token,
+) -> Result<serde_json::Value> {
+    #[derive(Debug, serde::Deserialize)]
+    struct GraphQLError {
+        message: String,
+    }
+    #[derive(Debug, serde::Deserialize)]
+    struct Res {
+        data: Option<serde_json::Value>,
+        errors: Option<Vec<GraphQLError>>,
+    }
+
+    let client = reqwest::Client::new();
+
+    let res = client
+        .post("https://api.github.com/graphql")
+        .header(header::AUTHORIZATION, format!("Bearer {}", token.secret))
+        .header(header::USER_AGENT, &token.user_agent)
+        .body(
+            serde_json::json!({
+                "query": query,
+                "variables": variables,
+            })
+            .to_string(),
+        )
+        .send()
+        .await
+        .map_err(|e| worker::Error::RustError(format!("Error in sending a request: {e}")))?
+        .text()
+        .await
+        .map_err(|e| {
+            worker::Error::RustError(format!("Error in reading text from the body: {e}"))
+        })?;
+
+    console_debug!("GraphQL response: {res:?}");
+
+    let res: Res = serde_json::from_str(&res).map_err(worker::Error::SerdeJsonError)?;
+    if let Some(errors) = res.errors.filter(|errors| !errors.is_empty()) {
+        let messages = errors
+            .into_iter()
+            .map(|e| e.message)
+            .collect::<Vec<_>>()
+            .join(", ");
+        return Err(worker::Error::RustError(format!(
+            "GraphQL error: {messages}"
+        )));
+    }
+
+    Ok(res.data.unwrap_or_default())
+}
+
+/// GitHubのauto-mergeを有効にする。必須のチェックが通ったらGitHubがマージする
+pub async fn enable_auto_merge(pr_node_id: &str, token: &Token) -> Result<()> {
+    const QUERY: &str = "mutation($id: ID!) {
+        enablePullRequestAutoMerge(input: { pullRequestId: $id, mergeMethod: MERGE }) {
+            clientMutationId
+        }
+    }";
+
+    graphql(QUERY, serde_json::json!({ "id": pr_node_id }), token).await?;
+    Ok(())
+}
+
+pub async fn disable_auto_merge(pr_node_id: &str, token: &Token) -> Result<()> {
+    const QUERY: &str = "mutation($id: ID!) {
+        disablePullRequestAutoMerge(input: { pullRequestId: $id }) {
+            clientMutationId
+        }
+    }";
+
+    graphql(QUERY, serde_json::json!({ "id": pr_node_id }), token).await?;
+    Ok(())
+}
+
+/// ベースブランチでマージキューが必須になっているか
+pub async fn is_merge_queue_enabled(pr_node_id: &str, token: &Token) -> Result<bool> {
+    const QUERY: &str = "query($id: ID!) {
+        node(id: $id) {
+            ... on PullRequest {
+                isMergeQueueEnabled
+            }
+        }
+    }";
+
+    let data = graphql(QUERY, serde_json::json!({ "id": pr_node_id }), token).await?;
+    Ok(data["node"]["isMergeQueueEnabled"]
+        .as_bool()
+        .unwrap_or(false))
+}
+
+/// マージキューに入れる。チェックが通ったらGitHubがマージする
+pub async fn enqueue_pull_request(pr_node_id: &str, token: &Token) -> Result<()> {
+    const QUERY: &str = "mutation($id: ID!) {
+        enqueuePullRequest(input: { pullRequestId: $id }) {
+            clientMutationId
+        }
+    }";
+
+    graphql(QUERY, serde_json::json!({ "id": pr_node_id }), token).await?;
+    Ok(())
+}
+
+pub async fn dequeue_pull_request(pr_node_id: &str, token: &Token) -> Result<()> {
+    const QUERY: &str = "mutation($id: ID!) {
+        dequeuePullRequest(input: { id: $id }) {
+            clientMutationId
+        }
+    }";
+
+    graphql(QUERY, serde_json::json!({ "id": pr_node_id }), token).await?;
+    Ok(())
+}
+
 impl From<&str> for EventType {
     fn from(v: &str) -> EventType {
         match v {
             "issue_comment" => Self::IssueComment,
+            "pull_request_review" => Self::PullRequestReview,
+            "pull_request_review_comment" => Self::PullRequestReviewComment,
+            "installation" => Self::Installation,
+            "installation_repositories" => Self::InstallationRepositories,
+            "repository" => Self::Repository,
+            "pull_request" => Self::PullRequest,
+            "merge_group" => Self::MergeGroup,
+            "check_run" => Self::CheckRun,
             _ => Self::_Unknown,
         }
     }
@@ -141,7 +645,30 @@ impl From<String> for EventType {
     fn from(v: String) -> EventType {
         match v.as_str() {
             "issue_comment" => Self::IssueComment,
+            "pull_request_review" => Self::PullRequestReview,
+            "pull_request_review_comment" => Self::PullRequestReviewComment,
+            "installation" => Self::Installation,
+            "installation_repositories" => Self::InstallationRepositories,
+            "repository" => Self::Repository,
+            "pull_request" => Self::PullRequest,
+            "merge_group" => Self::MergeGroup,
+            "check_run" => Self::CheckRun,
             _ => Self::_Unknown,
         }
     }
 }
+
+#[cfg(test)]
+mod tests {
+    #[test]
+    fn test_next_link() {
+        let link = r#"<https://api.github.com/repositories/1/issues?page=2>; rel="next", <https://api.github.com/repositories/1/issues?page=5>; rel="last""#;
+        assert_eq!(
+            super::next_link(link),
+            Some("https://api.github.com/repositories/1/issues?page=2")
+        );
+
+        let last = r#"<https://api.github.com/repositories/1/issues?page=4>; rel="prev", <https://api.github.com/repositories/1/issues?page=1>; rel="first""#;
+        assert_eq!(super::next_link(last), None);
+    }
+}
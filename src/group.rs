@@ -0,0 +1,244 @@
+//! `merge group`: 同じinstallationの複数のリポジトリのPRを同じ時間にまとめてマージする
+//! 時間になったら先に全部のPRがマージできるか確かめて、全部できるときだけマージする
+//! リポジトリをまたぐのでDurable Objectのアラームではなくcronで実行する
+
+use worker::*;
+
+use crate::app::AppConfig;
+use crate::check_run::{get_computed_pull_request, is_computed, is_ready, readiness};
+use crate::db::{
+    self,
+    audit::{NewAuditEntry, Outcome},
+    group::{GroupState, MergeGroup},
+    merge::{MergeJob, State},
+};
+use crate::github::comment_on_issue;
+
+/// cronで一度に実行するグループの数
+const RUN_LIMIT: u32 = 3;
+
+/// まとめの表の1行
+struct Row {
+    pr: String,
+    readiness: &'static str,
+    result: &'static str,
+}
+
+pub async fn run_due(d1: &D1Database, apps: &[AppConfig]) -> Result<()> {
+    db::group::reset_stale(d1).await?;
+
+    for group in db::group::due(d1, RUN_LIMIT).await? {
+        let Some(app) = crate::schedule::app_by_id(apps, group.app_id).await? else {
+            console_error!("The app {:?} is not configured", group.app_id);
+            continue;
+        };
+        if !db::group::start(d1, group.id).await? {
+            continue;
+        }
+
+        // 失敗しても5分ごとに試し続けないように終わらせる
+        let state = match run(d1, app, &group).await {
+            Ok(state) => state,
+            Err(e) => {
+                console_error!("Failed to merge the group {}: {e}", group.id);
+                // 待っているメンバーはグループの外では実行されないので残さない
+                if let Err(e) = db::merge::fail_group(d1, group.id).await {
+                    console_error!("Failed to fail the members of the group {}: {e}", group.id);
+                }
+                try_sync_members(d1, app, &group).await;
+                GroupState::Failed
+            }
+        };
+        db::group::finish(d1, group.id, state).await?;
+    }
+
+    Ok(())
+}
+
+async fn run(d1: &D1Database, app: &AppConfig, group: &MergeGroup) -> Result<GroupState> {
+    let members = db::merge::group_members(d1, group.id)
+        .await?
+        .into_iter()
+        .filter(|job| job.state == State::Pending)
+        .collect::<Vec<_>>();
+    if members.is_empty() {
+        return Ok(GroupState::Cancelled);
+    }
+    console_log!(
+        "Merging the group {} with {} Pull Requests",
+        group.id,
+        members.len()
+    );
+    let token = app.github_app.token(group.installation_id).await?;
+
+    // 1つでもマージできなければ何もマージしない
+    let mut ready = Vec::new();
+    for job in &members {
+        let pr = get_computed_pull_request(job.pr_number, &job.repo(), &token).await?;
+        // まだ計算されていないだけなので、失敗にせずに次のcronでもう一度確かめる
+        if pr.state != "closed" && !is_computed(pr.mergeable_state.as_deref()) {
+            console_warn!(
+                "The mergeability of {} is not computed yet. The group {} will be retried",
+                display(job),
+                group.id
+            );
+            return Ok(GroupState::Pending);
+        }
+        let (mergeable, reason) = match pr.state.as_str() {
+            "closed" => (false, "The Pull Request is closed"),
+            _ => (
+                is_ready(pr.mergeable_state.as_deref()),
+                readiness(pr.mergeable_state.as_deref()),
+            ),
+        };
+        ready.push((mergeable, reason));
+    }
+    let all_ready = ready.iter().all(|(mergeable, _)| *mergeable);
+
+    let mut rows = Vec::new();
+    let mut state = GroupState::Merged;
+    if all_ready {
+        for (job, (_, reason)) in members.iter().zip(&ready) {
+            // 残りのメンバーも続ける。結果はジョブの状態で見る
+            if let Err(e) = crate::schedule::merge(d1, app, job, db::audit::SCHEDULER).await {
+                console_error!("Failed to merge the job {} in the group: {e}", job.id);
+            }
+
+            let result = match db::merge::find_by_id(d1, job.id)
+                .await?
+                .map(|job| job.state)
+            {
+                Some(State::Merged) => "Merged",
+                Some(State::AutoMerge) => "Auto-merge enabled",
+                Some(State::Queued) => "Added to the merge queue",
+                // リポジトリのリースが取れなかった。グループの外では実行されないので諦める
+                Some(State::Pending) => {
+                    db::merge::cancel_by_id(d1, job.id).await?;
+                    state = GroupState::Failed;
+                    "Skipped because the repository was busy"
+                }
+                _ => {
+                    state = GroupState::Failed;
+                    "Failed to merge"
+                }
+            };
+            rows.push(Row {
+                pr: display(job),
+                readiness: reason,
+                result,
+            });
+        }
+    } else {
+        db::merge::fail_group(d1, group.id).await?;
+        state = GroupState::Failed;
+        for (job, (_, reason)) in members.iter().zip(&ready) {
+            rows.push(Row {
+                pr: display(job),
+                readiness: reason,
+                result: "Not merged",
+            });
+
+            db::audit::record(
+                d1,
+                &NewAuditEntry {
+                    actor: db::audit::SCHEDULER,
+                    installation_id: Some(job.installation_id),
+                    repo: Some(job.repo()),
+                    pr_number: Some(job.pr_number),
+                    action: "merge_group.merge",
+                    command: None,
+                    result: Outcome::Rejected,
+                    error: Some("Not all Pull Requests in the group were ready"),
+                },
+            )
+            .await;
+        }
+    }
+
+    let summary = summary(&group.name, all_ready, state, &rows);
+    for job in &members {
+        let repo = job.repo();
+        crate::check_run::try_sync(d1, &repo, job.pr_number, &token).await;
+        comment_on_issue(job.pr_number, &repo, &summary, &token).await?;
+    }
+
+    Ok(state)
+}
+
+/// 途中で失敗したときにメンバーのチェックランを更新する。表示のためだけなのでログだけ残す
+async fn try_sync_members(d1: &D1Database, app: &AppConfig, group: &MergeGroup) {
+    let token = match app.github_app.token(group.installation_id).await {
+        Ok(token) => token,
+        Err(e) => {
+            console_error!("Failed to get a token for the group {}: {e}", group.id);
+            return;
+        }
+    };
+    let members = match db::merge::group_members(d1, group.id).await {
+        Ok(members) => members,
+        Err(e) => {
+            console_error!("Failed to get the members of the group {}: {e}", group.id);
+            return;
+        }
+    };
+    for job in &members {
+        crate::check_run::try_sync(d1, &job.repo(), job.pr_number, &token).await;
+    }
+}
+
+/// 別のリポジトリのPRも並ぶので`owner/repo#123`
+fn display(job: &MergeJob) -> String {
+    crate::dependency::display(&job.repo(), job.pr_number)
+}
+
+fn summary(name: &str, all_ready: bool, state: GroupState, rows: &[Row]) -> String {
+    let mut summary = format!(
+        "### Merge group `{name}`\n\n| Pull Request | Readiness | Result |\n| --- | --- | --- |\n"
+    );
+    for row in rows {
+        summary.push_str(&format!(
+            "| {} | {} | {} |\n",
+            row.pr, row.readiness, row.result
+        ));
+    }
+    summary.push('\n');
+    summary.push_str(match (all_ready, state) {
+        (false, _) => {
+            "Not all Pull Requests in the group were ready, so none of them have been merged"
+        }
+        (true, GroupState::Merged) => {
+            "All Pull Requests in the group were ready, so they have been merged together"
+        }
+        (true, _) => {
+            "All Pull Requests in the group were ready, but some of them could not be merged"
+        }
+    });
+    summary
+}
+
+#[cfg(test)]
+mod tests {
+    use super::{GroupState, Row};
+
+    #[test]
+    fn test_summary() {
+        let rows = [
+            Row {
+                pr: "owner/api#1".into(),
+                readiness: "Ready to merge",
+                result: "Not merged",
+            },
+            Row {
+                pr: "owner/client#2".into(),
+                readiness: "There are merge conflicts",
+                result: "Not merged",
+            },
+        ];
+
+        let summary = super::summary("api-v2", false, GroupState::Failed, &rows);
+        assert!(summary.starts_with("### Merge group `api-v2`\n"));
+        assert!(summary.contains("| owner/api#1 | Ready to merge | Not merged |\n"));
+        assert!(summary.contains("| owner/client#2 | There are merge conflicts | Not merged |\n"));
+        assert!(summary.ends_with("none of them have been merged"));
+    }
+}
@@ -1,297 +1,1148 @@
-use chrono::{FixedOffset, NaiveDateTime, Utc};
+use chrono::{NaiveDateTime, Utc};
 use github_webhook::payload_types as gh;
 use worker::*;
 
-use crate::parser::{Command, Help, Merge};
+use crate::app::AppConfig;
+use crate::check_run::Action as CheckRunAction;
+use crate::dependency::Prerequisite;
+use crate::parser::{
+    format_jst, jst, Command, Group, Help, Merge, PullRequestRef, Recurrence, Train, Trigger,
+};
+use crate::schedule::MergeOutcome;
+use crate::stack::Discovery;
 
-use crate::github::comment_on_issue;
+use crate::db::{
+    self,
+    audit::{AuditEntry, NewAuditEntry, Outcome},
+    lease::RepoLease,
+    merge::{NewMergeJob, State},
+    recurring::{CatchUp, Kind, NewRecurringJob},
+    setting::MergeMode,
+};
+use crate::github::{comment_on_issue, Repo, Token};
 
-const MENTION: &str = "@satler-bot";
+const SLASH_PREFIX: &str = "/";
+
+/// リースが空くまで待つ時間。GitHubのマージはこれくらいで終わる
+const LEASE_WAIT: std::time::Duration = std::time::Duration::from_secs(5);
+
+const ALREADY_MERGING: &str =
+    "This Pull Request is already being merged, so the automatic merge can no longer be changed";
+
+/// ハンドラが共通で使うもの
+pub struct HandlerContext {
+    pub token: Token,
+    pub d1: D1Database,
+    pub env: Env,
+    pub installation_id: u64,
+    /// どのAppで処理しているか。cronで同じAppの認証情報を使うために保存する
+    pub app_id: u64,
+    /// Appのslug。`@<slug>`でメンションされる
+    pub slug: String,
+    /// コマンドを使える人。空ならリポジトリのownerだけ
+    pub authorised_users: Vec<String>,
+    /// `COMMAND_TRIGGERS`の設定
+    pub command_triggers: Option<String>,
+}
+
+/// コマンドが書かれていた場所
+/// Issue comment, review, review commentの違いをここで吸収する
+pub struct CommandSource<'a> {
+    /// コメントなどを一意に表すもの (`issue_comment:123`)
+    pub id: String,
+    pub owner: &'a str,
+    pub repo: &'a str,
+    pub repository_id: u64,
+    pub number: u64,
+    pub is_pull_request: bool,
+    /// PRがマージ済みか
+    pub merged: bool,
+    pub author: &'a str,
+    pub body: &'a str,
+}
+
+impl CommandSource<'_> {
+    fn repo(&self) -> Repo<'_> {
+        Repo {
+            owner: self.owner,
+            name: self.repo,
+            id: Some(self.repository_id),
+        }
+    }
+}
 
 pub async fn issue_comment_created<'a>(
     event: gh::IssueCommentCreatedEvent<'a>,
-    token: String,
-    d1: D1Database,
-    installation_id: u64,
+    ctx: HandlerContext,
 ) -> Result<()> {
     console_log!("Handling the event as IssueCommentCreatedEvent");
-    let input = event.comment.body;
-    let command = crate::parser::Command::try_parse(input, MENTION);
+    let issue = &event.issue.issue;
+    let repo = &event.repository;
 
-    // worker::console_debug!("{command:?}");
+    let source = CommandSource {
+        id: format!("issue_comment:{}", event.comment.id),
+        owner: &repo.owner.login,
+        repo: &repo.name,
+        repository_id: repo.id,
+        number: issue.number,
+        is_pull_request: issue.pull_request.is_some(),
+        merged: issue
+            .pull_request
+            .as_ref()
+            .is_some_and(|pr| pr.merged_at.is_some()),
+        author: &event.comment.user.login,
+        body: event.comment.body,
+    };
 
-    let issue = &event.issue.issue;
+    command(source, ctx).await
+}
+
+pub async fn pull_request_review_submitted<'a>(
+    event: gh::PullRequestReviewSubmittedEvent<'a>,
+    ctx: HandlerContext,
+) -> Result<()> {
+    console_log!("Handling the event as PullRequestReviewSubmittedEvent");
+    // 本文なしのApproveなど
+    let Some(body) = event.review.body else {
+        return Ok(());
+    };
+    let repo = &event.repository;
+
+    let source = CommandSource {
+        id: format!("pull_request_review:{}", event.review.id),
+        owner: &repo.owner.login,
+        repo: &repo.name,
+        repository_id: repo.id,
+        number: event.pull_request.number,
+        is_pull_request: true,
+        merged: event.pull_request.merged_at.is_some(),
+        author: &event.review.user.login,
+        body,
+    };
+
+    command(source, ctx).await
+}
+
+pub async fn pull_request_review_comment_created<'a>(
+    event: gh::PullRequestReviewCommentCreatedEvent<'a>,
+    ctx: HandlerContext,
+) -> Result<()> {
+    console_log!("Handling the event as PullRequestReviewCommentCreatedEvent");
     let repo = &event.repository;
-    let owner = &repo.owner.login;
-    let repo_name = &repo.name;
-    let issue_num = issue.number;
 
-    if "satler-git" // TODO:人を変更できるようにする？
-        != event.comment.user.login // TODO: 送った人にメンション?
+    let source = CommandSource {
+        id: format!("pull_request_review_comment:{}", event.comment.id),
+        owner: &repo.owner.login,
+        repo: &repo.name,
+        repository_id: repo.id,
+        number: event.pull_request.number,
+        is_pull_request: true,
+        merged: event.pull_request.merged_at.is_some(),
+        author: &event.comment.user.login,
+        body: event.comment.body,
+    };
+
+    command(source, ctx).await
+}
+
+async fn command(source: CommandSource<'_>, ctx: HandlerContext) -> Result<()> {
+    // 自分自身のコメントには反応しない
+    if source.author == format!("{}[bot]", ctx.slug) {
+        return Ok(());
+    }
+
+    let mention = format!("@{}", ctx.slug);
+    let mention_bot = format!("@{}[bot]", ctx.slug);
+    let triggers = match ctx.command_triggers.as_deref() {
+        Some(config) => Trigger::parse_list(config),
+        None => vec![
+            Trigger::Mention(&mention),
+            Trigger::Mention(&mention_bot),
+            Trigger::Prefix(SLASH_PREFIX),
+        ],
+    };
+
+    let command = crate::parser::Command::try_parse(source.body, &triggers);
+
+    // worker::console_debug!("{command:?}");
+
+    let token = &ctx.token;
+
+    let repo = source.repo();
+    let issue_num = source.number;
+
+    let mut entry = NewAuditEntry {
+        actor: source.author,
+        installation_id: Some(ctx.installation_id),
+        repo: Some(repo),
+        pr_number: Some(issue_num),
+        action: command.as_ref().map_or("invalid", action),
+        command: Some(source.body),
+        result: Outcome::Success,
+        error: None,
+    };
+
+    let authorised = is_authorised(&source, &ctx);
+    if !authorised // TODO: 送った人にメンション?
         || command.is_err()
     {
         if let Err(crate::parser::error::Error::NotACommand) = command {
             // メンションされたけど正しくない場合
             comment_on_issue(
                 issue_num,
-                owner,
-                repo_name,
+                &repo,
                 "Some syntax is wrong. View the help with the`help` command",
-                &token,
+                token,
             )
             .await?;
+            entry.result = Outcome::Rejected;
+            db::audit::record(&ctx.d1, &entry).await;
         }
-        if Some("satler-git") != event.comment.user.name && command.is_ok() {
-            worker::console_debug!("{:?}", event.comment.user.name);
+        // 対応していないタイムゾーンなどは理由を返す。権限のない人には返さない
+        if let Err(e @ crate::parser::error::Error::Recurrence(_)) = &command {
+            if authorised {
+                comment_on_issue(
+                    issue_num,
+                    &repo,
+                    &format!("{e}. View the help with the `train help` command"),
+                    token,
+                )
+                .await?;
+                entry.result = Outcome::Rejected;
+                db::audit::record(&ctx.d1, &entry).await;
+            }
+        }
+        if command.is_ok() {
+            worker::console_debug!("{:?}", source.author);
 
             comment_on_issue(
                 issue_num,
-                owner,
-                repo_name,
+                &repo,
                 "You are not authorised to operate this operation here",
-                &token,
+                token,
             )
             .await?;
+            entry.result = Outcome::Denied;
+            db::audit::record(&ctx.d1, &entry).await;
         }
         return Ok(());
     }
 
     let command = command.unwrap();
 
-    match command {
-        Command::Help => {
-            comment_on_issue(issue_num, owner, repo_name, Command::HELP, &token).await?
+    // 同じコメントから二回実行しない
+    if !crate::db::delivery::claim_command(&ctx.d1, &source.id).await? {
+        console_log!("{} has already been handled", source.id);
+        return Ok(());
+    }
+
+    let result = execute(&source, &ctx, command).await;
+    if result.is_err() {
+        crate::db::delivery::release_command(&ctx.d1, &source.id).await?;
+    }
+
+    result
+}
+
+/// "Scheduled merge"のチェックのボタンが押された
+/// コメントのコマンドと同じように実行する
+pub async fn check_run_requested_action(
+    event: crate::check_run::CheckRunEvent,
+    button: CheckRunAction,
+    ctx: HandlerContext,
+) -> Result<()> {
+    console_log!("Handling the event as CheckRunRequestedActionEvent");
+    // forkからのPRなどでは空になる
+    let Some(pr) = event.check_run.pull_requests.first() else {
+        return Ok(());
+    };
+    let repo = &event.repository;
+
+    let source = CommandSource {
+        id: format!("check_run:{}", event.check_run.id),
+        owner: &repo.owner.login,
+        repo: &repo.name,
+        repository_id: repo.id,
+        number: pr.number,
+        is_pull_request: true,
+        merged: false,
+        author: &event.sender.login,
+        body: button.label(),
+    };
+
+    let merge = match button {
+        CheckRunAction::MergeNow => Merge::Now,
+        CheckRunAction::Cancel => Merge::Cancel,
+        CheckRunAction::Postpone => {
+            let latest = db::merge::find_latest_by_pr(&ctx.d1, &source.repo(), pr.number).await?;
+            let scheduled = latest
+                .filter(|job| matches!(job.state, State::Pending | State::Suspended))
+                .and_then(|job| {
+                    NaiveDateTime::parse_from_str(&job.will_merged_at, "%Y-%m-%d %H:%M:%S").ok()
+                });
+            let Some(scheduled) = scheduled else {
+                comment_on_issue(
+                    pr.number,
+                    &source.repo(),
+                    "It is not possible to postpone in a Pull Request that does not have an automatic merge scheduled",
+                    &ctx.token,
+                )
+                .await?;
+                return Ok(());
+            };
+            // UTCで保存されているのでJSTにして1時間後
+            Merge::Add(scheduled + chrono::Duration::hours(9) + chrono::Duration::hours(1))
         }
+    };
+    let command = Command::Merge(merge);
+
+    if !is_authorised(&source, &ctx) {
+        comment_on_issue(
+            source.number,
+            &source.repo(),
+            "You are not authorised to operate this operation here",
+            &ctx.token,
+        )
+        .await?;
+        db::audit::record(
+            &ctx.d1,
+            &NewAuditEntry {
+                actor: source.author,
+                installation_id: Some(ctx.installation_id),
+                repo: Some(source.repo()),
+                pr_number: Some(source.number),
+                action: action(&command),
+                command: Some(source.body),
+                result: Outcome::Denied,
+                error: None,
+            },
+        )
+        .await;
+        return Ok(());
+    }
+
+    execute(&source, &ctx, command).await
+}
+
+/// `AUTHORISED_USERS`に書かれた人か、書かれていなければリポジトリのowner
+fn is_authorised(source: &CommandSource<'_>, ctx: &HandlerContext) -> bool {
+    if ctx.authorised_users.is_empty() {
+        return source.author == source.owner;
+    }
+    ctx.authorised_users
+        .iter()
+        .any(|user| user.eq_ignore_ascii_case(source.author))
+}
+
+/// 権限を確認した後のコマンドを実行して、結果を監査ログに残す
+async fn execute(source: &CommandSource<'_>, ctx: &HandlerContext, command: Command) -> Result<()> {
+    let token = &ctx.token;
+    let repo = source.repo();
+    let issue_num = source.number;
+
+    let mut entry = NewAuditEntry {
+        actor: source.author,
+        installation_id: Some(ctx.installation_id),
+        repo: Some(repo),
+        pr_number: Some(issue_num),
+        action: action(&command),
+        command: Some(source.body),
+        result: Outcome::Success,
+        error: None,
+    };
+
+    let result = match command {
+        Command::Help => comment_on_issue(issue_num, &repo, Command::HELP, token)
+            .await
+            .map(|_| Outcome::Success),
+        Command::History => handle_history(source, ctx).await,
         Command::Merge(merge) => match merge {
-            Merge::Add(date) => handle_merge_add(event, &token, date, &d1, installation_id).await?,
-            Merge::Cancel => handle_merge_cancel(event, &token, &d1).await?,
-            Merge::Help => {
-                comment_on_issue(issue_num, owner, repo_name, Merge::HELP, &token).await?
+            Merge::Add(date) => handle_merge_add(source, ctx, Some(date), None).await,
+            Merge::After { time, after } => handle_merge_add(source, ctx, time, Some(after)).await,
+            Merge::Stack(date) => handle_merge_stack(source, ctx, date).await,
+            Merge::Group(Group::Create { name, time }) => {
+                handle_merge_group_create(source, ctx, &name, time).await
             }
+            Merge::Group(Group::Join(name)) => handle_merge_group_join(source, ctx, &name).await,
+            Merge::Now => handle_merge_now(source, ctx).await,
+            Merge::Cancel => handle_merge_cancel(source, ctx).await,
+            Merge::Mode(mode) => handle_merge_mode(source, ctx, mode).await,
+            Merge::Help => comment_on_issue(issue_num, &repo, Merge::HELP, token)
+                .await
+                .map(|_| Outcome::Success),
+        },
+        Command::Train(train) => match train {
+            Train::Set {
+                departure,
+                label,
+                catch_up,
+            } => handle_train_set(source, ctx, departure, label.as_deref(), catch_up).await,
+            Train::Off => handle_train_off(source, ctx).await,
+            Train::Show => handle_train_show(source, ctx).await,
+            Train::Help => comment_on_issue(issue_num, &repo, Train::HELP, token)
+                .await
+                .map(|_| Outcome::Success),
         },
+    };
+
+    let error = result.as_ref().err().map(|e| e.to_string());
+    entry.result = *result.as_ref().unwrap_or(&Outcome::Failed);
+    entry.error = error.as_deref();
+    db::audit::record(&ctx.d1, &entry).await;
+
+    result.map(|_| ())
+}
+
+/// 監査ログに書く操作の名前
+fn action(command: &Command) -> &'static str {
+    match command {
+        Command::Help => "help",
+        Command::History => "history",
+        Command::Merge(Merge::Add(_) | Merge::After { .. }) => "merge.add",
+        Command::Merge(Merge::Stack(_)) => "merge.stack",
+        Command::Merge(Merge::Group(Group::Create { .. })) => "merge_group.create",
+        Command::Merge(Merge::Group(Group::Join(_))) => "merge_group.join",
+        Command::Merge(Merge::Now) => "merge.now",
+        Command::Merge(Merge::Cancel) => "merge.cancel",
+        Command::Merge(Merge::Mode(_)) => "merge.mode",
+        Command::Merge(Merge::Help) => "merge.help",
+        Command::Train(Train::Set { .. }) => "train.set",
+        Command::Train(Train::Off) => "train.off",
+        Command::Train(Train::Show) => "train.show",
+        Command::Train(Train::Help) => "train.help",
     }
-    Ok(())
 }
 
-async fn handle_merge_add<'a>(
-    event: gh::IssueCommentCreatedEvent<'a>,
-    token: &str,
-    date: NaiveDateTime,
-    d1: &D1Database,
-    installation_id: u64,
-) -> Result<()> {
+/// `date`がなければすぐ。`after`があればそのPRがマージされるまで待つ
+async fn handle_merge_add(
+    source: &CommandSource<'_>,
+    ctx: &HandlerContext,
+    date: Option<NaiveDateTime>,
+    after: Option<PullRequestRef>,
+) -> Result<Outcome> {
     console_log!("Handling merge add command");
-    let issue = &event.issue.issue;
-    let repo = &event.repository;
-    let owner = &repo.owner.login;
-    let repo_name = &repo.name;
-    let issue_num = issue.number;
+    let token = &ctx.token;
+    let d1 = &ctx.d1;
+    let repo = source.repo();
+    let issue_num = source.number;
+    let Some(date) = schedulable_date(source, ctx, date).await? else {
+        return Ok(Outcome::Rejected);
+    };
+
+    // 先にマージされるのを待つPR
+    let after = after.as_ref().map(|after| match &after.repo {
+        Some((owner, name)) => (
+            Repo {
+                owner,
+                name,
+                id: None,
+            },
+            after.number,
+        ),
+        None => (
+            Repo {
+                owner: source.owner,
+                name: source.repo,
+                id: None,
+            },
+            after.number,
+        ),
+    });
+    let after = match after {
+        Some((after_repo, after_number)) => {
+            let prerequisite =
+                crate::dependency::check(d1, token, &repo, issue_num, &after_repo, after_number)
+                    .await?;
+            let display = crate::dependency::display(&after_repo, after_number);
+
+            let message = match prerequisite {
+                Prerequisite::Open => None,
+                // 待つ必要がない
+                Prerequisite::Merged => None,
+                Prerequisite::Closed => Some(format!(
+                    "It is not possible to wait for {display} because it has been closed without being merged"
+                )),
+                Prerequisite::NotFound => Some(format!(
+                    "It is not possible to find {display}, or the app cannot access it"
+                )),
+                Prerequisite::Cycle => Some(format!(
+                    "It is not possible to wait for {display} because it would wait for this Pull Request in a cycle"
+                )),
+            };
+            if let Some(message) = message {
+                comment_on_issue(issue_num, &repo, &message, token).await?;
+                return Ok(Outcome::Rejected);
+            }
+
+            (prerequisite == Prerequisite::Open).then_some((after_repo, after_number))
+        }
+        None => None,
+    };
+
+    // GraphQLなどで使うためにnode_idも保存しておく
+    let pr = crate::github::get_pull_request(issue_num, &repo, token).await?;
+
+    let Some(lease) = acquire_lease(source, ctx).await? else {
+        return Ok(Outcome::Rejected);
+    };
+
+    let date_utc = date - std::time::Duration::from_secs(9 * 3600);
+    let scheduled = async {
+        let latest = db::merge::find_latest_by_pr(d1, &repo, issue_num).await?;
+        let latest = latest.map(|job| job.state);
+
+        let scheduled = db::merge::schedule(
+            d1,
+            &NewMergeJob {
+                pr_number: issue_num,
+                repo,
+                will_merged_at: date_utc,
+                installation_id: ctx.installation_id,
+                app_id: ctx.app_id,
+                pr_node_id: &pr.node_id,
+                scheduled_by: source.author,
+                after,
+                retarget_base: None,
+                group_id: None,
+            },
+        )
+        .await?;
+
+        // マージ中で上書きできなければNone。既にスケジュールされていれば時間を更新したことになる
+        Ok::<_, Error>(scheduled.then_some(matches!(
+            latest,
+            Some(State::Pending | State::Blocked | State::Suspended)
+        )))
+    }
+    .await;
+    lease.release().await?;
+
+    let Some(rescheduled) = scheduled? else {
+        comment_on_issue(issue_num, &repo, ALREADY_MERGING, token).await?;
+        return Ok(Outcome::Rejected);
+    };
+    crate::repo_scheduler::try_sync(&ctx.env, &repo).await;
+    crate::check_run::try_sync(d1, &repo, issue_num, token).await;
+
+    let mut message = if rescheduled {
+        "The scheduled time of the automatic merge has been successfully updated".to_string()
+    } else {
+        "Automatic merging has been successfully scheduled".to_string()
+    };
+    if let Some((after_repo, after_number)) = &after {
+        message.push_str(&format!(
+            ". It will be merged after {} is merged",
+            crate::dependency::display(after_repo, *after_number)
+        ));
+    }
+    comment_on_issue(issue_num, &repo, &message, token).await?;
+
+    Ok(Outcome::Success)
+}
+
+/// スケジュールできるPRと時間か確かめる。できなければ理由をコメントしてNone
+/// `date`がなければ今
+async fn schedulable_date(
+    source: &CommandSource<'_>,
+    ctx: &HandlerContext,
+    date: Option<NaiveDateTime>,
+) -> Result<Option<NaiveDateTime>> {
+    let token = &ctx.token;
+    let repo = source.repo();
+    let issue_num = source.number;
     // Issueな場合
     {
-        if issue.pull_request.is_none() {
+        if !source.is_pull_request {
             comment_on_issue(
                 issue_num,
-                owner,
-                repo_name,
+                &repo,
                 "This operation can only be performed on Pull Requests",
                 token,
             )
             .await?;
-            return Ok(());
+            return Ok(None);
         }
     }
     // 既にマージされている場合
-    let pr = issue.pull_request.as_ref().unwrap();
     {
-        if pr.merged_at.is_some() {
+        if source.merged {
             comment_on_issue(
                 issue_num,
-                owner,
-                repo_name,
+                &repo,
                 "It is not possible to run this command on the merged Pull Request",
                 token,
             )
             .await?;
-            return Ok(());
+            return Ok(None);
         }
     }
     // 過ぎている場合
-    let tz = FixedOffset::east_opt(9 * 3600).unwrap();
+    let now = Utc::now().with_timezone(&jst()).naive_local();
     {
-        let now = Utc::now().with_timezone(&tz).naive_local();
-
-        if now > date {
+        if date.is_some_and(|date| now > date) {
             comment_on_issue(
                 issue_num,
-                owner,
-                repo_name,
+                &repo,
                 "It is not possible to specify a time past",
                 token,
             )
             .await?;
-            return Ok(());
+            return Ok(None);
         }
     }
+    Ok(Some(date.unwrap_or(now)))
+}
 
-    // すでにスケジュール済み
-    let owner = &repo.owner.login;
-    let repo_name = &repo.name;
-    let issue_num = issue.number;
-    {
-        if is_already_merged(owner, repo_name, issue_num, d1)
-            .await?
-            .is_some()
-        {
-            comment_on_issue(
-                issue_num,
-                owner,
-                repo_name,
-                "It is not possible to schedule in a merged Pull Request",
-                token,
+/// `pr_node_id`などはスタックを辿るときに取ったPRのものを使う
+async fn handle_merge_stack(
+    source: &CommandSource<'_>,
+    ctx: &HandlerContext,
+    date: Option<NaiveDateTime>,
+) -> Result<Outcome> {
+    console_log!("Handling merge stack command");
+    let token = &ctx.token;
+    let d1 = &ctx.d1;
+    let repo = source.repo();
+    let issue_num = source.number;
+    let Some(date) = schedulable_date(source, ctx, date).await? else {
+        return Ok(Outcome::Rejected);
+    };
+
+    let (prs, default_branch) = match crate::stack::discover(&repo, issue_num, token).await? {
+        Discovery::Stack {
+            prs,
+            default_branch,
+        } => (prs, default_branch),
+        discovery => {
+            let message = match discovery {
+                Discovery::Missing { pr_number, branch } => format!(
+                    "It is not possible to find an open Pull Request for `{branch}`, the base branch of #{pr_number}"
+                ),
+                Discovery::Ambiguous { branch } => format!(
+                    "There are several open Pull Requests for `{branch}`, so it is not possible to tell which one is in the stack"
+                ),
+                _ => "The stack is too deep to merge automatically".to_string(),
+            };
+            comment_on_issue(issue_num, &repo, &message, token).await?;
+            return Ok(Outcome::Rejected);
+        }
+    };
+
+    let Some(lease) = acquire_lease(source, ctx).await? else {
+        return Ok(Outcome::Rejected);
+    };
+
+    let date_utc = date - std::time::Duration::from_secs(9 * 3600);
+    let scheduled = async {
+        // 1つでもマージ中なら何も変えない
+        for pr in &prs {
+            let latest = db::merge::find_latest_by_pr(d1, &repo, pr.number).await?;
+            if latest.is_some_and(|job| matches!(job.state, State::Merging | State::Queued)) {
+                return Ok(Some(pr.number));
+            }
+        }
+
+        for (i, pr) in prs.iter().enumerate() {
+            // 一番下以外は1つ下のPRを待つ
+            let below = i.checked_sub(1).map(|i| {
+                (
+                    Repo {
+                        owner: source.owner,
+                        name: source.repo,
+                        id: None,
+                    },
+                    prs[i].number,
+                )
+            });
+            db::merge::schedule(
+                d1,
+                &NewMergeJob {
+                    pr_number: pr.number,
+                    repo,
+                    will_merged_at: date_utc,
+                    installation_id: ctx.installation_id,
+                    app_id: ctx.app_id,
+                    pr_node_id: &pr.node_id,
+                    scheduled_by: source.author,
+                    retarget_base: below.as_ref().map(|_| default_branch.as_str()),
+                    group_id: None,
+                    after: below,
+                },
             )
             .await?;
-            return Ok(());
         }
+        Ok::<_, Error>(None)
     }
+    .await;
+    lease.release().await?;
 
-    let date_utc = date - std::time::Duration::from_secs(9 * 3600);
-    let insert_merge_query = worker::query!(
-        &d1,
-        "INSERT INTO merge (pr_number, owner, repository, will_merged_at, installation_id) VALUES (?1, ?2, ?3, ?4, ?5)",
-        &issue_num,
-        &owner,
-        &repo_name,
-        &date_utc.to_string(),
-        installation_id,
-    )?;
-
-    let result = d1.batch(vec![insert_merge_query]).await?;
-
-    if !result[0].success() {
-        return Err(worker::Error::RustError(
-            result[0].error().unwrap().to_string(),
-        ));
+    if let Some(merging) = scheduled? {
+        let message = if merging == issue_num {
+            ALREADY_MERGING.to_string()
+        } else {
+            format!(
+                "#{merging} in the stack is already being merged, so the stack cannot be scheduled"
+            )
+        };
+        comment_on_issue(issue_num, &repo, &message, token).await?;
+        return Ok(Outcome::Rejected);
     }
+    crate::repo_scheduler::try_sync(&ctx.env, &repo).await;
 
+    let order = prs
+        .iter()
+        .map(|pr| format!("#{}", pr.number))
+        .collect::<Vec<_>>()
+        .join(" → ");
+    for pr in &prs {
+        crate::check_run::try_sync(d1, &repo, pr.number, token).await;
+        if pr.number != issue_num {
+            comment_on_issue(
+                pr.number,
+                &repo,
+                &format!("This Pull Request will be merged as part of the stack scheduled in #{issue_num} ({order})"),
+                token,
+            )
+            .await?;
+        }
+    }
     comment_on_issue(
         issue_num,
-        owner,
-        repo_name,
-        "Automatic merging has been successfully scheduled",
+        &repo,
+        &format!("Automatic merging of the stack has been successfully scheduled. The Pull Requests will be merged from the bottom: {order}"),
         token,
     )
     .await?;
 
-    Ok(())
+    Ok(Outcome::Success)
 }
 
-async fn handle_merge_cancel<'a>(
-    event: gh::IssueCommentCreatedEvent<'a>,
-    token: &str,
-    d1: &D1Database,
-) -> Result<()> {
+async fn handle_merge_group_create(
+    source: &CommandSource<'_>,
+    ctx: &HandlerContext,
+    name: &str,
+    time: NaiveDateTime,
+) -> Result<Outcome> {
+    console_log!("Handling merge group create command");
+    let Some(date) = schedulable_date(source, ctx, Some(time)).await? else {
+        return Ok(Outcome::Rejected);
+    };
+    let date_utc = date - std::time::Duration::from_secs(9 * 3600);
+
+    let existing = db::group::find_pending_by_name(&ctx.d1, ctx.installation_id, name).await?;
+    let Some(group) = db::group::create(
+        &ctx.d1,
+        ctx.installation_id,
+        ctx.app_id,
+        name,
+        date_utc,
+        source.author,
+    )
+    .await?
+    else {
+        let message = format!("The merge group `{name}` is already being merged");
+        comment_on_issue(source.number, &source.repo(), &message, &ctx.token).await?;
+        return Ok(Outcome::Rejected);
+    };
+    // 他のリポジトリのメンバーも時間を合わせる
+    db::merge::reschedule_group(&ctx.d1, group.id, date_utc).await?;
+
+    let message = if existing.is_some() {
+        format!(
+            "The scheduled time of the merge group `{name}` has been successfully updated to {}",
+            date.format("%Y-%m-%d %H:%M JST")
+        )
+    } else {
+        format!(
+            "The merge group `{name}` has been successfully scheduled at {}. Pull Requests in other repositories can join it with `merge group join {name}`",
+            date.format("%Y-%m-%d %H:%M JST")
+        )
+    };
+    join_group(source, ctx, &group, &message).await
+}
+
+async fn handle_merge_group_join(
+    source: &CommandSource<'_>,
+    ctx: &HandlerContext,
+    name: &str,
+) -> Result<Outcome> {
+    console_log!("Handling merge group join command");
+    if schedulable_date(source, ctx, None).await?.is_none() {
+        return Ok(Outcome::Rejected);
+    }
+
+    let Some(group) = db::group::find_pending_by_name(&ctx.d1, ctx.installation_id, name).await?
+    else {
+        let message = format!(
+            "There is no merge group named `{name}` waiting to be merged. You can create it with `merge group create {name} 18:00`"
+        );
+        comment_on_issue(source.number, &source.repo(), &message, &ctx.token).await?;
+        return Ok(Outcome::Rejected);
+    };
+
+    let message = format!(
+        "This Pull Request has successfully joined the merge group `{name}`, which will be merged at {}",
+        NaiveDateTime::parse_from_str(&group.will_merged_at, "%Y-%m-%d %H:%M:%S")
+            .map(format_jst)
+            .unwrap_or_else(|_| group.will_merged_at.clone())
+    );
+    join_group(source, ctx, &group, &message).await
+}
+
+/// このPRをグループの時間にスケジュールする
+async fn join_group(
+    source: &CommandSource<'_>,
+    ctx: &HandlerContext,
+    group: &db::group::MergeGroup,
+    message: &str,
+) -> Result<Outcome> {
+    let token = &ctx.token;
+    let d1 = &ctx.d1;
+    let repo = source.repo();
+    let issue_num = source.number;
+
+    let will_merged_at = NaiveDateTime::parse_from_str(&group.will_merged_at, "%Y-%m-%d %H:%M:%S")
+        .map_err(|e| Error::RustError(format!("Invalid will_merged_at: {e}")))?;
+    // GraphQLなどで使うためにnode_idも保存しておく
+    let pr = crate::github::get_pull_request(issue_num, &repo, token).await?;
+
+    let Some(lease) = acquire_lease(source, ctx).await? else {
+        return Ok(Outcome::Rejected);
+    };
+    // マージ中などで上書きできなければfalse
+    let scheduled = db::merge::schedule(
+        d1,
+        &NewMergeJob {
+            pr_number: issue_num,
+            repo,
+            will_merged_at,
+            installation_id: ctx.installation_id,
+            app_id: ctx.app_id,
+            pr_node_id: &pr.node_id,
+            scheduled_by: source.author,
+            after: None,
+            retarget_base: None,
+            group_id: Some(group.id),
+        },
+    )
+    .await;
+    lease.release().await?;
+
+    if !scheduled? {
+        comment_on_issue(issue_num, &repo, ALREADY_MERGING, token).await?;
+        return Ok(Outcome::Rejected);
+    }
+    // グループから抜けたかもしれないのでアラームを合わせる
+    crate::repo_scheduler::try_sync(&ctx.env, &repo).await;
+    crate::check_run::try_sync(d1, &repo, issue_num, token).await;
+    comment_on_issue(issue_num, &repo, message, token).await?;
+
+    Ok(Outcome::Success)
+}
+
+async fn handle_merge_cancel(source: &CommandSource<'_>, ctx: &HandlerContext) -> Result<Outcome> {
     console_log!("Handling merge cancel command");
-    let issue = &event.issue.issue; // TODO: 上と共通だから切りだす
-    let repo = &event.repository;
-    let owner = &repo.owner.login;
-    let repo_name = &repo.name;
-    let issue_num = issue.number;
+    let token = &ctx.token;
+    let d1 = &ctx.d1;
+    let repo = source.repo();
+    let issue_num = source.number;
     // Issueな場合
     {
-        if issue.pull_request.is_none() {
+        if !source.is_pull_request {
             comment_on_issue(
                 issue_num,
-                owner,
-                repo_name,
+                &repo,
                 "This operation can only be performed on Pull Requests",
                 token,
             )
             .await?;
-            return Ok(());
+            return Ok(Outcome::Rejected);
         }
     }
 
-    {
-        let merged = is_already_merged(owner, repo_name, issue_num, d1).await?;
-        // select してマージされていたらコメント
-        if merged.is_none() {
-            comment_on_issue(
-                issue_num,
-                owner,
-                repo_name,
-                "It is not possible to cancel in a Pull Request that does not have an automatic merge scheduled",
-                token,
-            )
-            .await?;
-            return Ok(());
-        // スケジュールされていなかったらコメント
-        } else if merged == Some(true) {
-            comment_on_issue(
-                issue_num,
-                owner,
-                repo_name,
-                "It is not possible to cancel in a pull request that has been automatically merged",
-                token,
-            )
-            .await?;
-            return Ok(());
-        }
-    }
+    let Some(lease) = acquire_lease(source, ctx).await? else {
+        return Ok(Outcome::Rejected);
+    };
+    let cancelled = db::merge::cancel_by_pr(d1, &repo, issue_num).await;
+    lease.release().await?;
 
-    // 多分エラー処理おわったから削除
-    let delete_merge_query = worker::query!(
-        &d1,
-        "DELETE FROM merge WHERE (pr_number, owner, repository) = (?1, ?2, ?3)",
-        &issue_num,
-        &owner,
-        &repo_name,
-    )?;
+    if !cancelled? {
+        let latest = db::merge::find_latest_by_pr(d1, &repo, issue_num).await?;
+        // GitHubに任せた後ならGitHub側も取り消す
+        if let Some(job) = latest
+            .as_ref()
+            .filter(|job| matches!(job.state, State::AutoMerge | State::Queued))
+        {
+            let node_id = crate::schedule::pr_node_id(job, token).await?;
+            let message = if job.state == State::Queued {
+                crate::github::dequeue_pull_request(&node_id, token).await?;
+                "The Pull Request has been removed from the merge queue and the automatic merge has been cancelled"
+            } else {
+                crate::github::disable_auto_merge(&node_id, token).await?;
+                "Auto-merge has been disabled and the automatic merge has been cancelled"
+            };
+            db::merge::settle_by_pr(d1, &repo, issue_num, State::Cancelled).await?;
+            crate::check_run::try_sync(d1, &repo, issue_num, token).await;
 
-    let result = d1.batch(vec![delete_merge_query]).await?;
+            comment_on_issue(issue_num, &repo, message, token).await?;
+            return Ok(Outcome::Success);
+        }
 
-    if !result[0].success() {
-        return Err(worker::Error::RustError(
-            result[0].error().unwrap().to_string(),
-        ));
+        let message = match latest.map(|job| job.state) {
+            // マージ済みならコメント
+            Some(State::Merged) => {
+                "It is not possible to cancel in a pull request that has been automatically merged"
+            }
+            // リースが切れた後もマージしている
+            Some(State::Merging) => ALREADY_MERGING,
+            // スケジュールされていなかったらコメント
+            _ => "It is not possible to cancel in a Pull Request that does not have an automatic merge scheduled",
+        };
+        comment_on_issue(issue_num, &repo, message, token).await?;
+        return Ok(Outcome::Rejected);
     }
+    crate::repo_scheduler::try_sync(&ctx.env, &repo).await;
+    crate::check_run::try_sync(d1, &repo, issue_num, token).await;
 
     comment_on_issue(
         issue_num,
-        owner,
-        repo_name,
+        &repo,
         "The automatic merge has been successfully cancelled.",
         token,
     )
     .await?;
 
-    Ok(())
+    Ok(Outcome::Success)
 }
 
-/// -> (マージ済み)
-async fn is_already_merged(
-    owner: &str,
-    repo: &str,
-    number: u64,
-    d1: &D1Database,
-) -> Result<Option<bool>> {
-    #[derive(Debug, serde::Deserialize)]
-    struct Res {
-        merged: u64,
+async fn handle_merge_now(source: &CommandSource<'_>, ctx: &HandlerContext) -> Result<Outcome> {
+    console_log!("Handling merge now command");
+    let d1 = &ctx.d1;
+    let repo = source.repo();
+
+    let latest = db::merge::find_latest_by_pr(d1, &repo, source.number).await?;
+    let Some(job) = latest.filter(|job| job.state == State::Pending) else {
+        comment_on_issue(
+            source.number,
+            &repo,
+            "It is not possible to merge now in a Pull Request that does not have an automatic merge scheduled",
+            &ctx.token,
+        )
+        .await?;
+        return Ok(Outcome::Rejected);
+    };
+    // グループは全部揃っているときだけまとめてマージする
+    if job.group_id.is_some() {
+        comment_on_issue(
+            source.number,
+            &repo,
+            "This Pull Request is in a merge group, so it can only be merged together with the group",
+            &ctx.token,
+        )
+        .await?;
+        return Ok(Outcome::Rejected);
     }
 
-    let query = query!(
-        &d1,
-        "SELECT merged FROM merge WHERE (pr_number, owner, repository) = (?1, ?2, ?3)",
-        number,
-        owner,
-        &repo,
-    )?;
+    // スケジュールしたときのAppでマージする
+    let apps = AppConfig::load_all(&ctx.env)?;
+    let Some(app) = crate::schedule::app_for_job(&apps, &job).await? else {
+        return Err(Error::RustError(format!(
+            "The app {:?} is not configured",
+            job.app_id
+        )));
+    };
+    let outcome = crate::schedule::merge(d1, app, &job, source.author).await?;
+    crate::repo_scheduler::try_sync(&ctx.env, &repo).await;
+
+    // マージした結果のコメントは`merge`がしている
+    let (message, outcome) = match outcome {
+        MergeOutcome::Merged | MergeOutcome::AutoMerge | MergeOutcome::Queued => {
+            return Ok(Outcome::Success)
+        }
+        MergeOutcome::Failed => return Ok(Outcome::Failed),
+        MergeOutcome::Busy => (
+            "The repository is busy with another operation. This Pull Request will be merged as soon as it is free",
+            Outcome::Rejected,
+        ),
+        MergeOutcome::NotPending => (
+            "The scheduled merge has already been started or cancelled",
+            Outcome::Rejected,
+        ),
+    };
+    comment_on_issue(source.number, &repo, message, &ctx.token).await?;
+
+    Ok(outcome)
+}
+
+async fn handle_merge_mode(
+    source: &CommandSource<'_>,
+    ctx: &HandlerContext,
+    mode: Option<MergeMode>,
+) -> Result<Outcome> {
+    console_log!("Handling merge mode command");
+    let repo = source.repo();
+
+    let message = match mode {
+        Some(mode) => {
+            db::setting::set_merge_mode(&ctx.d1, source.repository_id, mode).await?;
+            format!(
+                "The merge mode of this repository has been set to `{}`",
+                mode.as_str()
+            )
+        }
+        None => {
+            let mode = db::setting::merge_mode(&ctx.d1, Some(source.repository_id)).await?;
+            format!("The merge mode of this repository is `{}`", mode.as_str())
+        }
+    };
+    comment_on_issue(source.number, &repo, &message, &ctx.token).await?;
+
+    Ok(Outcome::Success)
+}
+
+async fn handle_train_set(
+    source: &CommandSource<'_>,
+    ctx: &HandlerContext,
+    departure: Recurrence,
+    label: Option<&str>,
+    catch_up: Option<CatchUp>,
+) -> Result<Outcome> {
+    console_log!("Handling train set command");
+    let label = label.unwrap_or(crate::train::DEFAULT_LABEL);
+    let catch_up = catch_up.unwrap_or_default();
+    let next = crate::recurring::next_run(&departure);
+
+    db::recurring::set(
+        &ctx.d1,
+        &NewRecurringJob {
+            kind: Kind::Train,
+            repo: source.repo(),
+            repository_id: source.repository_id,
+            installation_id: ctx.installation_id,
+            app_id: ctx.app_id,
+            rule: &departure.to_string(),
+            argument: Some(label),
+            catch_up,
+            next_run_at: next,
+            updated_by: source.author,
+        },
+    )
+    .await?;
+
+    let message = format!(
+        "The release train of this repository has been set to depart `{departure}` with Pull Requests labelled `{label}` (catch-up: `{}`). The next departure is at {}",
+        catch_up.as_str(),
+        format_jst(next)
+    );
+    comment_on_issue(source.number, &source.repo(), &message, &ctx.token).await?;
+
+    Ok(Outcome::Success)
+}
+
+async fn handle_train_off(source: &CommandSource<'_>, ctx: &HandlerContext) -> Result<Outcome> {
+    console_log!("Handling train off command");
+
+    let (message, outcome) =
+        if db::recurring::delete(&ctx.d1, Kind::Train, source.repository_id).await? {
+            (
+                "The release train of this repository has been stopped",
+                Outcome::Success,
+            )
+        } else {
+            (
+                "There is no release train in this repository",
+                Outcome::Rejected,
+            )
+        };
+    comment_on_issue(source.number, &source.repo(), message, &ctx.token).await?;
+
+    Ok(outcome)
+}
 
-    let result = query.run().await?.results::<Res>()?;
+async fn handle_train_show(source: &CommandSource<'_>, ctx: &HandlerContext) -> Result<Outcome> {
+    console_log!("Handling train show command");
+
+    let message = match db::recurring::find(&ctx.d1, Kind::Train, source.repository_id).await? {
+        Some(train) => {
+            let next = NaiveDateTime::parse_from_str(&train.next_run_at, "%Y-%m-%d %H:%M:%S")
+                .map_err(|e| Error::RustError(format!("Invalid next_run_at: {e}")))?;
+            format!(
+                "The release train of this repository departs `{}` with Pull Requests labelled `{}` (catch-up: `{}`). The next departure is at {}",
+                train.rule,
+                train.argument.as_deref().unwrap_or(crate::train::DEFAULT_LABEL),
+                train.catch_up.as_str(),
+                format_jst(next)
+            )
+        }
+        None => "There is no release train in this repository. You can start one with `train every weekday 17:00`".into(),
+    };
+    comment_on_issue(source.number, &source.repo(), &message, &ctx.token).await?;
+
+    Ok(Outcome::Success)
+}
+
+/// リポジトリのリースを取る。取れなければ理由をコメントしてNone
+async fn acquire_lease<'a>(
+    source: &CommandSource<'_>,
+    ctx: &'a HandlerContext,
+) -> Result<Option<RepoLease<'a>>> {
+    let repo = source.repo();
+    if let Some(lease) = RepoLease::acquire(&ctx.d1, &repo, LEASE_WAIT).await? {
+        return Ok(Some(lease));
+    }
 
-    if result.is_empty() {
-        Ok(None)
+    let latest = db::merge::find_latest_by_pr(&ctx.d1, &repo, source.number).await?;
+    let message = if latest.is_some_and(|job| job.state == State::Merging) {
+        ALREADY_MERGING
     } else {
-        Ok(Some(result[0].merged == 1))
+        "Another operation is running on this repository. Please try again later"
+    };
+    comment_on_issue(source.number, &repo, message, &ctx.token).await?;
+
+    Ok(None)
+}
+
+async fn handle_history(source: &CommandSource<'_>, ctx: &HandlerContext) -> Result<Outcome> {
+    console_log!("Handling history command");
+    let repo = source.repo();
+    let entries = db::audit::find_by_pr(&ctx.d1, &repo, source.number, 20).await?;
+
+    comment_on_issue(source.number, &repo, &format_history(&entries), &ctx.token).await?;
+
+    Ok(Outcome::Success)
+}
+
+/// 時間はJSTで表示する
+fn format_history(entries: &[AuditEntry]) -> String {
+    if entries.is_empty() {
+        return "There is no history for this Pull Request".into();
     }
+
+    let mut message =
+        String::from("| Time (JST) | Actor | Action | Result |\n| --- | --- | --- | --- |\n");
+
+    // 古い順に並べる
+    for entry in entries.iter().rev() {
+        let time = NaiveDateTime::parse_from_str(&entry.created_at, "%Y-%m-%d %H:%M:%S")
+            .map(|time| {
+                time.and_utc()
+                    .with_timezone(&jst())
+                    .format("%Y-%m-%d %H:%M")
+                    .to_string()
+            })
+            .unwrap_or_else(|_| entry.created_at.clone());
+        let result = match &entry.error {
+            Some(error) => format!("{} ({error})", entry.result.as_str()),
+            None => entry.result.as_str().into(),
+        };
+
+        message.push_str(&format!(
+            "| {time} | {} | `{}` | {} |\n",
+            entry.actor,
+            entry.action,
+            result.replace('|', "\\|").replace('\n', " ")
+        ));
+    }
+
+    message
 }
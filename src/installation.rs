@@ -0,0 +1,377 @@
+//! `installation`と`installation_repositories`、`repository`を処理する
+//! アンインストールされたりリポジトリが外されたりしたらスケジュールをキャンセルして繰り返しのジョブを止め、
+//! suspendされている間はスケジュールを止めておく
+//! リポジトリがrenameやtransferされたら保存している名前を更新する
+
+use github_webhook::payload_types as gh;
+use worker::*;
+
+use crate::db::audit::{self, NewAuditEntry, Outcome};
+use crate::db::installation::{self, Installation, InstallationRepository};
+use crate::db::merge;
+use crate::db::recurring;
+use crate::github::Repo;
+
+pub async fn created(
+    event: gh::InstallationCreatedEvent<'_>,
+    d1: &D1Database,
+    app_id: u64,
+) -> Result<()> {
+    console_log!("Handling the event as InstallationCreatedEvent");
+    let installation = &event.installation;
+
+    let mut queries = vec![
+        installation::upsert_query(
+            d1,
+            &Installation {
+                id: installation.id,
+                app_id,
+                account: &installation.account.login,
+            },
+        )?,
+        audit_query(
+            d1,
+            &event.sender.login,
+            installation.id,
+            None,
+            "installation.created",
+        )?,
+    ];
+
+    for repo in event.repositories.iter().flatten() {
+        queries.push(add_repository_query(
+            d1,
+            installation.id,
+            repo.id,
+            &repo.full_name,
+        )?);
+    }
+
+    d1.batch(queries).await?;
+    Ok(())
+}
+
+pub async fn deleted(event: gh::InstallationDeletedEvent<'_>, d1: &D1Database) -> Result<()> {
+    console_log!("Handling the event as InstallationDeletedEvent");
+    let installation_id = event.installation.id;
+
+    // トークンが作れなくなるので、残っていても失敗し続けるだけ
+    d1.batch(vec![
+        merge::cancel_by_installation_query(d1, installation_id)?,
+        recurring::delete_by_installation_query(d1, installation_id)?,
+        installation::delete_repositories_query(d1, installation_id)?,
+        installation::delete_query(d1, installation_id)?,
+        audit_query(
+            d1,
+            &event.sender.login,
+            installation_id,
+            None,
+            "installation.deleted",
+        )?,
+    ])
+    .await?;
+    Ok(())
+}
+
+pub async fn suspend(event: gh::InstallationSuspendEvent<'_>, d1: &D1Database) -> Result<()> {
+    console_log!("Handling the event as InstallationSuspendEvent");
+    set_suspended(d1, &event.sender.login, event.installation.id, true).await
+}
+
+pub async fn unsuspend(
+    event: gh::InstallationUnsuspendEvent<'_>,
+    d1: &D1Database,
+    env: &Env,
+) -> Result<()> {
+    console_log!("Handling the event as InstallationUnsuspendEvent");
+    let installation_id = event.installation.id;
+    set_suspended(d1, &event.sender.login, installation_id, false).await?;
+
+    // 戻したスケジュールのアラームを掛け直す。cronを待つと遅れる
+    for repository in merge::pending_repositories_by_installation(d1, installation_id).await? {
+        crate::repo_scheduler::try_sync(env, &repository.repo()).await;
+    }
+    Ok(())
+}
+
+async fn set_suspended(
+    d1: &D1Database,
+    sender: &str,
+    installation_id: u64,
+    suspended: bool,
+) -> Result<()> {
+    let action = if suspended {
+        "installation.suspend"
+    } else {
+        "installation.unsuspend"
+    };
+
+    d1.batch(vec![
+        installation::set_suspended_query(d1, installation_id, suspended)?,
+        merge::set_suspended_by_installation_query(d1, installation_id, suspended)?,
+        audit_query(d1, sender, installation_id, None, action)?,
+    ])
+    .await?;
+    Ok(())
+}
+
+pub async fn repositories_added(
+    event: gh::InstallationRepositoriesAddedEvent<'_>,
+    d1: &D1Database,
+) -> Result<()> {
+    console_log!("Handling the event as InstallationRepositoriesAddedEvent");
+    let installation_id = event.installation.id;
+
+    let mut queries = vec![];
+    for repo in &event.repositories_added {
+        let (owner, name) = split_full_name(&repo.full_name)?;
+
+        queries.push(add_repository_query(
+            d1,
+            installation_id,
+            repo.id,
+            &repo.full_name,
+        )?);
+        queries.push(audit_query(
+            d1,
+            &event.sender.login,
+            installation_id,
+            Some(Repo {
+                owner,
+                name,
+                id: Some(repo.id),
+            }),
+            "installation_repositories.added",
+        )?);
+    }
+
+    if !queries.is_empty() {
+        d1.batch(queries).await?;
+    }
+    Ok(())
+}
+
+pub async fn repositories_removed(
+    event: gh::InstallationRepositoriesRemovedEvent<'_>,
+    d1: &D1Database,
+) -> Result<()> {
+    console_log!("Handling the event as InstallationRepositoriesRemovedEvent");
+    let installation_id = event.installation.id;
+
+    let mut queries = vec![];
+    for repo in &event.repositories_removed {
+        let (owner, name) = split_full_name(&repo.full_name)?;
+
+        queries.push(merge::cancel_by_repository_query(
+            d1,
+            installation_id,
+            repo.id,
+            owner,
+            name,
+        )?);
+        queries.push(recurring::delete_by_repository_query(
+            d1,
+            installation_id,
+            repo.id,
+        )?);
+        queries.push(installation::delete_repository_query(
+            d1,
+            installation_id,
+            repo.id,
+        )?);
+        queries.push(audit_query(
+            d1,
+            &event.sender.login,
+            installation_id,
+            Some(Repo {
+                owner,
+                name,
+                id: Some(repo.id),
+            }),
+            "installation_repositories.removed",
+        )?);
+    }
+
+    if !queries.is_empty() {
+        d1.batch(queries).await?;
+    }
+    Ok(())
+}
+
+pub async fn repository_renamed(
+    event: gh::RepositoryRenamedEvent<'_>,
+    d1: &D1Database,
+) -> Result<()> {
+    console_log!("Handling the event as RepositoryRenamedEvent");
+    let repo = &event.repository;
+    let owner = &repo.owner.login;
+
+    // repository_idがない古い行は前の名前で探す
+    let mut queries = vec![merge::adopt_legacy_repository_query(
+        d1,
+        repo.id,
+        owner,
+        &repo.name,
+        &event.changes.repository.name.from,
+    )?];
+    queries.extend(rename_repository_queries(d1, repo.id, owner, &repo.name)?);
+    queries.push(merge::rename_after_query(
+        d1,
+        &Repo {
+            owner,
+            name: &event.changes.repository.name.from,
+            id: None,
+        },
+        &Repo {
+            owner,
+            name: &repo.name,
+            id: Some(repo.id),
+        },
+    )?);
+    queries.push(repository_audit_query(
+        d1,
+        &event.sender.login,
+        repo,
+        event
+            .installation
+            .as_ref()
+            .map(|installation| installation.id),
+        "repository.renamed",
+    )?);
+
+    d1.batch(queries).await?;
+    Ok(())
+}
+
+pub async fn repository_transferred(
+    event: gh::RepositoryTransferredEvent<'_>,
+    d1: &D1Database,
+) -> Result<()> {
+    console_log!("Handling the event as RepositoryTransferredEvent");
+    let repo = &event.repository;
+
+    let mut queries = rename_repository_queries(d1, repo.id, &repo.owner.login, &repo.name)?;
+    // 前のownerのPRを待っているものも新しい名前にする
+    let from = &event.changes.owner.from;
+    let from_owner = from
+        .organization
+        .as_ref()
+        .map(|organization| &*organization.login)
+        .or(from.user.as_ref().map(|user| &*user.login));
+    if let Some(from_owner) = from_owner {
+        queries.push(merge::rename_after_query(
+            d1,
+            &Repo {
+                owner: from_owner,
+                name: &repo.name,
+                id: None,
+            },
+            &Repo {
+                owner: &repo.owner.login,
+                name: &repo.name,
+                id: Some(repo.id),
+            },
+        )?);
+    }
+    queries.push(repository_audit_query(
+        d1,
+        &event.sender.login,
+        repo,
+        event
+            .installation
+            .as_ref()
+            .map(|installation| installation.id),
+        "repository.transferred",
+    )?);
+
+    d1.batch(queries).await?;
+    Ok(())
+}
+
+fn rename_repository_queries(
+    d1: &D1Database,
+    repository_id: u64,
+    owner: &str,
+    name: &str,
+) -> Result<Vec<D1PreparedStatement>> {
+    Ok(vec![
+        merge::rename_repository_query(d1, repository_id, owner, name)?,
+        installation::rename_repository_query(d1, repository_id, owner, name)?,
+        recurring::rename_repository_query(d1, repository_id, owner, name)?,
+        audit::rename_repository_query(d1, repository_id, owner, name)?,
+    ])
+}
+
+fn audit_query(
+    d1: &D1Database,
+    sender: &str,
+    installation_id: u64,
+    repo: Option<Repo<'_>>,
+    action: &str,
+) -> Result<D1PreparedStatement> {
+    audit::insert_query(
+        d1,
+        &NewAuditEntry {
+            actor: sender,
+            installation_id: Some(installation_id),
+            repo,
+            pr_number: None,
+            action,
+            command: None,
+            result: Outcome::Success,
+            error: None,
+        },
+    )
+}
+
+fn repository_audit_query(
+    d1: &D1Database,
+    sender: &str,
+    repo: &gh::Repository<'_>,
+    installation_id: Option<u64>,
+    action: &str,
+) -> Result<D1PreparedStatement> {
+    audit::insert_query(
+        d1,
+        &NewAuditEntry {
+            actor: sender,
+            installation_id,
+            repo: Some(Repo {
+                owner: &repo.owner.login,
+                name: &repo.name,
+                id: Some(repo.id),
+            }),
+            pr_number: None,
+            action,
+            command: None,
+            result: Outcome::Success,
+            error: None,
+        },
+    )
+}
+
+fn add_repository_query(
+    d1: &D1Database,
+    installation_id: u64,
+    repository_id: u64,
+    full_name: &str,
+) -> Result<D1PreparedStatement> {
+    let (owner, name) = split_full_name(full_name)?;
+
+    installation::upsert_repository_query(
+        d1,
+        &InstallationRepository {
+            installation_id,
+            repository_id,
+            owner,
+            name,
+        },
+    )
+}
+
+/// `owner/name`
+fn split_full_name(full_name: &str) -> Result<(&str, &str)> {
+    full_name
+        .split_once('/')
+        .ok_or_else(|| Error::RustError(format!("Invalid repository name: {full_name}")))
+}
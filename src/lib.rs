@@ -1,100 +1,394 @@
 //! エントリポイントになってデシリアライズとルーティングをしている
 //! src/handle.rs にルート先の関数が置かれている
 
+mod admin;
+mod app;
+mod check_run;
 mod crypt;
+mod db;
+mod dependency;
 mod error;
 mod github;
+mod group;
 mod handle;
+mod installation;
+mod merge_queue;
 mod parser;
+mod recurring;
+mod repo_scheduler;
 mod schedule;
+mod stack;
+mod status;
+mod train;
 
-use crypt::GitHubApp;
+use app::AppConfig;
 use github::GitHubEvent;
 use worker::*;
 
 use github_webhook::payload_types as gh;
 
 use serde::de::Deserialize;
+use std::rc::Rc;
 
+/// Appのslugが取得できるまでのUser-Agent
 const APP_NAME: &str = "satler-bot";
 
 #[event(fetch, respond_with_errors)]
-pub async fn main(req: Request, env: Env, _ctx: worker::Context) -> Result<Response> {
+pub async fn main(req: Request, env: Env, ctx: worker::Context) -> Result<Response> {
     console_error_panic_hook::set_once();
 
-    let router = Router::new();
+    // WebHookの処理をレスポンスの後に回すために使う
+    let router = Router::with_data(Rc::new(ctx));
 
     router
         .get_async("/", |_req, _ctx| async move {
             Response::redirect(Url::parse("https://github.com/satler-git/bot")?)
         })
+        .get_async("/status", status::status)
         .post_async("/webhook", webhook)
+        .post_async("/webhook/:app", webhook)
+        .get_async("/admin/merges", admin::list_merges)
+        .post_async("/admin/merges/:id/cancel", admin::cancel_merge)
+        .post_async("/admin/merges/:id/run", admin::run_merge)
+        .get_async("/admin/audit", admin::list_audit)
+        .get_async("/admin/dead-letters", admin::list_dead_letters)
+        .post_async("/admin/dead-letters/:id/replay", admin::replay_dead_letter)
+        .post_async("/admin/status-link", admin::status_link)
+        .post_async("/admin/schedule", admin::run_scheduler)
         .run(req, env)
         .await
 }
 
-async fn webhook(req: Request, ctx: RouteContext<()>) -> Result<Response> {
-    let github_app = GitHubApp::new(
-        include_str!("../secret.pem"),
-        &ctx.secret("GITHUB_CLIENT_ID")?.to_string(),
-    );
+async fn webhook(req: Request, ctx: RouteContext<Rc<worker::Context>>) -> Result<Response> {
+    // `/webhook/{app}`かヘッダーでどのAppか決める
+    let target_id = req
+        .headers()
+        .get("X-GitHub-Hook-Installation-Target-ID")?
+        .and_then(|id| id.parse().ok());
+    let Some(app) =
+        AppConfig::resolve(&ctx.env, ctx.param("app").map(String::as_str), target_id).await?
+    else {
+        return Response::error("Not Found (unknown app)", 404);
+    };
 
-    let webhook_sec: String = ctx.secret("WEBHOOK_SEC")?.to_string();
+    let webhook_secrets = crypt::parse_secrets(&app.webhook_secret);
     let signature: Option<String> = req.headers().get("X-Hub-Signature-256")?;
 
     let mut req = req;
 
-    if let Some(sig) = signature {
-        let body = req.text().await?;
+    let Some(sig) = signature else {
+        return Response::error("Unauthorised (signature does not exit)", 401);
+    };
+    let Some(sig) = crypt::parse_signature_header(&sig) else {
+        return Response::error("Bad Request (signature is malformed)", 400);
+    };
+
+    let body = req.text().await?;
+
+    let Some(matched) = crypt::find_matching_secret(&body, &webhook_secrets, sig) else {
+        return Response::error("Unauthorised (signature did not match)", 401);
+    };
+    // 古いsecretを消していいか判断するため
+    console_log!(
+        "Signature matched the webhook secret #{matched} ({}) of the app {}",
+        crypt::secret_fingerprint(webhook_secrets[matched]),
+        app.name
+    );
+
+    let Some(event_type) = req.headers().get("X-GitHub-Event")? else {
+        return Response::error("Bad Request (X-GitHub-Event does not exist)", 400);
+    };
+    let Ok(payload) = serde_json::from_str(&body) else {
+        return Response::error("Bad Request (payload is not JSON)", 400);
+    };
+    let github_event = GitHubEvent {
+        _type: event_type.as_str().into(),
+        payload,
+    };
 
-        if !crypt::verify_signature(&body, &webhook_sec, &sig[7..] /* sha256= */) {
-            return Response::error("Unauthorised (signature did not match)", 401);
+    let d1 = ctx.env.d1("DB")?;
+    let delivery_id = req.headers().get("X-GitHub-Delivery")?;
+
+    // 再送されたものは処理しない
+    if let Some(delivery_id) = &delivery_id {
+        if !db::delivery::claim_delivery(&d1, delivery_id).await? {
+            console_log!("Delivery {delivery_id} has already been processed");
+            return Response::empty();
         }
+    } else {
+        console_warn!("X-GitHub-Delivery does not exist");
+    }
 
-        let github_event = {
-            let event_type = req.headers().get("X-GitHub-Event")?.unwrap().into();
-            GitHubEvent {
-                _type: event_type,
-                payload: serde_json::from_str(&body).map_err(Error::SerdeJsonError)?,
+    // GitHubは10秒でタイムアウトするので先に返して処理は後でする
+    let env = ctx.env.clone();
+    ctx.data.wait_until(async move {
+        let result = dispatch(github_event, &app, &env).await;
+
+        if let Err(e) = result {
+            console_error!("Failed to process the delivery {delivery_id:?}: {e}");
+            // 手動で再送されたときに処理できるようにする
+            if let Some(delivery_id) = &delivery_id {
+                if let Err(e) = db::delivery::release_delivery(&d1, delivery_id).await {
+                    console_error!("Failed to release the delivery: {e}");
+                }
             }
-        };
+            let dead_letter = db::delivery::NewDeadLetter {
+                app: &app.name,
+                delivery_id: delivery_id.as_deref(),
+                event: &event_type,
+                payload: &body,
+                error: &e.to_string(),
+            };
+            if let Err(e) = db::delivery::insert_dead_letter(&d1, &dead_letter).await {
+                console_error!("Failed to record the failure: {e}");
+            }
+        }
+    });
+
+    Ok(Response::empty()?.with_status(202))
+}
 
-        match github_event._type {
-            github::EventType::IssueComment => {
-                let issue_comment_event = gh::IssueCommentEvent::deserialize(&github_event.payload)
+async fn dispatch(github_event: GitHubEvent, app: &AppConfig, env: &Env) -> Result<()> {
+    match github_event._type {
+        github::EventType::IssueComment => {
+            let issue_comment_event = gh::IssueCommentEvent::deserialize(&github_event.payload)
+                .map_err(Error::SerdeJsonError)?;
+
+            match issue_comment_event {
+                gh::IssueCommentEvent::Created(event) => {
+                    // Appのインストールから届いたものでなければトークンが作れない
+                    let Some(installation) = event.installation.as_ref().map(|i| i.id) else {
+                        console_warn!("Ignored an event without an installation");
+                        return Ok(());
+                    };
+                    let handler_ctx = handler_context(app, env, installation).await?;
+
+                    handle::issue_comment_created(event, handler_ctx).await?;
+
+                    Ok(())
+                }
+                gh::IssueCommentEvent::Edited(_) => Ok(()),
+                gh::IssueCommentEvent::Deleted(_) => Ok(()),
+            }
+        }
+        github::EventType::PullRequestReview => {
+            let review_event = gh::PullRequestReviewEvent::deserialize(&github_event.payload)
+                .map_err(Error::SerdeJsonError)?;
+
+            match review_event {
+                gh::PullRequestReviewEvent::Submitted(event) => {
+                    // Appのインストールから届いたものでなければトークンが作れない
+                    let Some(installation) = event.installation.as_ref().map(|i| i.id) else {
+                        console_warn!("Ignored an event without an installation");
+                        return Ok(());
+                    };
+                    let handler_ctx = handler_context(app, env, installation).await?;
+
+                    handle::pull_request_review_submitted(event, handler_ctx).await?;
+
+                    Ok(())
+                }
+                _ => Ok(()),
+            }
+        }
+        github::EventType::PullRequestReviewComment => {
+            let review_comment_event =
+                gh::PullRequestReviewCommentEvent::deserialize(&github_event.payload)
                     .map_err(Error::SerdeJsonError)?;
 
-                match issue_comment_event {
-                    gh::IssueCommentEvent::Created(event) => {
-                        let d1 = ctx.env.d1("DB")?;
+            match review_comment_event {
+                gh::PullRequestReviewCommentEvent::Created(event) => {
+                    // Appのインストールから届いたものでなければトークンが作れない
+                    let Some(installation) = event.installation.as_ref().map(|i| i.id) else {
+                        console_warn!("Ignored an event without an installation");
+                        return Ok(());
+                    };
+                    let handler_ctx = handler_context(app, env, installation).await?;
 
-                        let installation = event.installation.as_ref().unwrap().id;
-                        let token = github_app.token(installation).await?;
+                    handle::pull_request_review_comment_created(event, handler_ctx).await?;
 
-                        handle::issue_comment_created(event, token, d1, installation).await?;
+                    Ok(())
+                }
+                _ => Ok(()),
+            }
+        }
+        github::EventType::Installation => {
+            let installation_event = gh::InstallationEvent::deserialize(&github_event.payload)
+                .map_err(Error::SerdeJsonError)?;
+            let d1 = env.d1("DB")?;
 
-                        Response::empty()
-                    }
-                    gh::IssueCommentEvent::Edited(_) => Response::empty(),
-                    gh::IssueCommentEvent::Deleted(_) => Response::empty(),
+            match installation_event {
+                gh::InstallationEvent::Created(event) => {
+                    installation::created(event, &d1, app.app_id().await?).await
+                }
+                gh::InstallationEvent::Deleted(event) => installation::deleted(event, &d1).await,
+                gh::InstallationEvent::Suspend(event) => installation::suspend(event, &d1).await,
+                gh::InstallationEvent::Unsuspend(event) => {
+                    installation::unsuspend(event, &d1, env).await
                 }
+                _ => Ok(()),
             }
-            _ => Response::empty(),
         }
-    } else {
-        Response::error("Unauthorised (signature does not exit)", 401)
+        github::EventType::InstallationRepositories => {
+            let installation_repositories_event =
+                gh::InstallationRepositoriesEvent::deserialize(&github_event.payload)
+                    .map_err(Error::SerdeJsonError)?;
+            let d1 = env.d1("DB")?;
+
+            match installation_repositories_event {
+                gh::InstallationRepositoriesEvent::Added(event) => {
+                    installation::repositories_added(event, &d1).await
+                }
+                gh::InstallationRepositoriesEvent::Removed(event) => {
+                    installation::repositories_removed(event, &d1).await
+                }
+            }
+        }
+        github::EventType::Repository => {
+            let repository_event = gh::RepositoryEvent::deserialize(&github_event.payload)
+                .map_err(Error::SerdeJsonError)?;
+            let d1 = env.d1("DB")?;
+
+            match repository_event {
+                gh::RepositoryEvent::Renamed(event) => {
+                    installation::repository_renamed(event, &d1).await
+                }
+                gh::RepositoryEvent::Transferred(event) => {
+                    installation::repository_transferred(event, &d1).await
+                }
+                _ => Ok(()),
+            }
+        }
+        github::EventType::PullRequest => {
+            let pull_request_event =
+                merge_queue::PullRequestEvent::deserialize(&github_event.payload)
+                    .map_err(Error::SerdeJsonError)?;
+
+            // 待っているジョブがあれば進める
+            if pull_request_event.action == "closed" {
+                dependency::prerequisite_closed(
+                    env,
+                    &pull_request_event.repository.repo(),
+                    pull_request_event.number,
+                    pull_request_event.pull_request.merged,
+                )
+                .await?;
+            }
+
+            merge_queue::pull_request(pull_request_event, app, env).await
+        }
+        github::EventType::MergeGroup => {
+            let merge_group_event =
+                merge_queue::MergeGroupEvent::deserialize(&github_event.payload)
+                    .map_err(Error::SerdeJsonError)?;
+
+            merge_queue::merge_group(merge_group_event, app, env).await
+        }
+        github::EventType::CheckRun => {
+            let check_run_event = check_run::CheckRunEvent::deserialize(&github_event.payload)
+                .map_err(Error::SerdeJsonError)?;
+
+            // 自分が作ったチェックのボタンだけ
+            let button = check_run_event
+                .requested_action
+                .as_ref()
+                .and_then(|action| check_run::Action::from_identifier(&action.identifier));
+            match button {
+                Some(button)
+                    if check_run_event.action == "requested_action"
+                        && check_run_event.check_run.name == check_run::NAME =>
+                {
+                    let installation = check_run_event.installation.id;
+                    let handler_ctx = handler_context(app, env, installation).await?;
+
+                    handle::check_run_requested_action(check_run_event, button, handler_ctx).await
+                }
+                _ => Ok(()),
+            }
+        }
+        _ => Ok(()),
     }
 }
 
+async fn handler_context(
+    app: &AppConfig,
+    env: &Env,
+    installation_id: u64,
+) -> Result<handle::HandlerContext> {
+    let identity = app.github_app.identity().await?;
+
+    Ok(handle::HandlerContext {
+        token: app.github_app.token(installation_id).await?,
+        d1: env.d1("DB")?,
+        env: env.clone(),
+        installation_id,
+        app_id: identity.id,
+        slug: identity.slug,
+        authorised_users: app.authorised_users.clone(),
+        command_triggers: app.command_triggers.clone(),
+    })
+}
+
 #[event(scheduled)]
 pub async fn scheduled_handler(_event: ScheduledEvent, env: Env, _ctx: ScheduleContext) {
-    let github_app = GitHubApp::new(
-        include_str!("../secret.pem"),
-        &env.secret("GITHUB_CLIENT_ID").unwrap().to_string(),
+    if let Err(e) = run_scheduled(&env).await {
+        console_error!("Failed to run the scheduled tasks: {e}");
+    }
+}
+
+/// cronと`POST /admin/schedule`で動かす
+async fn run_scheduled(env: &Env) -> Result<()> {
+    let apps = AppConfig::load_all(env)?;
+
+    let d1 = env.d1("DB")?;
+    // 1つが失敗しても残りは続ける。漏れたマージの実行と掃除を止めないように
+    log_error(
+        "check blocked merges",
+        dependency::check_blocked(&d1, env, &apps).await,
+    );
+    log_error("run merge groups", group::run_due(&d1, &apps).await);
+    log_error(
+        "run recurring jobs",
+        recurring::run_due(&d1, env, &apps).await,
     );
+    log_error(
+        "run overdue merges",
+        schedule::auto_merge(&d1, env, &apps).await,
+    );
+    log_error("prune deliveries", db::delivery::prune(&d1).await);
+    log_error("prune audit entries", db::audit::prune(&d1).await);
+    log_error("prune leases", db::lease::prune(&d1).await);
+    log_error("replay dead letters", replay_dead_letters(&d1, env).await);
+
+    Ok(())
+}
 
-    {
-        let d1 = env.d1("DB").unwrap();
-        schedule::auto_merge(&d1, github_app).await.unwrap();
+fn log_error(step: &str, result: Result<()>) {
+    if let Err(e) = result {
+        console_error!("Failed to {step}: {e}");
     }
 }
+
+/// 再実行するように指定された失敗した配送を処理する
+async fn replay_dead_letters(d1: &D1Database, env: &Env) -> Result<()> {
+    for dead_letter in db::delivery::replay_requested(d1, 5).await? {
+        console_log!("Replaying the dead letter {}", dead_letter.id);
+        // Appが消えていたりペイロードが壊れていても失敗として記録して、後の配送を止めない
+        let result = match (
+            AppConfig::load(env, &dead_letter.app),
+            serde_json::from_str(&dead_letter.payload).map_err(Error::SerdeJsonError),
+        ) {
+            (Ok(app), Ok(payload)) => {
+                let github_event = GitHubEvent {
+                    _type: dead_letter.event.as_str().into(),
+                    payload,
+                };
+                dispatch(github_event, &app, env).await
+            }
+            (Err(e), _) | (_, Err(e)) => Err(e),
+        };
+        db::delivery::mark_replayed(d1, dead_letter.id, result.as_ref().err()).await?;
+    }
+
+    Ok(())
+}
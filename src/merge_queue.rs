@@ -0,0 +1,238 @@
+//! auto-mergeかマージキューでGitHubに任せたジョブの結果を受け取る
+//! `pull_request`の`closed`と`dequeued`、`merge_group`の`destroyed`を処理する
+//! 同じ結果が複数のイベントで届くので、最初に状態を変えたものだけがコメントする
+
+use worker::*;
+
+use crate::app::AppConfig;
+use crate::db::{
+    self,
+    audit::{NewAuditEntry, Outcome},
+    merge::{MergeJob, State},
+};
+use crate::github::{comment_on_issue, Repo};
+
+const MERGED: &str = "The Pull Request has been merged by GitHub as scheduled";
+
+// 使うところだけ自前でデシリアライズする
+
+#[derive(Debug, serde::Deserialize)]
+pub struct Owner {
+    pub login: String,
+}
+
+#[derive(Debug, serde::Deserialize)]
+pub struct Repository {
+    pub id: u64,
+    pub name: String,
+    pub owner: Owner,
+}
+
+impl Repository {
+    pub fn repo(&self) -> Repo<'_> {
+        Repo {
+            owner: &self.owner.login,
+            name: &self.name,
+            id: Some(self.id),
+        }
+    }
+}
+
+#[derive(Debug, serde::Deserialize)]
+pub struct Sender {
+    pub login: String,
+}
+
+#[derive(Debug, serde::Deserialize)]
+pub struct PullRequest {
+    #[serde(default)]
+    pub merged: bool,
+}
+
+#[derive(Debug, serde::Deserialize)]
+pub struct PullRequestEvent {
+    pub action: String,
+    pub number: u64,
+    pub pull_request: PullRequest,
+    /// `dequeued`のとき
+    pub reason: Option<String>,
+    pub repository: Repository,
+    pub sender: Sender,
+}
+
+#[derive(Debug, serde::Deserialize)]
+pub struct MergeGroup {
+    /// `refs/heads/gh-readonly-queue/<base>/pr-<number>-<sha>`
+    pub head_ref: String,
+}
+
+#[derive(Debug, serde::Deserialize)]
+pub struct MergeGroupEvent {
+    pub action: String,
+    /// `destroyed`のとき。`merged`、`invalidated`、`dequeued`
+    pub reason: Option<String>,
+    pub merge_group: MergeGroup,
+    pub repository: Repository,
+    pub sender: Sender,
+}
+
+pub async fn pull_request(event: PullRequestEvent, app: &AppConfig, env: &Env) -> Result<()> {
+    let (state, message) = match event.action.as_str() {
+        "closed" if event.pull_request.merged => (State::Merged, Some(MERGED.to_string())),
+        // 閉じられたのでコメントはしない
+        "closed" => (State::Cancelled, None),
+        "dequeued" => {
+            let reason = event.reason.as_deref().unwrap_or("unknown");
+            match reason.to_ascii_uppercase().as_str() {
+                // マージされたときは`closed`で処理する
+                "MERGE" => return Ok(()),
+                "MANUAL" => (
+                    State::Cancelled,
+                    Some("The Pull Request was removed from the merge queue manually, so the automatic merge has been cancelled".to_string()),
+                ),
+                _ => (
+                    State::Failed,
+                    Some(format!(
+                        "The Pull Request was removed from the merge queue ({reason}). We couldn't merge this time"
+                    )),
+                ),
+            }
+        }
+        _ => return Ok(()),
+    };
+    console_log!("Handling the event as PullRequestEvent ({})", event.action);
+
+    let d1 = env.d1("DB")?;
+    let repo = event.repository.repo();
+    let Some(job) = db::merge::settle_by_pr(&d1, &repo, event.number, state).await? else {
+        return Ok(());
+    };
+
+    report(
+        &d1,
+        app,
+        env,
+        &job,
+        &event.sender.login,
+        &format!("pull_request.{}", event.action),
+        state,
+        event.reason.as_deref(),
+        message.as_deref(),
+    )
+    .await
+}
+
+pub async fn merge_group(event: MergeGroupEvent, app: &AppConfig, env: &Env) -> Result<()> {
+    // 失敗したときはPRごとに`pull_request.dequeued`が届く
+    if event.action != "destroyed" || event.reason.as_deref() != Some("merged") {
+        return Ok(());
+    }
+    let Some(pr_number) = queued_pr_number(&event.merge_group.head_ref) else {
+        console_warn!("Unknown merge group ref: {}", event.merge_group.head_ref);
+        return Ok(());
+    };
+    console_log!("Handling the event as MergeGroupEvent ({})", event.action);
+
+    let d1 = env.d1("DB")?;
+    let repo = event.repository.repo();
+    let Some(job) = db::merge::settle_by_pr(&d1, &repo, pr_number, State::Merged).await? else {
+        return Ok(());
+    };
+
+    report(
+        &d1,
+        app,
+        env,
+        &job,
+        &event.sender.login,
+        "merge_group.destroyed",
+        State::Merged,
+        None,
+        Some(MERGED),
+    )
+    .await
+}
+
+#[allow(clippy::too_many_arguments)]
+async fn report(
+    d1: &D1Database,
+    app: &AppConfig,
+    env: &Env,
+    job: &MergeJob,
+    actor: &str,
+    action: &str,
+    state: State,
+    reason: Option<&str>,
+    message: Option<&str>,
+) -> Result<()> {
+    let repo = job.repo();
+
+    db::audit::record(
+        d1,
+        &NewAuditEntry {
+            actor,
+            installation_id: Some(job.installation_id),
+            repo: Some(repo),
+            pr_number: Some(job.pr_number),
+            action,
+            command: None,
+            result: if state == State::Failed {
+                Outcome::Failed
+            } else {
+                Outcome::Success
+            },
+            error: reason.filter(|_| state == State::Failed),
+        },
+    )
+    .await;
+
+    let ctx = crate::handler_context(app, env, job.installation_id).await?;
+    crate::check_run::try_sync(d1, &repo, job.pr_number, &ctx.token).await;
+    if let Some(message) = message {
+        comment_on_issue(job.pr_number, &repo, message, &ctx.token).await?;
+    }
+    if state == State::Failed {
+        crate::stack::stop(d1, &ctx.token, job).await?;
+    }
+    Ok(())
+}
+
+/// マージキューが作るブランチからPRの番号を取り出す
+fn queued_pr_number(head_ref: &str) -> Option<u64> {
+    let branch = head_ref.strip_prefix("refs/heads/").unwrap_or(head_ref);
+    if !branch.starts_with("gh-readonly-queue/") {
+        return None;
+    }
+
+    // ベースブランチには`/`が入っているかもしれないので最後だけ見る
+    let (number, _sha) = branch
+        .rsplit('/')
+        .next()?
+        .strip_prefix("pr-")?
+        .split_once('-')?;
+    number.parse().ok()
+}
+
+#[cfg(test)]
+mod tests {
+    #[test]
+    fn test_queued_pr_number() {
+        assert_eq!(
+            super::queued_pr_number("refs/heads/gh-readonly-queue/main/pr-123-0123abcd"),
+            Some(123)
+        );
+        assert_eq!(
+            super::queued_pr_number("refs/heads/gh-readonly-queue/release/v1/pr-45-0123abcd"),
+            Some(45)
+        );
+        assert_eq!(super::queued_pr_number("refs/heads/main"), None);
+        assert_eq!(
+            super::queued_pr_number("refs/heads/gh-readonly-queue/main/pr-abc-0123abcd"),
+            None
+        );
+        assert_eq!(
+            super::queued_pr_number("refs/heads/feature/pr-123-0123abcd"),
+            None
+        );
+    }
+}
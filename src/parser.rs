@@ -1,9 +1,19 @@
 pub mod error;
+mod recurrence;
 mod time;
 
+pub use recurrence::Recurrence;
+pub(crate) use time::{format_jst, jst};
+
+use crate::db::recurring::CatchUp;
+use crate::db::setting::MergeMode;
+
 #[derive(Debug, PartialEq, Eq)]
 pub enum Command {
     Merge(Merge),
+    /// PRの監査ログを表示する
+    History,
+    Train(Train),
     Help,
 }
 
@@ -11,24 +21,240 @@ pub enum Command {
 pub enum Merge {
     /// Asia/Tokyoであるため注意
     Add(chrono::NaiveDateTime),
+    /// `after`のPRがマージされてからマージする。時間がなければすぐ
+    After {
+        time: Option<chrono::NaiveDateTime>,
+        after: PullRequestRef,
+    },
+    /// ベースブランチを辿ってスタックになっているPRを下から順にマージする
+    /// 時間がなければすぐ
+    Stack(Option<chrono::NaiveDateTime>),
+    /// 複数のリポジトリのPRをまとめてマージする
+    Group(Group),
+    /// 予定の時間を待たずにマージする
+    Now,
     Cancel,
+    /// Noneなら今の設定を表示する
+    Mode(Option<MergeMode>),
     #[default]
     Help,
 }
 
+/// `train`: ラベルの付いたPRを決まった時間にまとめてマージする
+#[derive(Debug, PartialEq, Eq, Default)]
+pub enum Train {
+    /// `label`がなければ`train`、`catch_up`がなければ`skip`
+    Set {
+        departure: Recurrence,
+        label: Option<String>,
+        catch_up: Option<CatchUp>,
+    },
+    Off,
+    /// 今の設定を表示する
+    Show,
+    #[default]
+    Help,
+}
+
+impl Train {
+    fn try_parse(input: &[&str]) -> error::Result<Train> {
+        match input.first().map(|s| s.to_lowercase()).as_deref() {
+            Some("off") => Ok(Train::Off),
+            Some("h" | "help") => Ok(Train::Help),
+            Some(_) => {
+                // 予定の後ろに`label`と`catch-up`を書ける
+                let end = input
+                    .iter()
+                    .position(|s| matches!(s.to_lowercase().as_str(), "label" | "catch-up"))
+                    .unwrap_or(input.len());
+                let departure = Recurrence::try_parse(&input[..end])?;
+
+                let mut label = None;
+                let mut catch_up = None;
+                for option in input[end..].chunks(2) {
+                    let value = option.get(1).ok_or(error::Error::NotACommand)?;
+                    match option[0].to_lowercase().as_str() {
+                        "label" if label.is_none() => label = Some(value.to_string()),
+                        "catch-up" if catch_up.is_none() => {
+                            catch_up = match value.to_lowercase().as_str() {
+                                "skip" => Some(CatchUp::Skip),
+                                "once" => Some(CatchUp::Once),
+                                _ => return Err(error::Error::NotACommand),
+                            }
+                        }
+                        _ => return Err(error::Error::NotACommand),
+                    }
+                }
+                Ok(Train::Set {
+                    departure,
+                    label,
+                    catch_up,
+                })
+            }
+            Option::None => Ok(Train::Show),
+        }
+    }
+}
+
+impl Help for Train {
+    const HELP: &str = "
+`train` command help.
+
+A release train merges every ready Pull Request with a label at recurring times.
+The times are in Asia/Tokyo unless a time zone is given.
+
+# Sub-commands
+
+- `train every weekday 17:00`: Departs at 17:00 on weekdays and merges Pull Requests labelled `train`.
+    - The days can be `day`, `weekday`, `weekend` or a list like `mon,wed,fri`.
+    - A time zone without daylight saving time can follow, like `Asia/Tokyo`, `UTC` or `+05:30`.
+    - `train cron 0 17 * * 1-5`: Uses a cron expression (minute, hour, day, month and day of week).
+    - `train RRULE:FREQ=WEEKLY;BYDAY=MO;BYHOUR=9`: Uses an iCalendar RRULE (`DAILY`, `WEEKLY` or `MONTHLY`).
+    - `train every weekday 17:00 label ship-it`: Uses another label.
+    - `train every weekday 17:00 catch-up once`: Departs once when a departure was missed by more than an hour,
+      for example while the app was suspended. By default (`catch-up skip`) missed departures are skipped.
+- `train`: Show the train of this repository and its next departure.
+- `train off`: Stop the train.
+- `help` (`h`): Display this help message.
+
+At each departure, the labelled Pull Requests are merged from the oldest one,
+if they are ready at that moment. A summary of what shipped and what was left
+behind, and why, is posted on each of them.
+";
+}
+
+/// `merge group`
+#[derive(Debug, PartialEq, Eq)]
+pub enum Group {
+    /// 同じ名前のグループが待っていれば時間を変える
+    Create {
+        name: String,
+        time: chrono::NaiveDateTime,
+    },
+    Join(String),
+}
+
+impl Group {
+    /// 名前に使える文字
+    fn try_parse_name(input: Option<&&str>) -> error::Result<String> {
+        match input {
+            Some(name)
+                if (1..=64).contains(&name.len())
+                    && name
+                        .chars()
+                        .all(|c| c.is_ascii_alphanumeric() || matches!(c, '-' | '_' | '.')) =>
+            {
+                Ok(name.to_string())
+            }
+            _ => Err(error::Error::NotACommand),
+        }
+    }
+
+    fn try_parse(input: &[&str]) -> error::Result<Group> {
+        match input.first().map(|s| s.to_lowercase()).as_deref() {
+            Some("create") => Ok(Group::Create {
+                name: Self::try_parse_name(input.get(1))?,
+                time: time::parse_time(input.get(2).ok_or(error::Error::NotACommand)?)?,
+            }),
+            Some("join") => Ok(Group::Join(Self::try_parse_name(input.get(1))?)),
+            _ => Err(error::Error::NotACommand),
+        }
+    }
+}
+
+/// `#123`か`owner/repo#45`
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct PullRequestRef {
+    /// Noneならコマンドと同じリポジトリ
+    pub repo: Option<(String, String)>,
+    pub number: u64,
+}
+
+impl PullRequestRef {
+    fn try_parse(input: &str) -> error::Result<PullRequestRef> {
+        let (repo, number) = input.split_once('#').ok_or(error::Error::NotACommand)?;
+        let number = number.parse().map_err(|_| error::Error::NotACommand)?;
+
+        let repo = match repo.split_once('/') {
+            _ if repo.is_empty() => None,
+            Some((owner, name)) if !owner.is_empty() && !name.is_empty() && !name.contains('/') => {
+                Some((owner.to_string(), name.to_string()))
+            }
+            _ => return Err(error::Error::NotACommand),
+        };
+
+        Ok(PullRequestRef { repo, number })
+    }
+}
+
+/// コマンドの呼び出し方
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum Trigger<'a> {
+    /// `@satler-bot merge 18:00`
+    Mention(&'a str),
+    /// `/merge 18:00`
+    Prefix(&'a str),
+}
+
+impl<'a> Trigger<'a> {
+    /// `@satler-bot,/`のような設定。`@`で始まるものはメンション、それ以外はprefix
+    pub fn parse_list(config: &'a str) -> Vec<Trigger<'a>> {
+        config
+            .split(',')
+            .map(str::trim)
+            .filter(|trigger| !trigger.is_empty())
+            .map(|trigger| {
+                if trigger.starts_with('@') {
+                    Trigger::Mention(trigger)
+                } else {
+                    Trigger::Prefix(trigger)
+                }
+            })
+            .collect()
+    }
+}
+
 impl Command {
-    pub fn try_parse(input: &str, mention: &str) -> error::Result<Command> {
+    const NAMES: [&str; 6] = ["m", "merge", "history", "train", "h", "help"];
+
+    pub fn try_parse(input: &str, triggers: &[Trigger<'_>]) -> error::Result<Command> {
         let tokens = Self::lexer(input);
 
-        if tokens.first() != Some(&mention) {
+        let Some(first) = tokens.first() else {
             return Err(error::Error::NotAMention);
+        };
+
+        for trigger in triggers {
+            match *trigger {
+                Trigger::Mention(mention) if *first == mention => {
+                    return Self::try_parse_command(tokens.get(1).copied(), &tokens[1..]);
+                }
+                Trigger::Prefix(prefix) => {
+                    let Some(cmd) = first.strip_prefix(prefix) else {
+                        continue;
+                    };
+                    // 他のBotのコマンドかもしれないので知らないものには反応しない
+                    if !Self::NAMES.contains(&cmd.to_lowercase().as_str()) {
+                        continue;
+                    }
+                    return Self::try_parse_command(Some(cmd), &tokens);
+                }
+                Trigger::Mention(_) => {}
+            }
         }
 
-        let cmd = tokens.get(1).map(|s| s.to_lowercase());
+        Err(error::Error::NotAMention)
+    }
+
+    /// `tokens[0]`はコマンド名そのもの
+    fn try_parse_command(cmd: Option<&str>, tokens: &[&str]) -> error::Result<Command> {
+        let cmd = cmd.map(|s| s.to_lowercase());
 
         match cmd {
             Some(s) => match s.as_str() {
-                "m" | "merge" => Ok(Command::Merge(Merge::try_parse_merge(&tokens[2..])?)),
+                "m" | "merge" => Ok(Command::Merge(Merge::try_parse_merge(&tokens[1..])?)),
+                "history" => Ok(Command::History),
+                "train" => Ok(Command::Train(Train::try_parse(&tokens[1..])?)),
                 "h" | "help" => Ok(Command::Help),
                 _ => Err(error::Error::NotACommand),
             },
@@ -43,16 +269,20 @@ impl Command {
 
 impl Help for Command {
     const HELP: &str = "
-You can run commands like `@mention help`.
+You can run commands like `@mention help` or `/help`.
 
 # Commands
 
 All commands are case-insensitive.
 Commands may have shorthand versions; for example, `help` is semantically equivalent to `h`.
 If you run a command without arguments, the help message will be displayed.
+Commands are also accepted in review bodies and review comments on Pull Requests,
+so approving with `@mention merge 18:00` approves and schedules at once.
 
 - `merge` (`m`): View the help for the merge command (`merge help`).
     - This command can only be used on Pull Requests.
+- `history`: Show who scheduled, cancelled or merged this Pull Request and what happened.
+- `train`: Show the release train of the repository. See `train help` to set one up.
 - `help` (`h`): Display this help message.
 ";
 }
@@ -64,19 +294,50 @@ impl Merge {
         match cmd {
             Some(s) => match s.as_str() {
                 "c" | "cancel" => Ok(Merge::Cancel),
+                "now" => Ok(Merge::Now),
+                "group" => Ok(Merge::Group(Group::try_parse(&input[1..])?)),
+                "stack" => Ok(Merge::Stack(
+                    input
+                        .get(1)
+                        .map(|time| time::parse_time(time))
+                        .transpose()?,
+                )),
                 "a" | "add" => {
                     if input.len() == 1 {
                         Err(error::Error::NotACommand)
                     } else {
-                        Ok(Merge::Add(time::parse_time(input[1])?))
+                        Self::try_parse_after(time::parse_time(input[1])?, &input[2..])
                     }
                 }
+                "after" => Ok(Merge::After {
+                    time: None,
+                    after: PullRequestRef::try_parse(
+                        input.get(1).ok_or(error::Error::NotACommand)?,
+                    )?,
+                }),
+                "mode" => match input.get(1).map(|s| s.to_lowercase()).as_deref() {
+                    Some("direct") => Ok(Merge::Mode(Some(MergeMode::Direct))),
+                    Some("auto") => Ok(Merge::Mode(Some(MergeMode::Auto))),
+                    Some(_) => Err(error::Error::NotACommand),
+                    Option::None => Ok(Merge::Mode(None)),
+                },
                 "h" | "help" => Ok(Merge::Help),
-                _ => Ok(Merge::Add(time::parse_time(input[0])?)),
+                _ => Self::try_parse_after(time::parse_time(input[0])?, &input[1..]),
             },
             Option::None => Ok(Merge::Help),
         }
     }
+
+    /// 時間の後に`after #123`が続くかもしれない
+    fn try_parse_after(time: chrono::NaiveDateTime, rest: &[&str]) -> error::Result<Merge> {
+        match rest.first().map(|s| s.to_lowercase()).as_deref() {
+            Some("after") => Ok(Merge::After {
+                time: Some(time),
+                after: PullRequestRef::try_parse(rest.get(1).ok_or(error::Error::NotACommand)?)?,
+            }),
+            _ => Ok(Merge::Add(time)),
+        }
+    }
 }
 
 impl Help for Merge {
@@ -91,7 +352,30 @@ impl Help for Merge {
             - Schedules merging at 16:00 today.
         - `merge add 2024-12-31T16:00`
             - Schedules merging at 16:00 on 2024-12-31.
+- `after`: Merge after another Pull Request has been merged.
+    - `merge after #123`: Merges as soon as #123 in this repository is merged.
+    - `merge after owner/repo#45`: The Pull Request can be in another repository.
+    - `merge 18:00 after #123`: Merges at 18:00, or as soon as #123 is merged if that is later.
+    - If the other Pull Request is closed without being merged, the automatic merge is cancelled.
+- `stack`: Merge a stack of Pull Requests, each based on the branch of the one below.
+    - `merge stack 18:00`: Starting at 18:00, merges the stack from the bottom up to this Pull Request.
+    - `merge stack`: Starts merging right away.
+    - After each Pull Request is merged, the next one is retargeted to the default branch and updated.
+    - If one of them cannot be merged, the ones above it are cancelled.
+- `group`: Merge Pull Requests in several repositories of the same installation together.
+    - `merge group create api-v2 18:00`: Creates the group `api-v2` with this Pull Request, merged at 18:00.
+      Running it again changes the time.
+    - `merge group join api-v2`: Adds this Pull Request to the group.
+    - At the scheduled time, every Pull Request in the group is checked first.
+      They are merged only if all of them are ready, and a summary is posted on each of them.
+- `now`: Merge a scheduled Pull Request without waiting for the scheduled time.
+    - The `Merge now` button on the `Scheduled merge` check does the same.
 - `cancel` (`c`): Cancel a scheduled merge.
+    - If GitHub's auto-merge has already been enabled by the bot, it is disabled.
+- `mode`: Show or change what happens at the scheduled time in this repository.
+    - `merge mode direct`: The bot merges the Pull Request (default).
+    - `merge mode auto`: The bot enables GitHub's auto-merge, and GitHub merges
+      once branch protection requirements are met.
 - `help` (`h`): Display this help message.
 
 Running the command **without sub-commands** acts as an alias for `add`.
@@ -104,23 +388,27 @@ pub trait Help {
 
 #[cfg(test)]
 mod tests {
-    use super::{Command, Merge};
+    use super::{
+        CatchUp, Command, Group, Merge, MergeMode, PullRequestRef, Recurrence, Train, Trigger,
+    };
+
+    const BOT: &[Trigger] = &[Trigger::Mention("@bot")];
 
     #[test]
     fn test_parse_simple_help() -> Result<(), Box<dyn std::error::Error>> {
-        assert_eq!(Command::try_parse("@bot h", "@bot")?, Command::Help);
-        assert_eq!(Command::try_parse("@bot HELP", "@bot")?, Command::Help);
-        assert_eq!(Command::try_parse("@bot help", "@bot")?, Command::Help);
-        assert_eq!(Command::try_parse("@bot H", "@bot")?, Command::Help);
-        assert_eq!(Command::try_parse("@bot h a a", "@bot")?, Command::Help);
-        assert_eq!(Command::try_parse("@bot", "@bot")?, Command::Help);
+        assert_eq!(Command::try_parse("@bot h", BOT)?, Command::Help);
+        assert_eq!(Command::try_parse("@bot HELP", BOT)?, Command::Help);
+        assert_eq!(Command::try_parse("@bot help", BOT)?, Command::Help);
+        assert_eq!(Command::try_parse("@bot H", BOT)?, Command::Help);
+        assert_eq!(Command::try_parse("@bot h a a", BOT)?, Command::Help);
+        assert_eq!(Command::try_parse("@bot", BOT)?, Command::Help);
         Ok(())
     }
 
     #[test]
     fn test_parse_parse_merge() -> Result<(), Box<dyn std::error::Error>> {
         assert_eq!(
-            Command::try_parse("@bot m 2024-11-30T12:00", "@bot")?,
+            Command::try_parse("@bot m 2024-11-30T12:00", BOT)?,
             Command::Merge(Merge::Add(
                 chrono::NaiveDate::from_ymd_opt(2024, 11, 30)
                     .unwrap()
@@ -129,7 +417,7 @@ mod tests {
             ))
         );
         assert_eq!(
-            Command::try_parse("@bot m add 2024-11-30T12:00", "@bot")?,
+            Command::try_parse("@bot m add 2024-11-30T12:00", BOT)?,
             Command::Merge(Merge::Add(
                 chrono::NaiveDate::from_ymd_opt(2024, 11, 30)
                     .unwrap()
@@ -138,14 +426,248 @@ mod tests {
             ))
         );
         assert_eq!(
-            Command::try_parse("@bot m h", "@bot")?,
+            Command::try_parse("@bot m h", BOT)?,
             Command::Merge(Merge::Help)
         );
-        Command::try_parse("@bot M 12:00", "@bot")?;
+        Command::try_parse("@bot M 12:00", BOT)?;
         assert_eq!(
-            Command::try_parse("@bot m c", "@bot")?,
+            Command::try_parse("@bot m c", BOT)?,
             Command::Merge(Merge::Cancel)
         );
+        assert_eq!(
+            Command::try_parse("@bot merge after #123", BOT)?,
+            Command::Merge(Merge::After {
+                time: None,
+                after: PullRequestRef {
+                    repo: None,
+                    number: 123
+                }
+            })
+        );
+        assert_eq!(
+            Command::try_parse("@bot m 2024-11-30T12:00 AFTER owner/repo#45", BOT)?,
+            Command::Merge(Merge::After {
+                time: Some(
+                    chrono::NaiveDate::from_ymd_opt(2024, 11, 30)
+                        .unwrap()
+                        .and_hms_opt(12, 0, 0)
+                        .unwrap()
+                ),
+                after: PullRequestRef {
+                    repo: Some(("owner".into(), "repo".into())),
+                    number: 45
+                }
+            })
+        );
+        assert!(matches!(
+            Command::try_parse("@bot merge add 12:00 after #1", BOT)?,
+            Command::Merge(Merge::After { time: Some(_), .. })
+        ));
+        for invalid in [
+            "@bot merge after",
+            "@bot merge after 123",
+            "@bot merge after #abc",
+            "@bot merge after owner#1",
+            "@bot merge after /repo#1",
+            "@bot merge after a/b/c#1",
+            "@bot merge 12:00 after",
+        ] {
+            assert_eq!(
+                Command::try_parse(invalid, BOT),
+                Err(super::error::Error::NotACommand),
+                "{invalid}"
+            );
+        }
+        assert_eq!(
+            Command::try_parse("@bot merge NOW", BOT)?,
+            Command::Merge(Merge::Now)
+        );
+        assert!(matches!(
+            Command::try_parse("@bot merge group create api-v2 18:00", BOT)?,
+            Command::Merge(Merge::Group(Group::Create { name, .. })) if name == "api-v2"
+        ));
+        assert_eq!(
+            Command::try_parse("@bot merge group JOIN api_v2.1", BOT)?,
+            Command::Merge(Merge::Group(Group::Join("api_v2.1".into())))
+        );
+        for invalid in [
+            "@bot merge group",
+            "@bot merge group create api-v2",
+            "@bot merge group join",
+            "@bot merge group join api/v2",
+            "@bot merge group leave api-v2",
+        ] {
+            assert_eq!(
+                Command::try_parse(invalid, BOT),
+                Err(super::error::Error::NotACommand),
+                "{invalid}"
+            );
+        }
+        assert_eq!(
+            Command::try_parse("@bot merge stack", BOT)?,
+            Command::Merge(Merge::Stack(None))
+        );
+        assert!(matches!(
+            Command::try_parse("@bot merge stack 18:00", BOT)?,
+            Command::Merge(Merge::Stack(Some(_)))
+        ));
+        assert_eq!(
+            Command::try_parse("@bot merge stack tomorrow", BOT),
+            Err(super::error::Error::TimeFormat("tomorrow".into()))
+        );
+        assert_eq!(
+            Command::try_parse("@bot merge mode", BOT)?,
+            Command::Merge(Merge::Mode(None))
+        );
+        assert_eq!(
+            Command::try_parse("@bot merge mode AUTO", BOT)?,
+            Command::Merge(Merge::Mode(Some(MergeMode::Auto)))
+        );
+        assert_eq!(
+            Command::try_parse("@bot merge mode direct", BOT)?,
+            Command::Merge(Merge::Mode(Some(MergeMode::Direct)))
+        );
+        assert_eq!(
+            Command::try_parse("@bot merge mode squash", BOT),
+            Err(super::error::Error::NotACommand)
+        );
+        Ok(())
+    }
+
+    #[test]
+    fn test_parse_train() -> Result<(), Box<dyn std::error::Error>> {
+        assert_eq!(
+            Command::try_parse("@bot train", BOT)?,
+            Command::Train(Train::Show)
+        );
+        assert_eq!(
+            Command::try_parse("@bot train OFF", BOT)?,
+            Command::Train(Train::Off)
+        );
+        assert_eq!(
+            Command::try_parse("@bot train every weekday 17:00", BOT)?,
+            Command::Train(Train::Set {
+                departure: "every weekday 17:00".parse()?,
+                label: None,
+                catch_up: None,
+            })
+        );
+        assert_eq!(
+            Command::try_parse("@bot train weekdays 17:00", BOT)?,
+            Command::Train(Train::Set {
+                departure: "weekdays 17:00".parse()?,
+                label: None,
+                catch_up: None,
+            })
+        );
+        assert_eq!(
+            Command::try_parse("@bot train mon,fri 09:30 UTC label Ship-It", BOT)?,
+            Command::Train(Train::Set {
+                departure: "mon,fri 09:30 UTC".parse()?,
+                label: Some("Ship-It".into()),
+                catch_up: None,
+            })
+        );
+        assert_eq!(
+            Command::try_parse(
+                "/train cron 0 17 * * 1-5 catch-up once label release",
+                &[Trigger::Prefix("/")]
+            )?,
+            Command::Train(Train::Set {
+                departure: Recurrence::try_parse(&["cron", "0", "17", "*", "*", "1-5"])?,
+                label: Some("release".into()),
+                catch_up: Some(CatchUp::Once),
+            })
+        );
+        for invalid in [
+            "@bot train weekdays",
+            "@bot train someday 17:00",
+            "@bot train daily 17:00 label",
+            "@bot train daily 17:00 tag train",
+            "@bot train daily 17:00 catch-up always",
+            "@bot train daily 17:00 label a label b",
+            "@bot train label train",
+        ] {
+            assert_eq!(
+                Command::try_parse(invalid, BOT),
+                Err(super::error::Error::NotACommand),
+                "{invalid}"
+            );
+        }
+        assert!(Command::try_parse("@bot train every day 17:00 Europe/London", BOT).is_err());
+        Ok(())
+    }
+
+    #[test]
+    fn test_parse_triggers() -> Result<(), Box<dyn std::error::Error>> {
+        let triggers = &[Trigger::Mention("@bot"), Trigger::Prefix("/")];
+
+        assert_eq!(Command::try_parse("/help", triggers)?, Command::Help);
+        assert_eq!(Command::try_parse("@bot h", triggers)?, Command::Help);
+        assert_eq!(Command::try_parse("/history", triggers)?, Command::History);
+        assert_eq!(
+            Command::try_parse("@bot HISTORY", triggers)?,
+            Command::History
+        );
+        assert_eq!(
+            Command::try_parse("/merge c", triggers)?,
+            Command::Merge(Merge::Cancel)
+        );
+        assert_eq!(
+            Command::try_parse("/M add 2024-11-30T12:00", triggers)?,
+            Command::Merge(Merge::Add(
+                chrono::NaiveDate::from_ymd_opt(2024, 11, 30)
+                    .unwrap()
+                    .and_hms_opt(12, 0, 0)
+                    .unwrap()
+            ))
+        );
+        assert_eq!(
+            Command::try_parse("/merge add", triggers),
+            Err(super::error::Error::NotACommand)
+        );
+        // 他のBotのコマンド
+        assert_eq!(
+            Command::try_parse("/assign @someone", triggers),
+            Err(super::error::Error::NotAMention)
+        );
+        assert_eq!(
+            Command::try_parse("/", triggers),
+            Err(super::error::Error::NotAMention)
+        );
+        assert_eq!(
+            Command::try_parse("@other h", triggers),
+            Err(super::error::Error::NotAMention)
+        );
+        assert_eq!(
+            Command::try_parse("", triggers),
+            Err(super::error::Error::NotAMention)
+        );
+        Ok(())
+    }
+
+    #[test]
+    fn test_configured_triggers() -> Result<(), Box<dyn std::error::Error>> {
+        let triggers = Trigger::parse_list(" @merge-bot, !,, ");
+        assert_eq!(
+            triggers,
+            [Trigger::Mention("@merge-bot"), Trigger::Prefix("!")]
+        );
+
+        assert_eq!(Command::try_parse("!help", &triggers)?, Command::Help);
+        assert_eq!(
+            Command::try_parse("@merge-bot merge c", &triggers)?,
+            Command::Merge(Merge::Cancel)
+        );
+        // 設定すると既定のものは使わない
+        assert_eq!(
+            Command::try_parse("/help", &triggers),
+            Err(super::error::Error::NotAMention)
+        );
+        assert_eq!(
+            Command::try_parse("@bot help", &triggers),
+            Err(super::error::Error::NotAMention)
+        );
         Ok(())
     }
 }
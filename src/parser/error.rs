@@ -4,6 +4,8 @@ pub(crate) type Result<T> = std::result::Result<T, Error>;
 pub enum Error {
     #[error("The time format is wrong: {0}")]
     TimeFormat(String),
+    #[error("The recurrence format is wrong or not supported: {0}")]
+    Recurrence(String),
     #[error("The following input is not a command(Syntax Error)")]
     NotACommand,
     #[error("The comment is not mentioning to the bot")]
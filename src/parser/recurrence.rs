@@ -0,0 +1,470 @@
+//! 繰り返しの予定 (`every weekday 17:00`、`cron 0 17 * * 1-5`、`RRULE:FREQ=WEEKLY;BYDAY=MO;BYHOUR=9`)
+//! どれもcronと同じ形 (分、時、日、月、曜日) にして次の時刻を計算する
+//! タイムゾーンは夏時間のない固定のオフセットだけ。書かなければAsia/Tokyo
+//! D1には`Display`した形で保存する
+
+use std::fmt;
+use std::str::FromStr;
+
+use chrono::{Datelike, Days, FixedOffset, NaiveDate, NaiveDateTime};
+
+use super::error;
+
+/// 月曜日から
+const DAY_NAMES: [&str; 7] = ["mon", "tue", "wed", "thu", "fri", "sat", "sun"];
+const FULL_DAY_NAMES: [&str; 7] = [
+    "monday",
+    "tuesday",
+    "wednesday",
+    "thursday",
+    "friday",
+    "saturday",
+    "sunday",
+];
+const RRULE_DAY_NAMES: [&str; 7] = ["mo", "tu", "we", "th", "fr", "sa", "su"];
+const WEEKDAYS: u8 = 0b0011111;
+const WEEKEND: u8 = 0b1100000;
+const DAILY: u8 = 0b1111111;
+
+/// 夏時間のないタイムゾーン
+const TIMEZONES: [(&str, i32); 11] = [
+    ("asia/tokyo", 9 * 3600),
+    ("jst", 9 * 3600),
+    ("asia/seoul", 9 * 3600),
+    ("kst", 9 * 3600),
+    ("asia/shanghai", 8 * 3600),
+    ("asia/singapore", 8 * 3600),
+    ("asia/kolkata", 5 * 3600 + 1800),
+    ("utc", 0),
+    ("etc/utc", 0),
+    ("gmt", 0),
+    ("z", 0),
+];
+const DEFAULT_OFFSET: i32 = 9 * 3600;
+
+/// 次の時刻を探す日数。2月29日だけのものも見つかるように
+const SEARCH_DAYS: u64 = 366 * 8;
+/// グレゴリオ暦は400年で曜日まで一周する
+const CYCLE_DAYS: u64 = 146097;
+
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct Recurrence {
+    /// 入力を空白1つで区切り直したもの
+    source: String,
+    minutes: u64,
+    hours: u32,
+    /// 1日が1ビット目
+    days: u32,
+    /// 1月が1ビット目
+    months: u16,
+    /// 月曜日が0ビット目
+    weekdays: u8,
+    /// 日と曜日のどちらかに合えばいい。cronで両方を指定したとき
+    either_day: bool,
+    /// UTCからの秒
+    offset: i32,
+}
+
+impl Recurrence {
+    /// 毎分
+    fn any() -> Recurrence {
+        Recurrence {
+            source: String::new(),
+            minutes: (1 << 60) - 1,
+            hours: (1 << 24) - 1,
+            days: 0xFFFF_FFFE,
+            months: 0x1FFE,
+            weekdays: DAILY,
+            either_day: false,
+            offset: DEFAULT_OFFSET,
+        }
+    }
+
+    /// `every weekday 17:00 [tz]`、`cron 0 17 * * 1-5 [tz]`、`RRULE:... [tz]`
+    /// `every`は省略できる (`weekdays 17:00`)
+    pub fn try_parse(input: &[&str]) -> error::Result<Recurrence> {
+        let (first, rest) = input.split_first().ok_or(error::Error::NotACommand)?;
+        let lower = first.to_lowercase();
+
+        let (mut recurrence, rest) = if lower == "cron" {
+            if rest.len() < 5 {
+                return Err(error::Error::NotACommand);
+            }
+            (Self::try_parse_cron(&rest[..5])?, &rest[5..])
+        } else if lower.starts_with("rrule:") || lower.starts_with("freq=") {
+            (Self::try_parse_rrule(&lower)?, rest)
+        } else {
+            let (days, rest) = match lower.as_str() {
+                "every" => rest.split_first().ok_or(error::Error::NotACommand)?,
+                _ => (first, rest),
+            };
+            let (time, rest) = rest.split_first().ok_or(error::Error::NotACommand)?;
+            (Self::try_parse_every(days, time)?, rest)
+        };
+
+        recurrence.offset = match rest {
+            [] => DEFAULT_OFFSET,
+            [timezone] => try_parse_offset(timezone)?,
+            _ => return Err(error::Error::NotACommand),
+        };
+        recurrence.source = input.join(" ");
+
+        // `cron 0 0 30 2 *`や、2月29日の木曜日だけ (28年ごと) など`next_after`で見つからないもの
+        if !recurrence.recurs_within_search_days() {
+            return Err(error::Error::Recurrence(recurrence.source));
+        }
+        Ok(recurrence)
+    }
+
+    /// どの日から探しても`SEARCH_DAYS`の間に次の日があるか
+    /// 合う日は1月1日の曜日とうるう年かで決まるので、その14種類の年だけ調べて400年分を辿る
+    fn recurs_within_search_days(&self) -> bool {
+        // 年の中の最初と最後に合う日。年の中の間隔は`SEARCH_DAYS`より短い
+        let mut kinds: [Option<Option<(u64, u64)>>; 14] = [None; 14];
+        let mut start = 0;
+        let mut first = None;
+        let mut last = None;
+        for year in 2000..2400 {
+            let new_year = NaiveDate::from_ymd_opt(year, 1, 1).unwrap();
+            let leap = NaiveDate::from_ymd_opt(year, 2, 29).is_some();
+            let kind = new_year.weekday().num_days_from_monday() as usize * 2 + leap as usize;
+            let matched = *kinds[kind].get_or_insert_with(|| {
+                let mut days = new_year
+                    .iter_days()
+                    .take_while(|date| date.year() == year)
+                    .enumerate()
+                    .filter(|(_, date)| self.matches(*date))
+                    .map(|(index, _)| index as u64);
+                let first = days.next()?;
+                Some((first, days.last().unwrap_or(first)))
+            });
+
+            if let Some((year_first, year_last)) = matched {
+                if last.is_some_and(|last| start + year_first - last >= SEARCH_DAYS) {
+                    return false;
+                }
+                first.get_or_insert(start + year_first);
+                last = Some(start + year_last);
+            }
+            start += if leap { 366 } else { 365 };
+        }
+
+        // 周期の最後から次の周期の最初までも
+        match (first, last) {
+            (Some(first), Some(last)) => first + CYCLE_DAYS - last < SEARCH_DAYS,
+            _ => false,
+        }
+    }
+
+    /// `daily`、`weekdays`、`weekend`、`mon,wed,fri`のどれかと`17:00`
+    fn try_parse_every(days: &str, time: &str) -> error::Result<Recurrence> {
+        let weekdays = match days.to_lowercase().as_str() {
+            "day" | "daily" => DAILY,
+            "weekday" | "weekdays" => WEEKDAYS,
+            "weekend" | "weekends" => WEEKEND,
+            list => {
+                let mut weekdays = 0;
+                for day in list.split(',') {
+                    let index = (0..7)
+                        .find(|&index| day == DAY_NAMES[index] || day == FULL_DAY_NAMES[index])
+                        .ok_or(error::Error::NotACommand)?;
+                    weekdays |= 1 << index;
+                }
+                weekdays
+            }
+        };
+        let (hour, minute) = time
+            .split_once(':')
+            .and_then(|(hour, minute)| Some((hour.parse::<u32>().ok()?, minute)))
+            .filter(|(hour, minute)| *hour < 24 && minute.len() == 2)
+            .and_then(|(hour, minute)| Some((hour, minute.parse::<u32>().ok()?)))
+            .filter(|(_, minute)| *minute < 60)
+            .ok_or_else(|| error::Error::TimeFormat(time.into()))?;
+
+        Ok(Recurrence {
+            minutes: 1 << minute,
+            hours: 1 << hour,
+            weekdays,
+            ..Recurrence::any()
+        })
+    }
+
+    /// 分 時 日 月 曜日。曜日は0と7が日曜日
+    fn try_parse_cron(fields: &[&str]) -> error::Result<Recurrence> {
+        let weekdays = try_parse_field(fields[4], 0, 7)?;
+        // 月曜日を0ビット目、日曜日を6ビット目に
+        let weekdays = ((weekdays >> 1 | (weekdays & 1) << 6) & DAILY as u64) as u8;
+
+        Ok(Recurrence {
+            minutes: try_parse_field(fields[0], 0, 59)?,
+            hours: try_parse_field(fields[1], 0, 23)? as u32,
+            days: try_parse_field(fields[2], 1, 31)? as u32,
+            months: try_parse_field(fields[3], 1, 12)? as u16,
+            weekdays,
+            either_day: !fields[2].starts_with('*') && !fields[4].starts_with('*'),
+            ..Recurrence::any()
+        })
+    }
+
+    /// FREQはDAILY、WEEKLY、MONTHLYだけ。BYHOURは必須
+    fn try_parse_rrule(rule: &str) -> error::Result<Recurrence> {
+        let invalid = || error::Error::Recurrence(rule.into());
+        let mut recurrence = Recurrence {
+            minutes: 1,
+            ..Recurrence::any()
+        };
+        let mut freq = None;
+        let mut has_hour = false;
+
+        for part in rule.trim_start_matches("rrule:").split(';') {
+            let (key, value) = part.split_once('=').ok_or_else(invalid)?;
+            match key {
+                "freq" => freq = Some(value),
+                "interval" if value == "1" => {}
+                "byminute" => recurrence.minutes = try_parse_field(value, 0, 59)?,
+                "byhour" => {
+                    recurrence.hours = try_parse_field(value, 0, 23)? as u32;
+                    has_hour = true;
+                }
+                "bymonthday" => recurrence.days = try_parse_field(value, 1, 31)? as u32,
+                "bymonth" => recurrence.months = try_parse_field(value, 1, 12)? as u16,
+                "byday" => {
+                    recurrence.weekdays = 0;
+                    for day in value.split(',') {
+                        let index = RRULE_DAY_NAMES
+                            .iter()
+                            .position(|name| *name == day)
+                            .ok_or_else(invalid)?;
+                        recurrence.weekdays |= 1 << index;
+                    }
+                }
+                _ => return Err(invalid()),
+            }
+        }
+
+        let has_day = recurrence.weekdays != DAILY || recurrence.days != Recurrence::any().days;
+        match freq {
+            Some("daily") if has_hour => Ok(recurrence),
+            Some("weekly" | "monthly") if has_hour && has_day => Ok(recurrence),
+            _ => Err(invalid()),
+        }
+    }
+
+    fn matches(&self, date: NaiveDate) -> bool {
+        let day = self.days & (1 << date.day()) != 0;
+        let weekday = self.weekdays & (1 << date.weekday().num_days_from_monday()) != 0;
+
+        self.months & (1 << date.month()) != 0
+            && if self.either_day {
+                day || weekday
+            } else {
+                day && weekday
+            }
+    }
+
+    /// `now`より後の最初の時刻。どちらもUTC
+    pub fn next_after(&self, now: NaiveDateTime) -> NaiveDateTime {
+        let offset = chrono::Duration::seconds(self.offset.into());
+        let local = now + offset;
+
+        (0..SEARCH_DAYS)
+            .filter_map(|days| local.date().checked_add_days(Days::new(days)))
+            .filter(|date| self.matches(*date))
+            .find_map(|date| {
+                (0..24)
+                    .filter(|hour| self.hours & (1 << hour) != 0)
+                    .flat_map(|hour| {
+                        (0..60)
+                            .filter(|minute| self.minutes & (1 << minute) != 0)
+                            .map(move |minute| (hour, minute))
+                    })
+                    .filter_map(|(hour, minute)| date.and_hms_opt(hour, minute, 0))
+                    .find(|time| *time > local)
+            })
+            .map(|time| time - offset)
+            .expect("a matching day within SEARCH_DAYS is checked when parsed")
+    }
+}
+
+/// `1,15`、`1-5`、`*/15`、`9-17/2`などを`min`からのビットにする
+fn try_parse_field(field: &str, min: u32, max: u32) -> error::Result<u64> {
+    let invalid = || error::Error::Recurrence(field.into());
+    let parse = |value: &str| {
+        value
+            .parse::<u32>()
+            .ok()
+            .filter(|value| (min..=max).contains(value))
+            .ok_or_else(invalid)
+    };
+
+    let mut bits = 0;
+    for item in field.split(',') {
+        let (range, step) = match item.split_once('/') {
+            Some((range, step)) => (
+                range,
+                step.parse::<usize>()
+                    .ok()
+                    .filter(|step| *step > 0)
+                    .ok_or_else(invalid)?,
+            ),
+            None => (item, 1),
+        };
+        let (start, end) = match range.split_once('-') {
+            _ if range == "*" => (min, max),
+            Some((start, end)) => (parse(start)?, parse(end)?),
+            // `5/15`は5から最後まで
+            None if step > 1 => (parse(range)?, max),
+            None => (parse(range)?, parse(range)?),
+        };
+        if start > end {
+            return Err(invalid());
+        }
+        for value in (start..=end).step_by(step) {
+            bits |= 1 << value;
+        }
+    }
+    Ok(bits)
+}
+
+/// `Asia/Tokyo`などの名前か`+09:00`
+fn try_parse_offset(timezone: &str) -> error::Result<i32> {
+    let lower = timezone.to_lowercase();
+    if let Some((_, offset)) = TIMEZONES.iter().find(|(name, _)| *name == lower) {
+        return Ok(*offset);
+    }
+    timezone
+        .parse::<FixedOffset>()
+        .map(|offset| offset.local_minus_utc())
+        .map_err(|_| error::Error::Recurrence(timezone.into()))
+}
+
+impl fmt::Display for Recurrence {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        write!(f, "{}", self.source)
+    }
+}
+
+impl FromStr for Recurrence {
+    type Err = error::Error;
+
+    fn from_str(s: &str) -> error::Result<Recurrence> {
+        Recurrence::try_parse(&s.split_whitespace().collect::<Vec<_>>())
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use chrono::NaiveDateTime;
+
+    use super::Recurrence;
+
+    fn datetime(s: &str) -> NaiveDateTime {
+        NaiveDateTime::parse_from_str(s, "%Y-%m-%d %H:%M").unwrap()
+    }
+
+    /// `now`と次の時刻はUTC
+    fn next(rule: &str, now: &str) -> NaiveDateTime {
+        rule.parse::<Recurrence>()
+            .unwrap()
+            .next_after(datetime(now))
+    }
+
+    #[test]
+    fn test_parse() {
+        for rule in [
+            "every weekday 17:00",
+            "every Monday 09:00 Asia/Tokyo",
+            "every sat,sun 9:05 UTC",
+            "weekdays 17:00",
+            "mon,wed,fri 17:00 +05:30",
+            "cron 0 17 * * 1-5",
+            "cron */15 9-17 1,15 * * -08:00",
+            "RRULE:FREQ=WEEKLY;BYDAY=MO,TH;BYHOUR=9;BYMINUTE=30",
+            "FREQ=MONTHLY;BYMONTHDAY=1;BYHOUR=0 utc",
+            // 2100年はうるう年ではないので8年空くこともある
+            "cron 0 0 29 2 *",
+        ] {
+            let recurrence = rule.parse::<Recurrence>().unwrap();
+            assert_eq!(recurrence.to_string(), rule);
+        }
+
+        for rule in [
+            "",
+            "every",
+            "every weekday",
+            "every someday 17:00",
+            "every day 5pm",
+            "every day 24:00",
+            "every day 17:00 America/New_York",
+            "every day 17:00 JST extra",
+            "cron 0 17 * *",
+            "cron 60 17 * * *",
+            "cron 0 17 5-1 * *",
+            "cron 0 0 30 2 *",
+            // 2024-02-29は木曜日だけど、次は2052年
+            "RRULE:FREQ=MONTHLY;BYMONTH=2;BYMONTHDAY=29;BYDAY=TH;BYHOUR=9",
+            "RRULE:FREQ=WEEKLY;BYHOUR=9",
+            "RRULE:FREQ=DAILY",
+            "RRULE:FREQ=DAILY;INTERVAL=2;BYHOUR=9",
+            "RRULE:FREQ=YEARLY;BYHOUR=9",
+        ] {
+            assert!(rule.parse::<Recurrence>().is_err(), "{rule}");
+        }
+    }
+
+    #[test]
+    fn test_next_after() {
+        // 2024-12-27は金曜日。17:00 JSTは08:00 UTC
+        assert_eq!(
+            next("every weekday 17:00", "2024-12-27 07:59"),
+            datetime("2024-12-27 08:00")
+        );
+        assert_eq!(
+            next("every weekday 17:00", "2024-12-27 08:00"),
+            datetime("2024-12-30 08:00")
+        );
+        assert_eq!(
+            next("every monday 09:00 UTC", "2024-12-27 00:00"),
+            datetime("2024-12-30 09:00")
+        );
+        assert_eq!(
+            next("every day 01:00 -05:00", "2024-12-27 05:00"),
+            datetime("2024-12-27 06:00")
+        );
+
+        assert_eq!(
+            next("cron 0 17 * * 1-5", "2024-12-27 08:00"),
+            datetime("2024-12-30 08:00")
+        );
+        assert_eq!(
+            next("cron */20 9 * * * utc", "2024-12-27 09:20"),
+            datetime("2024-12-27 09:40")
+        );
+        // 日曜日は0でも7でもいい
+        assert_eq!(
+            next("cron 0 9 * * 7 utc", "2024-12-27 00:00"),
+            datetime("2024-12-29 09:00")
+        );
+        // 日と曜日を両方指定するとどちらか
+        assert_eq!(
+            next("cron 0 9 1 * 0 utc", "2024-12-29 10:00"),
+            datetime("2025-01-01 09:00")
+        );
+        assert_eq!(
+            next("cron 0 0 29 2 * utc", "2025-01-01 00:00"),
+            datetime("2028-02-29 00:00")
+        );
+
+        assert_eq!(
+            next(
+                "RRULE:FREQ=WEEKLY;BYDAY=MO,TH;BYHOUR=9;BYMINUTE=30 utc",
+                "2024-12-27 00:00"
+            ),
+            datetime("2024-12-30 09:30")
+        );
+        assert_eq!(
+            next("FREQ=MONTHLY;BYMONTHDAY=1;BYHOUR=9", "2024-12-27 00:00"),
+            datetime("2025-01-01 00:00")
+        );
+    }
+}
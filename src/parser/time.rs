@@ -1,5 +1,18 @@
 use super::error::Result;
-use chrono::{NaiveDateTime, NaiveTime, Utc};
+use chrono::{FixedOffset, NaiveDateTime, NaiveTime, Utc};
+
+/// 入力と表示の時間はAsia/Tokyo
+pub(crate) fn jst() -> FixedOffset {
+    FixedOffset::east_opt(9 * 3600).unwrap()
+}
+
+/// UTCの時刻を`2024-12-31 18:00 JST`の形で表示する
+pub(crate) fn format_jst(utc: NaiveDateTime) -> String {
+    utc.and_utc()
+        .with_timezone(&jst())
+        .format("%Y-%m-%d %H:%M JST")
+        .to_string()
+}
 
 pub(crate) fn parse_time(value: &str) -> Result<NaiveDateTime> {
     if let Ok(datetime) = NaiveDateTime::parse_from_str(value, "%Y-%m-%dT%H:%M") {
@@ -23,4 +36,11 @@ mod tests {
         let _ = super::parse_time("2000-1-1T01:01").unwrap();
         Ok(())
     }
+
+    #[test]
+    fn test_format_jst() {
+        let utc =
+            chrono::NaiveDateTime::parse_from_str("2024-12-31 09:00", "%Y-%m-%d %H:%M").unwrap();
+        assert_eq!(super::format_jst(utc), "2024-12-31 18:00 JST");
+    }
 }
@@ -0,0 +1,89 @@
+//! 繰り返しのジョブ (`recurring_job`) をcronで実行する
+//! 先に次の時刻に進めてから実行するので、同じ回を2回実行することはない
+//! 大きく遅れた回は`catch_up`の設定で飛ばすか1回だけ実行する
+
+use chrono::{NaiveDateTime, Utc};
+use worker::*;
+
+use crate::app::AppConfig;
+use crate::db::{
+    self,
+    audit::{NewAuditEntry, Outcome},
+    recurring::{CatchUp, Kind, RecurringJob},
+};
+use crate::parser::Recurrence;
+
+/// cronで一度に実行する数
+const RUN_LIMIT: u32 = 5;
+
+/// これより遅れた回は遅れたものとして`catch_up`に従う。cronの間隔よりは長く
+const MAX_DELAY: std::time::Duration = std::time::Duration::from_secs(60 * 60);
+
+/// 今より後の次の時刻。UTC
+pub fn next_run(rule: &Recurrence) -> NaiveDateTime {
+    rule.next_after(Utc::now().naive_utc())
+}
+
+pub async fn run_due(d1: &D1Database, env: &Env, apps: &[AppConfig]) -> Result<()> {
+    for job in db::recurring::due(d1, RUN_LIMIT).await? {
+        // 1つ失敗しても残りは続ける
+        if let Err(e) = run(d1, env, apps, &job).await {
+            console_error!("Failed to run the recurring job {}: {e}", job.id);
+        }
+    }
+    Ok(())
+}
+
+async fn run(d1: &D1Database, env: &Env, apps: &[AppConfig], job: &RecurringJob) -> Result<()> {
+    let rule = match job.rule.parse::<Recurrence>() {
+        Ok(rule) => rule,
+        Err(e) => {
+            // 二度と実行できないので、毎回先頭に残って他を止めないように消す
+            // 設定した人が気付けるように監査ログに残しておく
+            let error = format!("Stopped because the rule `{}` is invalid: {e}", job.rule);
+            db::audit::record(
+                d1,
+                &NewAuditEntry {
+                    actor: db::audit::SCHEDULER,
+                    installation_id: Some(job.installation_id),
+                    repo: Some(job.repo()),
+                    pr_number: None,
+                    action: match job.kind {
+                        Kind::Train => "train.off",
+                    },
+                    command: None,
+                    result: Outcome::Failed,
+                    error: Some(&error),
+                },
+            )
+            .await;
+            db::recurring::delete(d1, job.kind, job.repository_id).await?;
+            return Err(Error::RustError(error));
+        }
+    };
+    // 先に次の時刻に進めて、他のcronと2回実行しないようにする
+    if !db::recurring::advance(d1, job, next_run(&rule)).await? {
+        return Ok(());
+    }
+
+    let scheduled = NaiveDateTime::parse_from_str(&job.next_run_at, "%Y-%m-%d %H:%M:%S")
+        .map_err(|e| Error::RustError(format!("Invalid next_run_at: {e}")))?;
+    let delay = (Utc::now().naive_utc() - scheduled).to_std();
+    if job.catch_up == CatchUp::Skip && delay.is_ok_and(|delay| delay > MAX_DELAY) {
+        console_warn!(
+            "Skipped the run of the recurring job {} at {scheduled} because it is too late",
+            job.id
+        );
+        return Ok(());
+    }
+
+    let Some(app) = crate::schedule::app_by_id(apps, job.app_id).await? else {
+        return Err(Error::RustError(format!(
+            "The app {:?} is not configured",
+            job.app_id
+        )));
+    };
+    match job.kind {
+        Kind::Train => crate::train::depart(d1, env, app, job).await,
+    }
+}
@@ -0,0 +1,148 @@
+//! リポジトリごとのDurable Object
+//! 次にマージする時間にアラームをセットして、時間ちょうどにマージする
+//! ジョブ自体はD1にあって、ここでは対象のリポジトリだけ覚えておく
+//! cronはアラームが漏れたときのための保険で、定期的に`sync`し直す
+
+use worker::*;
+
+use crate::app::AppConfig;
+use crate::db;
+use crate::github::Repo;
+
+const BINDING: &str = "REPO_SCHEDULER";
+const REPO_KEY: &str = "repo";
+/// 失敗して残っているジョブで何度もアラームが鳴らないようにする
+const RETRY_DELAY_MILLIS: i64 = 60 * 1000;
+
+/// Durable Objectに保存するリポジトリ
+#[derive(Debug, serde::Deserialize, serde::Serialize)]
+struct RepoKey {
+    owner: String,
+    name: String,
+    id: Option<u64>,
+}
+
+impl RepoKey {
+    fn repo(&self) -> Repo<'_> {
+        Repo {
+            owner: &self.owner,
+            name: &self.name,
+            id: self.id,
+        }
+    }
+
+    /// リースと同じで、idが分かっていればrenameされても同じものになる
+    fn object_name(&self) -> String {
+        db::lease::key(&self.repo())
+    }
+}
+
+impl From<&Repo<'_>> for RepoKey {
+    fn from(repo: &Repo<'_>) -> Self {
+        RepoKey {
+            owner: repo.owner.into(),
+            name: repo.name.into(),
+            id: repo.id,
+        }
+    }
+}
+
+/// リポジトリのジョブが変わったらアラームをセットし直す
+pub async fn sync(env: &Env, repo: &Repo<'_>) -> Result<()> {
+    let key = RepoKey::from(repo);
+    let stub = env
+        .durable_object(BINDING)?
+        .id_from_name(&key.object_name())?
+        .get_stub()?;
+
+    let mut init = RequestInit::new();
+    init.with_method(Method::Post).with_body(Some(
+        serde_json::to_string(&key)
+            .map_err(Error::SerdeJsonError)?
+            .into(),
+    ));
+
+    stub.fetch_with_request(Request::new_with_init(
+        "https://repo-scheduler/sync",
+        &init,
+    )?)
+    .await?;
+    Ok(())
+}
+
+/// `sync`して失敗してもcronで直るのでログだけ残す
+pub async fn try_sync(env: &Env, repo: &Repo<'_>) {
+    if let Err(e) = sync(env, repo).await {
+        console_error!(
+            "Failed to sync the scheduler of {}/{}: {e}",
+            repo.owner,
+            repo.name
+        );
+    }
+}
+
+#[durable_object]
+pub struct RepoScheduler {
+    state: State,
+    env: Env,
+}
+
+#[durable_object]
+impl DurableObject for RepoScheduler {
+    fn new(state: State, env: Env) -> Self {
+        Self { state, env }
+    }
+
+    async fn fetch(&self, mut req: Request) -> Result<Response> {
+        let key: RepoKey = req.json().await?;
+        self.state.storage().put(REPO_KEY, &key).await?;
+
+        self.set_next_alarm(&key).await?;
+        Response::empty()
+    }
+
+    async fn alarm(&self) -> Result<Response> {
+        let Ok(key) = self.state.storage().get::<RepoKey>(REPO_KEY).await else {
+            return Response::empty();
+        };
+        let repo = key.repo();
+        let d1 = self.env.d1("DB")?;
+
+        let apps = AppConfig::load_all(&self.env)?;
+        for job in db::merge::due_in_repository(&d1, &repo, 10).await? {
+            let Some(app) = crate::schedule::app_for_job(&apps, &job).await? else {
+                crate::schedule::fail_unconfigured(&d1, &job).await?;
+                continue;
+            };
+
+            // 1つ失敗しても残りは続ける
+            if let Err(e) = crate::schedule::merge(&d1, app, &job, db::audit::SCHEDULER).await {
+                console_error!("Failed to merge the job {}: {e}", job.id);
+            }
+        }
+
+        self.set_next_alarm(&key).await?;
+        Response::empty()
+    }
+}
+
+impl RepoScheduler {
+    async fn set_next_alarm(&self, key: &RepoKey) -> Result<()> {
+        let d1 = self.env.d1("DB")?;
+        let storage = self.state.storage();
+
+        match db::merge::next_in_repository(&d1, &key.repo()).await? {
+            Some(next) => {
+                let now = chrono::Utc::now().timestamp_millis();
+                let mut millis = next.and_utc().timestamp_millis();
+                if millis <= now {
+                    millis = now + RETRY_DELAY_MILLIS;
+                }
+                let date = js_sys::Date::new(&(millis as f64).into());
+                storage.set_alarm(ScheduledTime::new(date)).await?;
+            }
+            None => storage.delete_alarm().await?,
+        }
+        Ok(())
+    }
+}
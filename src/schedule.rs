@@ -1,76 +1,324 @@
 //! Scheduled handler
 
-use crate::github::{comment_on_issue, marge_pr};
+use crate::app::AppConfig;
+use crate::db::{
+    self,
+    audit::{NewAuditEntry, Outcome},
+    lease::RepoLease,
+    merge::{MergeJob, State},
+    setting::MergeMode,
+};
+use crate::github::{
+    comment_on_issue, enqueue_pull_request, get_pull_request, is_merge_queue_enabled, marge_pr,
+    Repo, Token,
+};
 use worker::*;
 
-pub async fn auto_merge(d1: &D1Database, github_app: crate::crypt::GitHubApp) -> Result<()> {
-    #[derive(Debug, serde::Deserialize)]
-    struct Res {
-        id: u64,
-        pr_number: u64,
-        owner: String,
-        repository: String,
-        installation_id: u64,
-    }
+/// リースが空くまで待つ時間
+const LEASE_WAIT: std::time::Duration = std::time::Duration::from_secs(5);
+
+/// 始める前の失敗をこの回数繰り返したら諦める
+const MAX_ATTEMPTS: u32 = 5;
+
+/// `merge`で何が起きたか
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum MergeOutcome {
+    Merged,
+    AutoMerge,
+    Queued,
+    /// コメントして失敗にした
+    Failed,
+    /// リポジトリのリースが取れなかった。ジョブは待ったままなので後でやり直される
+    Busy,
+    /// リースを待っている間にキャンセルなどされていた
+    NotPending,
+}
+
+/// 普段はリポジトリごとのDurable Objectがアラームでマージするので、
+/// ここではアラームをセットし直して、それでも漏れたものを実行する
+pub async fn auto_merge(d1: &D1Database, env: &Env, apps: &[AppConfig]) -> Result<()> {
     console_log!("Scheduled auto merge");
-    console_log!("Querying merges");
-    let query = query!(
-        &d1,
-        "SELECT id, pr_number, owner, repository, installation_id FROM merge where will_merged_at < DATETIME('now') AND merged = 0 LIMIT 5"
-    );
 
-    let results = query.run().await?.results::<Res>()?;
+    db::merge::reset_stale(d1).await?;
+    for repository in db::merge::pending_repositories(d1).await? {
+        crate::repo_scheduler::try_sync(env, &repository.repo()).await;
+    }
+
+    console_log!("Querying overdue merges");
+    let results = db::merge::overdue(d1, 5).await?;
     console_debug!("Query Result: {results:?}");
     for ri in results {
-        console_log!(
-            "Merging PR: {}/{}:#{}",
-            ri.owner,
-            ri.repository,
-            ri.pr_number
-        );
-        let token = github_app.token(ri.installation_id).await?;
-        // // マージできるか
-        // {
-        //     let is_pr_mergeable =
-        //         crate::github::is_pr_mergeable(ri.pr_number, &ri.owner, &ri.repository, &token)
-        //             .await?;
-        //
-        //     if is_pr_mergeable == Some(false) {
-        //         comment_on_issue(
-        //             ri.pr_number,
-        //             &ri.owner,
-        //             &ri.repository,
-        //             "Somethins were wrong. We can't merge this time",
-        //             &token,
-        //         )
-        //         .await?;
-        //         mark_as_merged(&d1, ri.id).await?; // 5分ごとにのアラームみたいになるのをさけるため
-        //         return Ok(());
-        //     } else if is_pr_mergeable == None {
-        //         console_warn!("Merggeable is none.");
-        //         return Ok(());
-        //     }
-        // }
-        let m = marge_pr(ri.pr_number, &ri.owner, &ri.repository, &token).await;
-        if m.is_err() {
-            console_error!("{m:?}");
-            comment_on_issue(
-                ri.pr_number,
-                &ri.owner,
-                &ri.repository,
-                "Somethins were wrong. We couldn't merge this time",
-                &token,
-            )
-            .await?;
+        console_warn!("The merge {} was not run by the alarm", ri.id);
+        // 1つのジョブの失敗で残りを止めない
+        if let Err(e) = merge_overdue(d1, apps, &ri).await {
+            console_error!("Failed to run the merge {}: {e}", ri.id);
         }
-        mark_as_merged(&d1, ri.id).await?;
     }
 
     Ok(())
 }
 
-async fn mark_as_merged(d1: &D1Database, id: u64) -> Result<()> {
-    let update_query = query!(&d1, "UPDATE merge SET merged = 1 WHERE id = ?1", id)?;
-    update_query.run().await?;
+async fn merge_overdue(d1: &D1Database, apps: &[AppConfig], job: &MergeJob) -> Result<()> {
+    let Some(app) = app_for_job(apps, job).await? else {
+        return fail_unconfigured(d1, job).await;
+    };
+    merge(d1, app, job, db::audit::SCHEDULER).await?;
     Ok(())
 }
+
+/// ジョブを登録したApp
+pub async fn app_for_job<'a>(
+    apps: &'a [AppConfig],
+    job: &MergeJob,
+) -> Result<Option<&'a AppConfig>> {
+    app_by_id(apps, job.app_id).await
+}
+
+pub async fn app_by_id(apps: &[AppConfig], app_id: Option<u64>) -> Result<Option<&AppConfig>> {
+    // app_idがないものは最初のAppで登録されたもの
+    let Some(app_id) = app_id else {
+        return Ok(apps.first());
+    };
+
+    for app in apps {
+        if app.app_id().await? == app_id {
+            return Ok(Some(app));
+        }
+    }
+    Ok(None)
+}
+
+/// 登録したAppが設定にないジョブは実行できない
+/// 待たせたままだとアラームとcronで毎回取り出されて他のジョブが進まないので失敗にする
+pub async fn fail_unconfigured(d1: &D1Database, job: &MergeJob) -> Result<()> {
+    console_error!(
+        "The app {:?} of the merge {} is not configured",
+        job.app_id,
+        job.id
+    );
+    db::merge::fail_by_id(d1, job.id).await?;
+    db::audit::record(
+        d1,
+        &NewAuditEntry {
+            actor: db::audit::SCHEDULER,
+            installation_id: Some(job.installation_id),
+            repo: Some(job.repo()),
+            pr_number: Some(job.pr_number),
+            action: "merge",
+            command: None,
+            result: Outcome::Failed,
+            error: Some("The app that scheduled this merge is not configured"),
+        },
+    )
+    .await;
+    Ok(())
+}
+
+/// トークンや設定が取れずに始められなかったジョブを後に回す
+/// そのままだとアラームが毎回すぐに同じジョブを取り出すので、間隔を空けて何回かで諦める
+async fn retry_later(d1: &D1Database, job: &MergeJob, error: &Error) -> Result<()> {
+    let Some(attempts) = db::merge::retry_later(d1, job.id).await? else {
+        return Ok(());
+    };
+    console_warn!(
+        "The merge {} could not start ({attempts}/{MAX_ATTEMPTS}): {error}",
+        job.id
+    );
+    if attempts < MAX_ATTEMPTS {
+        return Ok(());
+    }
+
+    db::merge::fail_by_id(d1, job.id).await?;
+    let error = error.to_string();
+    db::audit::record(
+        d1,
+        &NewAuditEntry {
+            actor: db::audit::SCHEDULER,
+            installation_id: Some(job.installation_id),
+            repo: Some(job.repo()),
+            pr_number: Some(job.pr_number),
+            action: "merge",
+            command: None,
+            result: Outcome::Failed,
+            error: Some(&error),
+        },
+    )
+    .await;
+    Ok(())
+}
+
+/// ジョブを1つ実行する。結果はジョブの状態と監査ログに残す
+/// リポジトリのリースが取れなければ何もしないので、アラームかcronでやり直される
+pub async fn merge(
+    d1: &D1Database,
+    app: &AppConfig,
+    job: &MergeJob,
+    actor: &str,
+) -> Result<MergeOutcome> {
+    let repo = job.repo();
+
+    let Some(lease) = RepoLease::acquire(d1, &repo, LEASE_WAIT).await? else {
+        console_warn!(
+            "{}/{} is busy. The merge {} will be retried",
+            job.owner,
+            job.repository,
+            job.id
+        );
+        return Ok(MergeOutcome::Busy);
+    };
+
+    let result = merge_with_lease(d1, app, job, actor).await;
+    lease.release().await?;
+    result
+}
+
+async fn merge_with_lease(
+    d1: &D1Database,
+    app: &AppConfig,
+    job: &MergeJob,
+    actor: &str,
+) -> Result<MergeOutcome> {
+    let prepared = async {
+        let token = app.github_app.token(job.installation_id).await?;
+        let mode = db::setting::merge_mode(d1, job.repository_id).await?;
+        Ok::<_, Error>((token, mode))
+    }
+    .await;
+    let (token, mode) = match prepared {
+        Ok(prepared) => prepared,
+        Err(e) => {
+            retry_later(d1, job, &e).await?;
+            return Err(e);
+        }
+    };
+    let repo = job.repo();
+
+    // リースを待っている間にキャンセルされたかもしれない
+    if !db::merge::start(d1, job.id).await? {
+        console_log!("The merge {} is no longer pending", job.id);
+        return Ok(MergeOutcome::NotPending);
+    }
+
+    console_log!(
+        "Merging PR: {}/{}:#{} ({})",
+        job.owner,
+        job.repository,
+        job.pr_number,
+        mode.as_str()
+    );
+    // // マージできるか
+    // {
+    //     let is_pr_mergeable =
+    //         crate::github::is_pr_mergeable(job.pr_number, &repo, &token).await?;
+    //
+    //     if is_pr_mergeable == Some(false) {
+    //         comment_on_issue(
+    //             job.pr_number,
+    //             &repo,
+    //             "Somethins were wrong. We can't merge this time",
+    //             &token,
+    //         )
+    //         .await?;
+    //         db::merge::finish(d1, job.id, State::Failed).await?; // 5分ごとにのアラームみたいになるのをさけるため
+    //         return Ok(());
+    //     } else if is_pr_mergeable == None {
+    //         console_warn!("Merggeable is none.");
+    //         return Ok(());
+    //     }
+    // }
+    let m = match mode {
+        MergeMode::Direct => merge_or_enqueue(job, &repo, &token).await,
+        MergeMode::Auto => enable_auto_merge(job, &token)
+            .await
+            .map(|()| State::AutoMerge),
+    };
+    // 失敗しても5分ごとに試し続けないように終わらせる
+    let state = *m.as_ref().unwrap_or(&State::Failed);
+    db::merge::finish(d1, job.id, state).await?;
+    crate::check_run::try_sync(d1, &repo, job.pr_number, &token).await;
+    let message = match &m {
+        Ok(State::AutoMerge) => Some(
+            "Auto-merge has been enabled. GitHub will merge this Pull Request once all requirements are met",
+        ),
+        Ok(State::Queued) => Some(
+            "This Pull Request has been added to the merge queue. GitHub will merge it once the queue checks pass",
+        ),
+        Ok(_) => None,
+        Err(e) => {
+            console_error!("{e:?}");
+            Some("Somethins were wrong. We couldn't merge this time")
+        }
+    };
+    if let Some(message) = message {
+        // コメントに失敗しても監査ログとスタックの停止は必ず行う
+        if let Err(e) = comment_on_issue(job.pr_number, &repo, message, &token).await {
+            console_error!("Failed to comment on the merge {}: {e}", job.id);
+        }
+    }
+
+    let action = match (mode, state) {
+        (_, State::Queued) => "merge_queue.enqueue",
+        (MergeMode::Auto, _) => "auto_merge.enable",
+        (MergeMode::Direct, _) => "merge",
+    };
+    let error = m.as_ref().err().map(|e| e.to_string());
+    db::audit::record(
+        d1,
+        &NewAuditEntry {
+            actor,
+            installation_id: Some(job.installation_id),
+            repo: Some(repo),
+            pr_number: Some(job.pr_number),
+            action,
+            command: None,
+            result: if m.is_ok() {
+                Outcome::Success
+            } else {
+                Outcome::Failed
+            },
+            error: error.as_deref(),
+        },
+    )
+    .await;
+
+    // スタックの途中なら上のPRは進められない
+    if state == State::Failed {
+        crate::stack::stop(d1, &token, job).await?;
+    }
+
+    Ok(match state {
+        State::AutoMerge => MergeOutcome::AutoMerge,
+        State::Queued => MergeOutcome::Queued,
+        State::Failed => MergeOutcome::Failed,
+        _ => MergeOutcome::Merged,
+    })
+}
+
+/// node_idがない古いジョブはPRから取る
+pub async fn pr_node_id(job: &MergeJob, token: &Token) -> Result<String> {
+    match &job.pr_node_id {
+        Some(node_id) => Ok(node_id.clone()),
+        None => Ok(get_pull_request(job.pr_number, &job.repo(), token)
+            .await?
+            .node_id),
+    }
+}
+
+/// マージキューが必須のブランチには直接マージできないのでキューに入れる
+async fn merge_or_enqueue(job: &MergeJob, repo: &Repo<'_>, token: &Token) -> Result<State> {
+    let node_id = pr_node_id(job, token).await?;
+
+    if is_merge_queue_enabled(&node_id, token).await? {
+        enqueue_pull_request(&node_id, token).await?;
+        return Ok(State::Queued);
+    }
+
+    marge_pr(job.pr_number, repo, token).await?;
+    Ok(State::Merged)
+}
+
+async fn enable_auto_merge(job: &MergeJob, token: &Token) -> Result<()> {
+    let node_id = pr_node_id(job, token).await?;
+
+    crate::github::enable_auto_merge(&node_id, token).await
+}
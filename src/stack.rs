@@ -0,0 +1,165 @@
+//! `merge stack`: ベースブランチが他のPRのheadになっているPRを下から順にマージする
+//! 上のPRは下のPRを待つジョブ (`blocked`) にして、下がマージされたらベースをデフォルトブランチに変えて進める
+//! 途中で失敗したら、その上のPRは全部キャンセルする
+
+use worker::*;
+
+use crate::db::{
+    self,
+    audit::{NewAuditEntry, Outcome},
+    merge::MergeJob,
+};
+use crate::github::{
+    comment_on_issue, find_pull_requests_by_head, get_pull_request, update_branch,
+    update_pull_request_base, PullRequest, Repo, Token,
+};
+
+/// 辿るPRの数の上限
+const MAX_DEPTH: usize = 16;
+
+/// ベースブランチを辿った結果
+pub enum Discovery {
+    Stack {
+        /// 下から順
+        prs: Vec<PullRequest>,
+        default_branch: String,
+    },
+    /// `branch`をheadにしている開いているPRがない
+    Missing {
+        pr_number: u64,
+        branch: String,
+    },
+    /// `branch`をheadにしているPRが複数ある
+    Ambiguous {
+        branch: String,
+    },
+    TooDeep,
+}
+
+/// `pr_number`からデフォルトブランチまでベースブランチを辿る
+/// 別のリポジトリ (fork) のブランチは辿らない
+pub async fn discover(repo: &Repo<'_>, pr_number: u64, token: &Token) -> Result<Discovery> {
+    let mut pr = get_pull_request(pr_number, repo, token).await?;
+    let default_branch = pr.base.repo.default_branch.clone();
+
+    let mut prs = Vec::new();
+    while pr.base.ref_name != default_branch {
+        if prs.len() >= MAX_DEPTH {
+            return Ok(Discovery::TooDeep);
+        }
+
+        let mut below = find_pull_requests_by_head(repo, &pr.base.ref_name, token).await?;
+        let next = match below.len() {
+            0 => {
+                return Ok(Discovery::Missing {
+                    pr_number: pr.number,
+                    branch: pr.base.ref_name,
+                })
+            }
+            1 => below.remove(0),
+            _ => {
+                return Ok(Discovery::Ambiguous {
+                    branch: pr.base.ref_name,
+                })
+            }
+        };
+        prs.push(pr);
+        pr = next;
+    }
+    prs.push(pr);
+    prs.reverse();
+
+    Ok(Discovery::Stack {
+        prs,
+        default_branch,
+    })
+}
+
+/// 下のPRがマージされたので、ベースを変えてベースの変更を取り込む
+pub async fn retarget(job: &MergeJob, base: &str, token: &Token) -> Result<()> {
+    let repo = job.repo();
+    update_pull_request_base(job.pr_number, &repo, base, token).await?;
+    update_branch(job.pr_number, &repo, token).await
+}
+
+/// `failed`がマージできなかったので、その上に積まれているPRのマージをキャンセルする
+pub async fn stop(d1: &D1Database, token: &Token, failed: &MergeJob) -> Result<()> {
+    let mut cancelled = Vec::new();
+    let mut below = (
+        failed.owner.clone(),
+        failed.repository.clone(),
+        failed.pr_number,
+    );
+    for _ in 0..MAX_DEPTH {
+        let below_repo = Repo {
+            owner: &below.0,
+            name: &below.1,
+            id: None,
+        };
+        let Some(job) = db::merge::dependents(d1, &below_repo, below.2)
+            .await?
+            .into_iter()
+            .find(|job| job.retarget_base.is_some())
+        else {
+            break;
+        };
+        if !db::merge::cancel_by_id(d1, job.id).await? {
+            break;
+        }
+        below = (job.owner.clone(), job.repository.clone(), job.pr_number);
+        cancelled.push(job);
+    }
+    if cancelled.is_empty() {
+        return Ok(());
+    }
+    console_log!(
+        "Stopped the stack at the job {} and cancelled {} jobs",
+        failed.id,
+        cancelled.len()
+    );
+
+    let list = cancelled
+        .iter()
+        .map(|job| format!("#{}", job.pr_number))
+        .collect::<Vec<_>>()
+        .join(", ");
+    comment_on_issue(
+        failed.pr_number,
+        &failed.repo(),
+        &format!("Stopped merging the stack here. The automatic merges of {list} stacked on this Pull Request have been cancelled"),
+        token,
+    )
+    .await?;
+
+    for job in &cancelled {
+        let repo = job.repo();
+        crate::check_run::try_sync(d1, &repo, job.pr_number, token).await;
+        comment_on_issue(
+            job.pr_number,
+            &repo,
+            &format!(
+                "The automatic merge has been cancelled because #{} below this Pull Request in the stack could not be merged",
+                failed.pr_number
+            ),
+            token,
+        )
+        .await?;
+
+        db::audit::record(
+            d1,
+            &NewAuditEntry {
+                actor: db::audit::SCHEDULER,
+                installation_id: Some(job.installation_id),
+                repo: Some(repo),
+                pr_number: Some(job.pr_number),
+                action: "merge.cancel",
+                command: None,
+                result: Outcome::Success,
+                error: None,
+            },
+        )
+        .await;
+    }
+
+    Ok(())
+}
@@ -0,0 +1,186 @@
+//! `GET /status`で予定されているマージをHTMLで表示する
+//! `POST /admin/status-link`で作った署名付きリンクでだけ見られる
+//! 時間はブラウザのタイムゾーンで表示する
+
+use std::collections::BTreeMap;
+use std::fmt::Write;
+use std::rc::Rc;
+
+use worker::*;
+
+use crate::db::{self, merge::MergeJob};
+
+/// 状態ごとに表示する数
+const LIMIT: u32 = 50;
+
+/// 見出しと`merge.state`
+const SECTIONS: [(&str, &str); 8] = [
+    ("Merging", "merging"),
+    ("Pending", "pending"),
+    ("Blocked by another Pull Request", "blocked"),
+    ("Suspended", "suspended"),
+    ("In merge queue", "queued"),
+    ("Waiting for auto-merge", "auto_merge"),
+    ("Recently merged", "merged"),
+    ("Failed", "failed"),
+];
+
+fn link_secret(env: &Env) -> Option<String> {
+    env.secret("STATUS_LINK_SECRET")
+        .ok()
+        .map(|sec| sec.to_string())
+        .filter(|sec| !sec.is_empty())
+}
+
+/// `expires`秒まで有効なリンク
+pub fn signed_link(env: &Env, origin: &str, expires: i64) -> Option<String> {
+    let sec = link_secret(env)?;
+    let sig = crate::crypt::sign_status_link(&sec, expires);
+
+    Some(format!("{origin}/status?expires={expires}&sig={sig}"))
+}
+
+/// `GET /status?expires=&sig=`
+pub async fn status(req: Request, ctx: RouteContext<Rc<worker::Context>>) -> Result<Response> {
+    let Some(sec) = link_secret(&ctx.env) else {
+        return Response::error("Not Found", 404);
+    };
+
+    let url = req.url()?;
+    let mut expires = None;
+    let mut sig = None;
+    for (key, value) in url.query_pairs() {
+        match key.as_ref() {
+            "expires" => expires = value.parse::<i64>().ok(),
+            "sig" => sig = Some(value.into_owned()),
+            _ => {}
+        }
+    }
+
+    let now = chrono::Utc::now().timestamp();
+    let authorized = match (expires, sig) {
+        (Some(expires), Some(sig)) => crate::crypt::verify_status_link(&sec, expires, &sig, now),
+        _ => false,
+    };
+    if !authorized {
+        return Response::error("Forbidden (the link is invalid or expired)", 403);
+    }
+
+    let d1 = ctx.env.d1("DB")?;
+    let mut sections = Vec::with_capacity(SECTIONS.len());
+    for (title, state) in SECTIONS {
+        let filter = db::merge::Filter {
+            state: Some(state),
+            ..Default::default()
+        };
+        sections.push((title, db::merge::list(&d1, &filter, LIMIT).await?));
+    }
+
+    let mut res = Response::from_html(render(&sections))?;
+    res.headers_mut().set("Cache-Control", "no-store")?;
+    res.headers_mut().set("Referrer-Policy", "no-referrer")?;
+    Ok(res)
+}
+
+fn render(sections: &[(&str, Vec<MergeJob>)]) -> String {
+    let mut html = String::from(
+        r#"<!DOCTYPE html>
+<html lang="en">
+<head>
+<meta charset="utf-8">
+<meta name="viewport" content="width=device-width, initial-scale=1">
+<meta name="robots" content="noindex">
+<title>Scheduled merges</title>
+<style>
+body { font-family: system-ui, sans-serif; margin: 2rem auto; max-width: 60rem; padding: 0 1rem; }
+table { border-collapse: collapse; width: 100%; margin-bottom: 1rem; }
+th, td { border-bottom: 1px solid #ddd; padding: 0.25rem 0.5rem; text-align: left; }
+</style>
+</head>
+<body>
+<h1>Scheduled merges</h1>
+"#,
+    );
+
+    for (title, jobs) in sections {
+        let _ = writeln!(html, "<h2>{title} ({})</h2>", jobs.len());
+        if jobs.is_empty() {
+            html.push_str("<p>None</p>\n");
+            continue;
+        }
+
+        // リポジトリごとにまとめる
+        let mut by_repo: BTreeMap<(&str, &str), Vec<&MergeJob>> = BTreeMap::new();
+        for job in jobs {
+            by_repo
+                .entry((job.owner.as_str(), job.repository.as_str()))
+                .or_default()
+                .push(job);
+        }
+
+        for ((owner, repository), jobs) in by_repo {
+            let owner = escape(owner);
+            let repository = escape(repository);
+            let _ = writeln!(
+                html,
+                "<h3><a href=\"https://github.com/{owner}/{repository}\">{owner}/{repository}</a></h3>\n<table>\n<tr><th>Pull Request</th><th>Scheduled at</th></tr>"
+            );
+            for job in jobs {
+                let _ = writeln!(
+                    html,
+                    "<tr><td><a href=\"https://github.com/{owner}/{repository}/pull/{number}\">#{number}</a></td><td>{time}{after}</td></tr>",
+                    number = job.pr_number,
+                    time = time(&job.will_merged_at),
+                    after = after(job),
+                );
+            }
+            html.push_str("</table>\n");
+        }
+    }
+
+    // UTCで埋め込んでブラウザのタイムゾーンに直す
+    html.push_str(
+        r#"<script>
+for (const el of document.querySelectorAll("time[datetime]")) {
+  el.textContent = new Date(el.getAttribute("datetime")).toLocaleString();
+  el.title = Intl.DateTimeFormat().resolvedOptions().timeZone;
+}
+</script>
+</body>
+</html>
+"#,
+    );
+
+    html
+}
+
+/// `merge.will_merged_at` (UTC) を`<time>`にする
+fn time(utc: &str) -> String {
+    match chrono::NaiveDateTime::parse_from_str(utc, "%Y-%m-%d %H:%M:%S") {
+        Ok(time) => format!(
+            "<time datetime=\"{}\">{} UTC</time>",
+            time.and_utc().to_rfc3339(),
+            time.format("%Y-%m-%d %H:%M")
+        ),
+        Err(_) => escape(utc),
+    }
+}
+
+/// 待っているPRへのリンク
+fn after(job: &MergeJob) -> String {
+    let Some((repo, number)) = job.after() else {
+        return String::new();
+    };
+    let owner = escape(repo.owner);
+    let name = escape(repo.name);
+
+    format!(" after <a href=\"https://github.com/{owner}/{name}/pull/{number}\">{owner}/{name}#{number}</a>")
+}
+
+fn escape(s: &str) -> String {
+    s.replace('&', "&amp;")
+        .replace('<', "&lt;")
+        .replace('>', "&gt;")
+        .replace('"', "&quot;")
+        .replace('\'', "&#39;")
+}
@@ -0,0 +1,213 @@
+//! `train`: リポジトリごとに決まった時間 (発車時刻) に、ラベルの付いたPRをまとめてマージする
+//! 発車したらラベルの付いたPRを古い順に確かめて、その時点でマージできるものだけマージする
+//! 何がマージされて、何がなぜ残ったかをラベルの付いた全部のPRにコメントする
+//! 発車時刻は`recurring_job`の`train`として持つ
+
+use chrono::Utc;
+use worker::*;
+
+use crate::app::AppConfig;
+use crate::check_run::{get_computed_pull_request, is_computed, is_ready, readiness};
+use crate::db::{
+    self,
+    audit::{NewAuditEntry, Outcome},
+    merge::{NewMergeJob, State},
+    recurring::RecurringJob,
+};
+use crate::github::{comment_on_issue, list_labelled_pull_requests, Token};
+use crate::schedule::MergeOutcome;
+
+pub const DEFAULT_LABEL: &str = "train";
+
+/// まとめの1行
+struct Row {
+    pr_number: u64,
+    shipped: bool,
+    detail: String,
+}
+
+/// 発車する。`argument`はラベル
+pub async fn depart(
+    d1: &D1Database,
+    env: &Env,
+    app: &AppConfig,
+    train: &RecurringJob,
+) -> Result<()> {
+    let repo = train.repo();
+    let label = train.argument.as_deref().unwrap_or(DEFAULT_LABEL);
+    let token = app.github_app.token(train.installation_id).await?;
+
+    let pr_numbers = list_labelled_pull_requests(&repo, label, &token).await?;
+    console_log!(
+        "The train of {}/{} departed with {} Pull Requests",
+        train.owner,
+        train.repository,
+        pr_numbers.len()
+    );
+    if pr_numbers.is_empty() {
+        return Ok(());
+    }
+
+    // 前のPRがマージされるとマージできなくなるものもあるので、1つずつ確かめてマージする
+    let mut rows = Vec::new();
+    for pr_number in pr_numbers {
+        let row = match board(d1, app, train, pr_number, &token).await {
+            Ok(row) => row,
+            Err(e) => {
+                console_error!("Failed to merge #{pr_number} on the train: {e}");
+                Row {
+                    pr_number,
+                    shipped: false,
+                    detail: "Failed to merge".into(),
+                }
+            }
+        };
+        if !row.shipped {
+            db::audit::record(
+                d1,
+                &NewAuditEntry {
+                    actor: db::audit::SCHEDULER,
+                    installation_id: Some(train.installation_id),
+                    repo: Some(repo),
+                    pr_number: Some(pr_number),
+                    action: "train.leave",
+                    command: None,
+                    result: Outcome::Rejected,
+                    error: Some(&row.detail),
+                },
+            )
+            .await;
+        }
+        rows.push(row);
+    }
+    // 作ってすぐ終わったジョブやキャンセルしたジョブに合わせてアラームをセットし直す
+    crate::repo_scheduler::try_sync(env, &repo).await;
+
+    let summary = summary(&train.rule, label, &rows);
+    for row in &rows {
+        crate::check_run::try_sync(d1, &repo, row.pr_number, &token).await;
+        comment_on_issue(row.pr_number, &repo, &summary, &token).await?;
+    }
+    Ok(())
+}
+
+/// マージできればマージして結果を返す
+async fn board(
+    d1: &D1Database,
+    app: &AppConfig,
+    train: &RecurringJob,
+    pr_number: u64,
+    token: &Token,
+) -> Result<Row> {
+    let repo = train.repo();
+    let left = |detail: &str| Row {
+        pr_number,
+        shipped: false,
+        detail: detail.into(),
+    };
+
+    let pr = get_computed_pull_request(pr_number, &repo, token).await?;
+    if !is_computed(pr.mergeable_state.as_deref()) {
+        return Ok(left("The mergeability is not computed yet"));
+    }
+    if !is_ready(pr.mergeable_state.as_deref()) {
+        return Ok(left(readiness(pr.mergeable_state.as_deref())));
+    }
+    // 別に予定されているものは上書きしない
+    let latest = db::merge::find_latest_by_pr(d1, &repo, pr_number).await?;
+    match latest.map(|job| job.state) {
+        Some(State::Merging | State::Queued | State::AutoMerge) => {
+            return Ok(left("Already being merged"));
+        }
+        Some(State::Pending | State::Blocked | State::Suspended) => {
+            return Ok(left("Already scheduled"));
+        }
+        _ => {}
+    }
+
+    // 普段のマージと同じようにジョブを作って今すぐ実行する
+    db::merge::schedule(
+        d1,
+        &NewMergeJob {
+            pr_number,
+            repo,
+            will_merged_at: Utc::now().naive_utc(),
+            installation_id: train.installation_id,
+            app_id: app.app_id().await?,
+            pr_node_id: &pr.node_id,
+            scheduled_by: db::audit::SCHEDULER,
+            after: None,
+            retarget_base: None,
+            group_id: None,
+        },
+    )
+    .await?;
+    let Some(job) = db::merge::find_latest_by_pr(d1, &repo, pr_number).await? else {
+        return Ok(left("Failed to schedule"));
+    };
+    let outcome = crate::schedule::merge(d1, app, &job, db::audit::SCHEDULER).await?;
+
+    let shipped = |detail: &str| Row {
+        pr_number,
+        shipped: true,
+        detail: detail.into(),
+    };
+    Ok(match outcome {
+        MergeOutcome::Merged => shipped("Merged"),
+        MergeOutcome::AutoMerge => shipped("Auto-merge enabled"),
+        MergeOutcome::Queued => shipped("Added to the merge queue"),
+        // リポジトリのリースが取れなかった。発車時刻の外では乗せないのでキャンセルする
+        MergeOutcome::Busy => {
+            db::merge::cancel_by_id(d1, job.id).await?;
+            left("Skipped because the repository was busy")
+        }
+        MergeOutcome::Failed | MergeOutcome::NotPending => left("Failed to merge"),
+    })
+}
+
+fn summary(rule: &str, label: &str, rows: &[Row]) -> String {
+    let mut summary = format!("### Release train (`{rule}`, label `{label}`)\n");
+
+    for (shipped, heading) in [(true, "Shipped"), (false, "Left behind")] {
+        let rows = rows
+            .iter()
+            .filter(|row| row.shipped == shipped)
+            .collect::<Vec<_>>();
+        if rows.is_empty() {
+            continue;
+        }
+        summary.push_str(&format!("\n**{heading}**\n\n"));
+        for row in rows {
+            summary.push_str(&format!("- #{}: {}\n", row.pr_number, row.detail));
+        }
+    }
+
+    summary
+}
+
+#[cfg(test)]
+mod tests {
+    use super::Row;
+
+    #[test]
+    fn test_summary() {
+        let rows = [
+            Row {
+                pr_number: 3,
+                shipped: true,
+                detail: "Merged".into(),
+            },
+            Row {
+                pr_number: 4,
+                shipped: false,
+                detail: "There are merge conflicts".into(),
+            },
+        ];
+
+        assert_eq!(
+            super::summary("every weekday 17:00", "train", &rows),
+            "### Release train (`every weekday 17:00`, label `train`)\n\n**Shipped**\n\n- #3: Merged\n\n**Left behind**\n\n- #4: There are merge conflicts\n"
+        );
+        assert!(!super::summary("every weekday 17:00", "train", &rows[..1]).contains("Left behind"));
+    }
+}